@@ -0,0 +1,42 @@
+#!/bin/bash
+# Cache compression benchmark: for every codec, the size of the cache, the time of the first -c run (trains from
+# text input and writes the cache) and of a second -c run (trains from the cache), each the mean of RUNS runs.
+# Prints the table in BENCHMARK.md.
+# usage: ./cache_compression.sh [EXAMPLES] [RUNS]
+set -e
+EXAMPLES=${1:-2000000}
+RUNS=${2:-3}
+FW=$(pwd)/../target/release/fw
+PARAMS="--adaptive -l 0.1 --power_t 0.39 -b 25 --interactions AB --keep A --keep B --keep C --keep D --keep E --keep F --keep G --keep H --keep I --keep J --keep K --keep L"
+
+(cd .. && cargo build --release)
+mkdir -p work_dir_cache
+cd work_dir_cache
+if [ ! -f train.vw ]; then
+    # same synthetic dataset as the main benchmark (12 namespaces), writes train.vw and vw_namespace_map.csv
+    python3 ../generate.py $EXAMPLES
+fi
+
+# Runs the command RUNS times and prints its mean wall time in seconds, the cache is removed before each run when asked
+mean_seconds() {
+    local remove_cache=$1; shift
+    local total=0
+    for i in $(seq $RUNS); do
+        if [ "$remove_cache" = "remove" ]; then rm -f train.vw.fwcache; fi
+        local start=$(date +%s.%N)
+        "$@" > /dev/null
+        local end=$(date +%s.%N)
+        total=$(awk "BEGIN {print $total + $end - $start}")
+    done
+    awk "BEGIN {printf \"%.2f\", $total / $RUNS}"
+}
+
+echo "Codec|Cache size (MB)|Training from text, writing the cache (seconds)|Training from cache (seconds)"
+echo "----|----:|----:|----:"
+for codec in none lz4 zstd:1 zstd zstd:9; do
+    create=$(mean_seconds remove $FW --data train.vw -c --cache_compression $codec $PARAMS)
+    size=$(awk "BEGIN {printf \"%.1f\", $(stat -c %s train.vw.fwcache) / 1000000}")
+    train=$(mean_seconds keep $FW --data train.vw -c $PARAMS)
+    echo "$codec|$size | $create | $train"
+done
+rm -f train.vw.fwcache
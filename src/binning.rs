@@ -0,0 +1,171 @@
+use std::error::Error;
+use std::io::BufRead;
+
+use crate::model_instance;
+use crate::parser;
+
+const VOWPAL_FNV_PRIME:u32 = 16777619;	// vowpal magic number
+
+/*
+Binning turns a continuous namespace into a categorical one. Each feature (hash, value) of such
+namespace gets replaced by a feature (hash of the feature combined with bucket index, 1.0).
+This happens on the record buffer, before FeatureBufferTranslator, so LR and FFM parts see
+ordinary categorical features and cache files keep the original values.
+*/
+
+#[inline(always)]
+pub fn bucket_index(boundaries: &[f32], value: f32) -> u32 {
+    // Number of boundaries that are smaller or equal to the value
+    boundaries.partition_point(|boundary| *boundary <= value) as u32
+}
+
+#[inline(always)]
+pub fn bucket_hash(hash: u32, bucket: u32) -> u32 {
+    (hash.overflowing_mul(VOWPAL_FNV_PRIME).0 ^ bucket) & parser::MASK31
+}
+
+pub fn bucketize_record(binning_descs: &[model_instance::BinningDesc], record_buffer: &mut [u32]) {
+    for bd in binning_descs {
+        let desc_offset = bd.namespace_index + parser::HEADER_LEN;
+        let namespace_desc = record_buffer[desc_offset];
+        if (namespace_desc & parser::IS_NOT_SINGLE_MASK) != 0 {
+            let start = ((namespace_desc >> 16) & 0x7fff) as usize;
+            let end = (namespace_desc & 0xffff) as usize;
+            for hash_offset in (start..end).step_by(2) {
+                let value = f32::from_bits(record_buffer[hash_offset + 1]);
+                record_buffer[hash_offset] = bucket_hash(record_buffer[hash_offset], bucket_index(&bd.boundaries, value));
+                record_buffer[hash_offset + 1] = parser::FLOAT32_ONE;
+            }
+        } else {
+            // Single feature stored in-place has an implicit value of 1.0
+            record_buffer[desc_offset] = bucket_hash(namespace_desc, bucket_index(&bd.boundaries, 1.0));
+        }
+    }
+}
+
+fn collect_values(namespace_index: usize, record_buffer: &[u32], values: &mut Vec<f32>) {
+    let namespace_desc = record_buffer[namespace_index + parser::HEADER_LEN];
+    if (namespace_desc & parser::IS_NOT_SINGLE_MASK) != 0 {
+        let start = ((namespace_desc >> 16) & 0x7fff) as usize;
+        let end = (namespace_desc & 0xffff) as usize;
+        for hash_offset in (start..end).step_by(2) {
+            values.push(f32::from_bits(record_buffer[hash_offset + 1]));
+        }
+    } else {
+        values.push(1.0);
+    }
+}
+
+pub fn quantile_boundaries(values: &mut Vec<f32>, num_quantiles: u32) -> Vec<f32> {
+    values.retain(|v| !v.is_nan());
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mut boundaries: Vec<f32> = Vec::new();
+    if values.is_empty() {
+        return boundaries;
+    }
+    for q in 1..num_quantiles as usize {
+        let boundary = values[q * values.len() / num_quantiles as usize];
+        // Heavily repeated values would produce the same boundary multiple times
+        if boundaries.last().is_none_or(|last| *last < boundary) {
+            boundaries.push(boundary);
+        }
+    }
+    boundaries
+}
+
+// Learns boundaries of all binning descs that request quantiles, by looking at the first max_examples examples
+pub fn learn_quantile_boundaries(mi: &mut model_instance::ModelInstance,
+                                 pa: &mut parser::VowpalParser,
+                                 mut input_bufread: &mut dyn BufRead,
+                                 max_examples: u64) -> Result<(), Box<dyn Error>> {
+    let mut values: Vec<Vec<f32>> = vec![Vec::new(); mi.binning_descs.len()];
+    let mut example_num:u64 = 0;
+    while example_num < max_examples {
+        let buffer = match pa.next(&mut input_bufread) {
+            Ok([]) => break, // EOF
+            Ok(buffer) => buffer,
+            Err(e) => return Err(e)
+        };
+        for (i, bd) in mi.binning_descs.iter().enumerate() {
+            if bd.num_quantiles > 0 {
+                collect_values(bd.namespace_index, buffer, &mut values[i]);
+            }
+        }
+        example_num += 1;
+    }
+
+    for (i, bd) in mi.binning_descs.iter_mut().enumerate() {
+        if bd.num_quantiles > 0 {
+            bd.boundaries = quantile_boundaries(&mut values[i], bd.num_quantiles);
+            println!("Learned {} bin boundaries for namespace index {} from {} examples", bd.boundaries.len(), bd.namespace_index, example_num);
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::io::Cursor;
+    use crate::vwmap;
+
+    fn nd(start: u32, end: u32) -> u32 {
+        return (start << 16) + end;
+    }
+
+    #[test]
+    fn test_bucket_index() {
+        let boundaries = vec![0.5, 1.0, 2.5];
+        assert_eq!(bucket_index(&boundaries, -1.0), 0);
+        assert_eq!(bucket_index(&boundaries, 0.5), 1);
+        assert_eq!(bucket_index(&boundaries, 0.7), 1);
+        assert_eq!(bucket_index(&boundaries, 1.0), 2);
+        assert_eq!(bucket_index(&boundaries, 100.0), 3);
+        assert_eq!(bucket_index(&vec![], 100.0), 0);
+    }
+
+    #[test]
+    fn test_bucketize_record() {
+        let bd = vec![model_instance::BinningDesc {namespace_index: 1, num_quantiles: 0, boundaries: vec![0.5, 1.0, 2.5]}];
+        // namespace 0 is untouched, namespace 1 has two features
+        let mut rb = vec![9, 1, parser::FLOAT32_ONE, 0xfea, parser::IS_NOT_SINGLE_MASK | nd(5, 9), 0xfeb, 0.7f32.to_bits(), 0xfec, 3.0f32.to_bits()];
+        bucketize_record(&bd, &mut rb);
+        assert_eq!(rb, vec![9, 1, parser::FLOAT32_ONE, 0xfea, parser::IS_NOT_SINGLE_MASK | nd(5, 9),
+                            bucket_hash(0xfeb, 1), parser::FLOAT32_ONE, bucket_hash(0xfec, 3), parser::FLOAT32_ONE]);
+
+        // single feature in-place has value 1.0
+        let mut rb = vec![5, 1, parser::FLOAT32_ONE, 0xfea, 0xfeb];
+        bucketize_record(&bd, &mut rb);
+        assert_eq!(rb, vec![5, 1, parser::FLOAT32_ONE, 0xfea, bucket_hash(0xfeb, 2)]);
+        assert_eq!(rb[4] & parser::IS_NOT_SINGLE_MASK, 0);
+
+        // missing namespace stays missing
+        let mut rb = vec![5, 1, parser::FLOAT32_ONE, 0xfea, parser::NULL];
+        bucketize_record(&bd, &mut rb);
+        assert_eq!(rb, vec![5, 1, parser::FLOAT32_ONE, 0xfea, parser::NULL]);
+    }
+
+    #[test]
+    fn test_learn_quantile_boundaries() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.binning_descs.push(model_instance::BinningDesc {namespace_index: 1, num_quantiles: 4, boundaries: vec![]});
+        let mut input = String::new();
+        for i in 0..100 {
+            input.push_str(&format!("1 |A a |B price:{}\n", i));
+        }
+        let mut buf = Cursor::new(input.into_bytes());
+        learn_quantile_boundaries(&mut mi, &mut parser::VowpalParser::new(&vw), &mut buf, 1000).unwrap();
+        assert_eq!(mi.binning_descs[0].boundaries, vec![25.0, 50.0, 75.0]);
+
+        // repeated values collapse boundaries
+        let mut values = vec![1.0, 1.0, 1.0, 1.0, 1.0, 2.0];
+        assert_eq!(quantile_boundaries(&mut values, 3), vec![1.0]);
+    }
+}
@@ -2,6 +2,7 @@
 use std::{mem,slice};
 use std::io;
 use std::io::Read;
+use std::io::Write;
 use std::fs;
 use std::error::Error;
 use std::path;
@@ -9,86 +10,216 @@ use std::path;
 //use flate2::Compression;
 //use flate2::read::DeflateDecoder;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-//use zstd::stream::{Encoder, Decoder};
+use std::io::Error as IOError;
+use std::io::ErrorKind;
 
-//use lz4::{Decoder, EncoderBuilder};
+use fasthash::murmur3;
 
 use crate::vwmap;
+use crate::cache_index;
 
 const CACHE_HEADER_MAGIC_STRING: &[u8; 4] = b"FWCA";    // Fwumious Wabbit CAche
-const CACHE_HEADER_VERSION:u32 = 8;
+const CACHE_HEADER_VERSION:u32 = 12;
 /*
 Version incompatibilites:
 7->8: add example importance to the parsed buffer format
+8->9: magic, version and compression codec are stored uncompressed, everything after them is compressed
+9->10: examples are stored in checksummed blocks, followed by a footer with the number of examples
+10->11: header records the timestamp namespace, whose desc holds a timestamp instead of a hash
+11->12: block checksum covers the block header too
 */
 
 // Cache layout:
 // 4 bytes: Magic bytes
 // u32: Version of the cache format
+// u32: Compression codec (0 - none, 1 - lz4, 2 - zstd)
+// --- everything below is compressed with the codec ---
 // u_size + blob: json encoding of vw_source
-// u_size + blob: json encoding of model_instance
-// ...cached examples
+// u32: desc offset of the --timestamp_namespace, 0 when there is none
+// ...blocks of cached examples, each block is:
+//   u32: length of block payload in u32 words
+//   u32: number of examples in the block
+//   u32: murmur3 checksum of block length, number of examples and payload
+//   payload: examples (record buffers) one after another, an example never spans two blocks
+// footer:
+//   u32: FOOTER_MARKER
+//   u64: total number of examples
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheCompression {
+    None,
+    Lz4,
+    Zstd(i32),  // compression level
+}
+
+const ZSTD_DEFAULT_LEVEL:i32 = 3;
+
+impl CacheCompression {
+    // Parses "none", "lz4", "zstd" or "zstd:level"
+    pub fn from_str(s: &str) -> Result<CacheCompression, Box<dyn Error>> {
+        let vsplit: Vec<&str> = s.splitn(2, ":").collect();
+        match (vsplit[0], vsplit.len()) {
+            ("none", 1) => Ok(CacheCompression::None),
+            ("lz4", 1) => Ok(CacheCompression::Lz4),
+            ("zstd", 1) => Ok(CacheCompression::Zstd(ZSTD_DEFAULT_LEVEL)),
+            ("zstd", _) => match vsplit[1].parse::<i32>() {
+                Ok(level) => Ok(CacheCompression::Zstd(level)),
+                Err(_) => Err(Box::new(IOError::new(ErrorKind::Other, format!("Zstd compression level has to be an integer, got: {}", vsplit[1]))))
+            },
+            _ => Err(Box::new(IOError::new(ErrorKind::Other, format!("--cache_compression has to be none, lz4 or zstd[:level], got: {}", s))))
+        }
+    }
+
+    // Historical default: compress the cache only when the input is compressed
+    pub fn default_for_input(input_filename: &str) -> CacheCompression {
+        if input_filename.ends_with("gz") {
+            CacheCompression::Lz4
+        } else {
+            CacheCompression::None
+        }
+    }
+
+    fn to_codec_id(&self) -> u32 {
+        match self {
+            CacheCompression::None => 0,
+            CacheCompression::Lz4 => 1,
+            CacheCompression::Zstd(_) => 2,
+        }
+    }
+}
+
+
+const BLOCK_LEN:usize = 1024*25;     // in u32 words, block is written out when it grows over this
+const MAX_BLOCK_LEN:usize = 1 << 26; // anything larger can only come from a corrupted length
+const FOOTER_MARKER:u32 = 0xffffffff;
+const CACHE_HEADER_START_LEN:u64 = 12;  // magic, version, codec
+const BLOCK_HEADER_LEN:u64 = 12;        // length, number of examples, checksum
+
+// Checksum of a block covers the rest of its header too, a damaged number of examples would go unnoticed otherwise
+fn block_checksum(block_len: u32, num_records: u32, payload: &[u8]) -> u32 {
+    let mut header: [u8; 8] = [0; 8];
+    header[0..4].copy_from_slice(&block_len.to_le_bytes());
+    header[4..8].copy_from_slice(&num_records.to_le_bytes());
+    murmur3::hash32_with_seed(payload, murmur3::hash32(header))
+}
+
+// Writer of the cache file. Compressing encoders are kept as they are, since their streams have to be finished
+// explicitly (lz4 doesn't write the frame end mark on drop)
+enum CacheWriter {
+    Sink,
+    Plain(io::BufWriter<fs::File>),
+    Lz4(io::BufWriter<lz4::Encoder<fs::File>>),
+    Zstd(io::BufWriter<zstd::stream::write::Encoder<'static, fs::File>>),
+}
 
-const READBUF_LEN:usize = 1024*100;
+impl io::Write for CacheWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CacheWriter::Sink => Ok(buf.len()),
+            CacheWriter::Plain(w) => w.write(buf),
+            CacheWriter::Lz4(w) => w.write(buf),
+            CacheWriter::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CacheWriter::Sink => Ok(()),
+            CacheWriter::Plain(w) => w.flush(),
+            CacheWriter::Lz4(w) => w.flush(),
+            CacheWriter::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+impl CacheWriter {
+    // Flushes the buffered data and ends the compressed stream
+    fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            CacheWriter::Sink => {},
+            CacheWriter::Plain(w) => {
+                w.into_inner().map_err(|e| e.into_error())?;
+            },
+            CacheWriter::Lz4(w) => {
+                let (_output_file, result) = w.into_inner().map_err(|e| e.into_error())?.finish();
+                result?;
+            },
+            CacheWriter::Zstd(w) => {
+                w.into_inner().map_err(|e| e.into_error())?.finish()?;
+            },
+        }
+        Ok(())
+    }
+}
 
 
 pub struct RecordCache {
-    output_bufwriter: Box<dyn io::Write>,
+    output_bufwriter: CacheWriter,
     input_bufreader: Box<dyn io::Read>,
     temporary_filename: String,
     final_filename: String,
     pub writing: bool,
     pub reading: bool,
-//    pub output_buffer: Vec<u32>,
-    pub byte_buffer: Vec<u8>,//[u8; READBUF_LEN],
-    start_pointer: usize,
-    end_pointer: usize,
-    total_read: usize,
+    output_block: Vec<u32>,
+    output_block_records: u32,
+    total_written_records: u64,
+    block_buffer: Vec<u32>,
+    block_pointer: usize,
+    block_records_left: u32,
+    total_read_records: u64,
+    num_read_blocks: u64,
+    total_read: usize,          // bytes of (uncompressed) examples read so far
+    finished: bool,             // footer was read
+    compression: CacheCompression,
+    written_bytes: u64,         // position in the cache file, only meaningful without compression
+    index_writer: Option<cache_index::CacheIndexWriter>,
+    timestamp_desc_offset: Option<usize>,
 }
 
 
 impl RecordCache {
-    pub fn new(input_filename: &str, enabled: bool, vw_map: &vwmap::VwNamespaceMap) -> RecordCache {
-        let temporary_filename: String;
-        let final_filename: String;
-        let gz: bool;
-        temporary_filename = format!("{}.fwcache.writing", input_filename);
-        final_filename = format!("{}.fwcache", input_filename);
-        if !input_filename.ends_with("gz") {
-            gz = false;
-        } else
-        {
-            gz = true;
-        }
-        
-        let mut rc = RecordCache {
-            output_bufwriter: Box::new(io::BufWriter::new(io::sink())),
+    // Default cache location is next to the input file
+    pub fn default_cache_filename(input_filename: &str) -> String {
+        format!("{}.fwcache", input_filename)
+    }
+
+    fn new_empty(cache_filename: &str) -> RecordCache {
+        RecordCache {
+            output_bufwriter: CacheWriter::Sink,
             input_bufreader: Box::new(io::empty()),
-            temporary_filename: temporary_filename.to_string(),
-            final_filename: final_filename.to_string(),
+            temporary_filename: format!("{}.writing", cache_filename),
+            final_filename: cache_filename.to_string(),
             writing: false,
             reading: false,
-            byte_buffer: Vec::new(),
-            start_pointer: 0,
-            end_pointer: 0,
+            output_block: Vec::new(),
+            output_block_records: 0,
+            total_written_records: 0,
+            block_buffer: Vec::new(),
+            block_pointer: 0,
+            block_records_left: 0,
+            total_read_records: 0,
+            num_read_blocks: 0,
             total_read: 0,
-        };
+            finished: false,
+            compression: CacheCompression::None,
+            written_bytes: 0,
+            index_writer: None,
+            timestamp_desc_offset: None,
+        }
+    }
+
+    // When the cache file can't be read, it is written, unless there is no input to write it from (can_write)
+    pub fn new(cache_filename: &str, enabled: bool, can_write: bool, vw_map: &vwmap::VwNamespaceMap, timestamp_desc_offset: Option<usize>, compression: CacheCompression) -> Result<RecordCache, Box<dyn Error>> {
+        let final_filename = cache_filename.to_string();
+        let mut rc = RecordCache::new_empty(cache_filename);
+        rc.timestamp_desc_offset = timestamp_desc_offset;
         
         if enabled {
             if path::Path::new(&final_filename).exists() {
                 rc.reading = true;
-                if !gz {
-                    // we buffer ourselves, otherwise i would be wise to use bufreader
-                    rc.input_bufreader = Box::new(fs::File::open(&final_filename).unwrap());
-                } else {
-//                    rc.input_bufreader = Box::new(zstd::stream::Decoder::new(fs::File::open(&final_filename).unwrap()).unwrap());
-                    rc.input_bufreader = Box::new(lz4::Decoder::new(fs::File::open(&final_filename).unwrap()).unwrap());
-                }
                 println!("using cache_file = {}", final_filename );
                 println!("ignoring text input in favor of cache input");
-                rc.byte_buffer.resize(READBUF_LEN, 0);
-                match rc.verify_header(vw_map) {
+                match rc.open_for_reading(vw_map) {
                     Ok(()) => {},
                     Err(e) => {
                         
@@ -97,108 +228,488 @@ impl RecordCache {
                     }
                 }
             }
-            if !rc.reading {
+            if !rc.reading && can_write {
                 rc.writing = true;
-                println!("creating cache file = {}", final_filename );
-                if !gz {
-                    rc.output_bufwriter = Box::new(io::BufWriter::new(fs::File::create(temporary_filename).unwrap()));
-                } else {
-//                    rc.output_bufwriter = Box::new(io::BufWriter::new(DeflateEncoder::new(fs::File::create(temporary_filename).unwrap(),
-//                                                                    Compression::fast())));
-
-//                      rc.output_bufwriter = Box::new(io::BufWriter::new(zstd::stream::Encoder::new(fs::File::create(temporary_filename).unwrap(),
-//                                                                    -5).unwrap().auto_finish()));
-                      rc.output_bufwriter = Box::new(io::BufWriter::new(lz4::EncoderBuilder::new()
-                                                                      .level(3).build(fs::File::create(temporary_filename).unwrap()
-                                                                    ).unwrap()));
+                println!("creating cache file = {}, compression: {:?}", final_filename, compression);
+                if let Err(e) = rc.open_for_writing(vw_map, compression) {
+                    return Err(Box::new(IOError::new(ErrorKind::Other, format!("Cannot write cache file {}: {}", rc.temporary_filename, e))));
                 }
-                rc.write_header(vw_map).unwrap();
             }
         }        
-        rc
+        Ok(rc)
     }
-    
+
+    // Opens the cache file, checks the uncompressed part of the header and returns decompressing reader
+    fn open_cache_reader(cache_filename: &str) -> Result<Box<dyn io::Read>, Box<dyn Error>> {
+        let mut input_file = fs::File::open(cache_filename)?;
+        let codec_id = RecordCache::verify_header_start(&mut input_file)?;
+        // we buffer ourselves, otherwise i would be wise to use bufreader
+        match codec_id {
+            0 => Ok(Box::new(input_file)),
+            1 => Ok(Box::new(lz4::Decoder::new(input_file)?)),
+            2 => Ok(Box::new(zstd::stream::read::Decoder::new(input_file)?)),
+            _ => Err(format!("Unknown cache compression codec: {}", codec_id))?
+        }
+    }
+
+    // Namespace map stored in the cache, so training can run from the cache file alone
+    pub fn vwmap_from_cache_file(cache_filename: &str) -> Result<vwmap::VwNamespaceMap, Box<dyn Error>> {
+        let mut input_bufreader = RecordCache::open_cache_reader(cache_filename)?;
+        RecordCache::read_header_vwmap(cache_filename, &mut input_bufreader)
+    }
+
+    fn read_header_vwmap(cache_filename: &str, input_bufreader: &mut dyn io::Read) -> Result<vwmap::VwNamespaceMap, Box<dyn Error>> {
+        match vwmap::VwNamespaceMap::new_from_buf(input_bufreader) {
+            Ok(vw) => Ok(vw),
+            Err(e) => Err(Box::new(IOError::new(ErrorKind::Other, format!("Cache file {} header is damaged: {}", cache_filename, e))))
+        }
+    }
+
+    fn open_for_reading(&mut self, vw_map: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
+        self.input_bufreader = RecordCache::open_cache_reader(&self.final_filename)?;
+        self.verify_header(vw_map)
+    }
+
+    fn open_for_writing(&mut self, vw_map: &vwmap::VwNamespaceMap, compression: CacheCompression) -> Result<(), Box<dyn Error>> {
+        let mut output_file = fs::File::create(&self.temporary_filename)?;
+        RecordCache::write_header_start(&mut output_file, compression)?;
+        self.compression = compression;
+        self.written_bytes = CACHE_HEADER_START_LEN;
+        self.output_bufwriter = match compression {
+            CacheCompression::None => CacheWriter::Plain(io::BufWriter::new(output_file)),
+            CacheCompression::Lz4 => CacheWriter::Lz4(io::BufWriter::new(lz4::EncoderBuilder::new()
+                                                                         .level(3).build(output_file)?)),
+            CacheCompression::Zstd(level) => CacheWriter::Zstd(io::BufWriter::new(zstd::stream::write::Encoder::new(output_file, level)?)),
+        };
+        self.write_header(vw_map)
+    }
+
     pub fn push_record(&mut self, record_buf: &[u32]) -> Result<(), Box<dyn Error>> {
         if self.writing {
-            let element_size = mem::size_of::<u32>();
-            unsafe { 
-                let vv:&[u8] = slice::from_raw_parts(record_buf.as_ptr() as *const u8, 
-                                            record_buf.len() * element_size) ;
-                self.output_bufwriter.write(&vv)?;
+            self.output_block.extend_from_slice(record_buf);
+            self.output_block_records += 1;
+            self.total_written_records += 1;
+            if self.output_block.len() >= BLOCK_LEN {
+                self.write_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> Result<(), Box<dyn Error>> {
+        let element_size = mem::size_of::<u32>();
+        let vv:&[u8] = unsafe {
+            slice::from_raw_parts(self.output_block.as_ptr() as *const u8, 
+                                  self.output_block.len() * element_size)
+        };
+        self.output_bufwriter.write_u32::<LittleEndian>(self.output_block.len() as u32)?;
+        self.output_bufwriter.write_u32::<LittleEndian>(self.output_block_records)?;
+        self.output_bufwriter.write_u32::<LittleEndian>(block_checksum(self.output_block.len() as u32, self.output_block_records, vv))?;
+        self.output_bufwriter.write_all(vv)?;
+        let payload_offset = self.written_bytes + BLOCK_HEADER_LEN;
+        if let Some(index_writer) = self.index_writer.as_mut() {
+            let mut pos:usize = 0;
+            while pos < self.output_block.len() {
+                index_writer.push(payload_offset + (pos * element_size) as u64, &self.output_block[pos..])?;
+                pos += self.output_block[pos] as usize;
             }
         }
+        self.written_bytes = payload_offset + vv.len() as u64;
+        self.output_block.truncate(0);
+        self.output_block_records = 0;
         Ok(())
     }
     
     pub fn write_finish(&mut self)  -> Result<(), Box<dyn Error>> {
         if self.writing {
-            self.output_bufwriter.flush()?;
+            if self.output_block_records > 0 {
+                self.write_block()?;
+            }
+            self.output_bufwriter.write_u32::<LittleEndian>(FOOTER_MARKER)?;
+            self.output_bufwriter.write_u64::<LittleEndian>(self.total_written_records)?;
+            self.written_bytes += 12;
+            mem::replace(&mut self.output_bufwriter, CacheWriter::Sink).finish()?;
+            if let Some(index_writer) = self.index_writer.take() {
+                index_writer.finish(self.written_bytes)?;
+            }
             fs::rename(&self.temporary_filename, &self.final_filename)?;
         }
         Ok(())
     }
 
+    fn write_header_start(output_file: &mut fs::File, compression: CacheCompression) -> Result<(), Box<dyn Error>> {
+        output_file.write_all(CACHE_HEADER_MAGIC_STRING)?;
+        output_file.write_u32::<LittleEndian>(CACHE_HEADER_VERSION)?;
+        output_file.write_u32::<LittleEndian>(compression.to_codec_id())?;
+        Ok(())
+    }
+
     pub fn write_header(&mut self, vw_map: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
-        self.output_bufwriter.write(CACHE_HEADER_MAGIC_STRING)?;
-        self.output_bufwriter.write_u32::<LittleEndian>(CACHE_HEADER_VERSION)?;
-        vw_map.save_to_buf(&mut self.output_bufwriter)?;
+        let mut header: Vec<u8> = Vec::new();
+        vw_map.save_to_buf(&mut header)?;
+        header.write_u32::<LittleEndian>(self.timestamp_desc_offset.unwrap_or(0) as u32)?;
+        self.output_bufwriter.write_all(&header)?;
+        self.written_bytes += header.len() as u64;
         Ok(())
     }
 
-    pub fn verify_header(&mut self, vwmap: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
+    // Also write an index of example offsets next to the cache file, so the cache can be randomly accessed
+    pub fn write_index(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.writing {
+            return Ok(());
+        }
+        if self.compression != CacheCompression::None {
+            return Err("Cache index needs an uncompressed cache, use --cache_compression none")?;
+        }
+        self.index_writer = Some(cache_index::CacheIndexWriter::new(&self.final_filename)?);
+        Ok(())
+    }
+
+    // Reads the uncompressed part of the header and returns the compression codec id
+    pub fn verify_header_start(input_file: &mut fs::File) -> Result<u32, Box<dyn Error>> {
         let mut magic_string: [u8; 4] = [0;4];
-        self.input_bufreader.read(&mut magic_string)?;
+        input_file.read_exact(&mut magic_string)?;
         if &magic_string != CACHE_HEADER_MAGIC_STRING {
-            return Err("Cache header does not begin with magic bytes FWFW")?;
+            return Err("Cache header does not begin with magic bytes FWCA")?;
         }
         
-        let version = self.input_bufreader.read_u32::<LittleEndian>()?;
+        let version = input_file.read_u32::<LittleEndian>()?;
         if CACHE_HEADER_VERSION != version {
             return Err(format!("Cache file version of this binary: {}, version of the cache file: {}", CACHE_HEADER_VERSION, version))?;
         }
-        
+        Ok(input_file.read_u32::<LittleEndian>()?)
+    }
+
+    pub fn verify_header(&mut self, vwmap: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
         // Compare vwmap in cache and the one we've been given. If they differ, rebuild cache
         let vwmap_from_cache = vwmap::VwNamespaceMap::new_from_buf(&mut self.input_bufreader)?;
         if vwmap_from_cache.vw_source != vwmap.vw_source {
             return Err("vw_namespace_map.csv and the one from cache file differ")?;
         }
+        // Timestamp namespace holds timestamps instead of hashes, so it has to be the same one
+        let timestamp_desc_offset = self.input_bufreader.read_u32::<LittleEndian>()?;
+        if timestamp_desc_offset != self.timestamp_desc_offset.unwrap_or(0) as u32 {
+            return Err("Cache file was written with a different --timestamp_namespace")?;
+        }
         
         Ok(())
     }
     
 
+    fn damage_error(&self, what: String) -> Box<dyn Error> {
+        Box::new(IOError::new(ErrorKind::Other, format!("Cache file {} {} (block {}, byte {} of cached examples, {} good examples before it)",
+                                                        self.final_filename, what, self.num_read_blocks, self.total_read, self.total_read_records)))
+    }
+
+    fn read_exact_or_damaged(&mut self, buf: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        match self.input_bufreader.read_exact(buf) {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(self.damage_error("is truncated".to_string())),
+            Err(e) => return Err(self.damage_error(format!("can't be read: {}", e))),
+        }
+        self.total_read += buf.len();
+        Ok(())
+    }
+
+    fn read_u32_or_damaged(&mut self) -> Result<u32, Box<dyn Error>> {
+        let mut buf: [u8; 4] = [0; 4];
+        self.read_exact_or_damaged(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    // Reads the next block into block_buffer, returns false when the footer is reached
+    fn read_block(&mut self) -> Result<bool, Box<dyn Error>> {
+        if self.block_pointer != self.block_buffer.len() {
+            return Err(self.damage_error("is corrupted: block has data after its last example".to_string()));
+        }
+        let block_len = self.read_u32_or_damaged()?;
+        if block_len == FOOTER_MARKER {
+            let mut buf: [u8; 8] = [0; 8];
+            self.read_exact_or_damaged(&mut buf)?;
+            let total_records = u64::from_le_bytes(buf);
+            if total_records != self.total_read_records {
+                return Err(self.damage_error(format!("is corrupted: footer says it has {} examples", total_records)));
+            }
+            self.finished = true;
+            return Ok(false);
+        }
+        let num_records = self.read_u32_or_damaged()?;
+        let checksum = self.read_u32_or_damaged()?;
+        if block_len == 0 || block_len as usize > MAX_BLOCK_LEN || num_records == 0 {
+            return Err(self.damage_error(format!("is corrupted: invalid block header, length {}, examples {}", block_len, num_records)));
+        }
+        // Buffer is taken out while reading into it, so we can still report errors through self
+        let mut block_buffer = mem::take(&mut self.block_buffer);
+        block_buffer.resize(block_len as usize, 0);
+        let element_size = mem::size_of::<u32>();
+        // We're going to cast another view over the data, so we can read it as bytes
+        let vv:&mut [u8] = unsafe {
+            slice::from_raw_parts_mut(block_buffer.as_mut_ptr() as *mut u8, block_len as usize * element_size)
+        };
+        let result = self.read_exact_or_damaged(vv);
+        let checksum_ok = block_checksum(block_len, num_records, vv) == checksum;
+        self.block_buffer = block_buffer;
+        result?;
+        if !checksum_ok {
+            return Err(self.damage_error("is corrupted: block checksum mismatch".to_string()));
+        }
+        self.block_pointer = 0;
+        self.block_records_left = num_records;
+        self.num_read_blocks += 1;
+        Ok(true)
+    }
+
     pub fn get_next_record(&mut self) -> Result<&[u32], Box<dyn Error>> {
         if !self.reading {
             return Err("next_recrod() called on reading cache, when not opened in reading mode")?;
         }
-        unsafe { 
-            // We're going to cast another view over the data, so we can read it as u32
-            let buf_view:&[u32] = slice::from_raw_parts(self.byte_buffer.as_ptr() as *const u32, READBUF_LEN/4);
-            loop {
-                // Classical buffer strategy:
-                // Return if you have full record in buffer,
-                // Otherwise shift the buffer and backfill it
-                if self.end_pointer - self.start_pointer >= 4 {
-                    let record_len = buf_view[self.start_pointer /4 ] as usize;
-                    if self.start_pointer + record_len * 4 <= self.end_pointer {
-                        let ret_buf = &buf_view[self.start_pointer/4..self.start_pointer/4 + record_len];
-                        self.start_pointer += record_len * 4;
-                        return Ok(ret_buf);
-                    }
-                } 
-                self.byte_buffer.copy_within(self.start_pointer..self.end_pointer, 0);
-                self.end_pointer -= self.start_pointer;
-                self.start_pointer = 0;
+        if self.finished {
+            return Ok(&[]);
+        }
+        if self.block_records_left == 0 {
+            if !self.read_block()? {
+                return Ok(&[]);
+            }
+        }
+        let start = self.block_pointer;
+        if start >= self.block_buffer.len() {
+            return Err(self.damage_error(format!("is corrupted: block ends before its {} remaining examples", self.block_records_left)));
+        }
+        let record_len = self.block_buffer[start] as usize;
+        if record_len == 0 || start + record_len > self.block_buffer.len() {
+            return Err(self.damage_error(format!("is corrupted: example of length {} at word {} of the block", record_len, start)));
+        }
+        self.block_pointer += record_len;
+        self.block_records_left -= 1;
+        self.total_read_records += 1;
+        Ok(&self.block_buffer[start..start + record_len])
+    }
+
+    // Reads the whole cache file and returns the number of examples and blocks, or the description of the damage
+    pub fn verify_cache_file(cache_filename: &str) -> Result<(u64, u64), Box<dyn Error>> {
+        let mut rc = RecordCache::new_empty(cache_filename);
+        rc.input_bufreader = RecordCache::open_cache_reader(cache_filename)?;
+        RecordCache::read_header_vwmap(cache_filename, &mut rc.input_bufreader)?;
+        rc.input_bufreader.read_u32::<LittleEndian>()?;
+        rc.reading = true;
+        while rc.get_next_record()?.len() > 0 {}
+        Ok((rc.total_read_records, rc.num_read_blocks))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn test_cache_compression_parsing() {
+        assert_eq!(CacheCompression::from_str("none").unwrap(), CacheCompression::None);
+        assert_eq!(CacheCompression::from_str("lz4").unwrap(), CacheCompression::Lz4);
+        assert_eq!(CacheCompression::from_str("zstd").unwrap(), CacheCompression::Zstd(ZSTD_DEFAULT_LEVEL));
+        assert_eq!(CacheCompression::from_str("zstd:-5").unwrap(), CacheCompression::Zstd(-5));
+        assert!(CacheCompression::from_str("zstd:x").is_err());
+        assert!(CacheCompression::from_str("lz4:3").is_err());
+        assert!(CacheCompression::from_str("gzip").is_err());
+        assert_eq!(CacheCompression::default_for_input("train.vw.gz"), CacheCompression::Lz4);
+        assert_eq!(CacheCompression::default_for_input("train.vw"), CacheCompression::None);
+    }
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let records: Vec<Vec<u32>> = (0..1000).map(|i| vec![5, 1, i, i * 3, i * 7]).collect();
+        for compression in [CacheCompression::None, CacheCompression::Lz4, CacheCompression::Zstd(1)].iter() {
+            let dir = tempfile::tempdir().unwrap();
+            let cache_filename = dir.path().join("train.vw.fwcache");
+            let cache_filename = cache_filename.to_str().unwrap();
+
+            let mut rc = RecordCache::new(cache_filename, true, true, &vw, None, *compression).unwrap();
+            assert!(rc.writing);
+            for record in &records {
+                rc.push_record(record).unwrap();
+            }
+            rc.write_finish().unwrap();
+
+            // Codec is detected from the header, the one passed in is ignored when reading
+            assert_eq!(RecordCache::vwmap_from_cache_file(cache_filename).unwrap().vw_source, vw.vw_source);
+            let mut rc = RecordCache::new(cache_filename, true, true, &vw, None, CacheCompression::None).unwrap();
+            assert!(rc.reading, "{:?}", compression);
+            for record in &records {
+                assert_eq!(rc.get_next_record().unwrap(), &record[..]);
+            }
+            assert_eq!(rc.get_next_record().unwrap().len(), 0);
+        }
+    }
+
+    #[test]
+    fn test_cache_compressed_stream_is_finished() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        for compression in [CacheCompression::Lz4, CacheCompression::Zstd(1)].iter() {
+            let dir = tempfile::tempdir().unwrap();
+            let cache_filename = dir.path().join("train.vw.fwcache");
+            let cache_filename = cache_filename.to_str().unwrap();
+            let mut rc = RecordCache::new(cache_filename, true, true, &vw, None, *compression).unwrap();
+            rc.push_record(&[4, 1, 2, 3]).unwrap();
+            rc.write_finish().unwrap();
+
+            // The compressed stream after the uncompressed start of the header has its end mark
+            let mut input_file = fs::File::open(cache_filename).unwrap();
+            RecordCache::verify_header_start(&mut input_file).unwrap();
+            let mut data = Vec::new();
+            match compression {
+                CacheCompression::Lz4 => {
+                    let mut decoder = lz4::Decoder::new(input_file).unwrap();
+                    decoder.read_to_end(&mut data).unwrap();
+                    decoder.finish().1.unwrap();
+                },
+                _ => {
+                    // zstd decoder fails on a stream without its last frame
+                    let mut decoder = zstd::stream::read::Decoder::new(input_file).unwrap();
+                    decoder.read_to_end(&mut data).unwrap();
+                }
+            }
+            assert!(data.len() > 0);
+        }
+    }
 
-                let read_len = match self.input_bufreader.read(&mut self.byte_buffer[self.end_pointer..READBUF_LEN])  {
-                    Ok(0) => return Ok(&[]),
-                    Ok(n) => n,
-                    Err(e) => Err(e)?          
-                };
+    #[test]
+    fn test_cache_without_input() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cache_filename = dir.path().join("train.vw.fwcache");
+        let cache_filename = cache_filename.to_str().unwrap();
+        // Missing cache and nothing to write it from: no temporary file is left behind
+        let rc = RecordCache::new(cache_filename, true, false, &vw, None, CacheCompression::None).unwrap();
+        assert!(!rc.reading && !rc.writing);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
 
-                self.end_pointer += read_len;
-                self.total_read += read_len;
-            }            
+        // Cache in a directory that doesn't exist can't be written
+        let cache_filename = dir.path().join("missing").join("train.vw.fwcache");
+        let cache_filename = cache_filename.to_str().unwrap();
+        match RecordCache::new(cache_filename, true, true, &vw, None, CacheCompression::None) {
+            Err(e) => assert!(e.to_string().starts_with(&format!("Cannot write cache file {}.writing", cache_filename)), "{}", e),
+            Ok(_) => panic!("cache in a missing directory was opened for writing"),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_cache_timestamp_namespace() {
+        let vw_map_string = r#"
+A,featureA
+T,time
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cache_filename = dir.path().join("train.vw.fwcache");
+        let cache_filename = cache_filename.to_str().unwrap();
+        let mut rc = RecordCache::new(cache_filename, true, true, &vw, Some(4), CacheCompression::None).unwrap();
+        rc.push_record(&[5, 1, parser::FLOAT32_ONE, 7, 1600000000]).unwrap();
+        rc.write_finish().unwrap();
+        assert_eq!(RecordCache::verify_cache_file(cache_filename).unwrap(), (1, 1));
+
+        // Timestamps would be read as hashes, or hashes as timestamps
+        let rc = RecordCache::new(cache_filename, true, false, &vw, None, CacheCompression::None).unwrap();
+        assert!(!rc.reading);
+        let rc = RecordCache::new(cache_filename, true, false, &vw, Some(3), CacheCompression::None).unwrap();
+        assert!(!rc.reading);
+        let mut rc = RecordCache::new(cache_filename, true, false, &vw, Some(4), CacheCompression::None).unwrap();
+        assert!(rc.reading);
+        assert_eq!(rc.get_next_record().unwrap(), &[5, 1, parser::FLOAT32_ONE, 7, 1600000000]);
+    }
+
+    fn write_test_cache(cache_filename: &str, vw: &vwmap::VwNamespaceMap, records: &Vec<Vec<u32>>) {
+        let mut rc = RecordCache::new(cache_filename, true, true, vw, None, CacheCompression::None).unwrap();
+        for record in records {
+            rc.push_record(record).unwrap();
+        }
+        rc.write_finish().unwrap();
+    }
+
+    #[test]
+    fn test_cache_damage() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        // A few blocks worth of examples, plus one example longer than a block
+        let mut records: Vec<Vec<u32>> = (0..30000).map(|i| vec![4, 1, i, i * 3]).collect();
+        let mut long_record = vec![0; BLOCK_LEN * 3];
+        long_record[0] = (BLOCK_LEN * 3) as u32;
+        records.insert(100, long_record);
+        let dir = tempfile::tempdir().unwrap();
+        let cache_filename = dir.path().join("train.vw.fwcache");
+        let cache_filename = cache_filename.to_str().unwrap();
+
+        write_test_cache(cache_filename, &vw, &records);
+        let (num_records, num_blocks) = RecordCache::verify_cache_file(cache_filename).unwrap();
+        assert_eq!(num_records, records.len() as u64);
+        assert!(num_blocks > 3);
+        let mut rc = RecordCache::new(cache_filename, true, true, &vw, None, CacheCompression::None).unwrap();
+        for record in &records {
+            assert_eq!(rc.get_next_record().unwrap(), &record[..]);
+        }
+        assert_eq!(rc.get_next_record().unwrap().len(), 0);
+        assert_eq!(rc.get_next_record().unwrap().len(), 0);
+
+        // Truncated file, footer is missing
+        let data = fs::read(cache_filename).unwrap();
+        fs::write(cache_filename, &data[..data.len() - 5]).unwrap();
+        let e = format!("{}", RecordCache::verify_cache_file(cache_filename).err().unwrap());
+        assert!(e.contains("is truncated"), "{}", e);
+        // Truncated in the middle of a block
+        fs::write(cache_filename, &data[..data.len() / 2]).unwrap();
+        let e = format!("{}", RecordCache::verify_cache_file(cache_filename).err().unwrap());
+        assert!(e.contains("is truncated"), "{}", e);
+
+        // Flipped bit in the middle
+        let mut damaged = data.clone();
+        let middle = damaged.len() / 2;
+        damaged[middle] ^= 0x10;
+        fs::write(cache_filename, &damaged).unwrap();
+        let e = format!("{}", RecordCache::verify_cache_file(cache_filename).err().unwrap());
+        assert!(e.contains("is corrupted"), "{}", e);
+        // Reading stops with an error, only after returning the good examples before the damage
+        let mut rc = RecordCache::new(cache_filename, true, true, &vw, None, CacheCompression::None).unwrap();
+        let mut good_records = 0;
+        loop {
+            match rc.get_next_record() {
+                Ok(buffer) => { assert!(buffer.len() > 0); good_records += 1},
+                Err(_) => break
+            }
+        }
+        assert!(good_records > 0 && good_records < records.len());
+
+        // Number of examples in the header of the first block is larger than the block holds
+        let empty_cache_filename = dir.path().join("empty.vw.fwcache");
+        let empty_cache_filename = empty_cache_filename.to_str().unwrap();
+        write_test_cache(empty_cache_filename, &vw, &Vec::new());
+        let num_records_offset = fs::read(empty_cache_filename).unwrap().len() - 12 + 4;
+        let mut damaged = data.clone();
+        let num_records = u32::from_le_bytes([damaged[num_records_offset], damaged[num_records_offset + 1],
+                                              damaged[num_records_offset + 2], damaged[num_records_offset + 3]]);
+        damaged[num_records_offset..num_records_offset + 4].copy_from_slice(&(num_records + 1).to_le_bytes());
+        fs::write(cache_filename, &damaged).unwrap();
+        let e = format!("{}", RecordCache::verify_cache_file(cache_filename).err().unwrap());
+        assert!(e.contains("is corrupted: block checksum mismatch"), "{}", e);
+        // Even when the checksum can't tell, reading past the end of the block is an error
+        let mut rc = RecordCache::new(cache_filename, true, true, &vw, None, CacheCompression::None).unwrap();
+        rc.block_buffer = vec![4, 1, 0, 0];
+        rc.block_pointer = 0;
+        rc.block_records_left = 2;
+        assert_eq!(rc.get_next_record().unwrap(), &[4, 1, 0, 0]);
+        let e = format!("{}", rc.get_next_record().err().unwrap());
+        assert!(e.contains("is corrupted: block ends before its 1 remaining examples"), "{}", e);
+    }
+}
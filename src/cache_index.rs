@@ -0,0 +1,346 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use merand48::*;
+
+use crate::cache;
+use crate::parser;
+use crate::sampling;
+
+const INDEX_HEADER_MAGIC_STRING: &[u8; 4] = b"FWCI";    // Fwumious Wabbit Cache Index
+const INDEX_HEADER_VERSION:u32 = 1;
+const INDEX_HEADER_LEN:u64 = 24;
+const MAX_RECORD_LEN:u32 = 1 << 26;
+
+// Index layout, it lives next to an uncompressed cache file as {cache_filename}.index:
+// 4 bytes: Magic bytes
+// u32: Version of the index format
+// u64: Length of the cache file the index was written for
+// u64: Number of examples
+// u64 for each example: (offset of the example in cache file << 2) | label code
+//
+// Label code (0 - negative, 1 - positive, 2 - no label) allows sampling by label without touching the cache
+
+const LABEL_CODE_NEGATIVE:u64 = 0;
+const LABEL_CODE_POSITIVE:u64 = 1;
+const LABEL_CODE_NONE:u64 = 2;
+
+pub fn index_filename(cache_filename: &str) -> String {
+    format!("{}.index", cache_filename)
+}
+
+fn index_error(message: String) -> Box<dyn Error> {
+    Box::new(IOError::new(ErrorKind::Other, message))
+}
+
+pub struct CacheIndexWriter {
+    output_bufwriter: io::BufWriter<fs::File>,
+    temporary_filename: String,
+    final_filename: String,
+    num_records: u64,
+}
+
+impl CacheIndexWriter {
+    pub fn new(cache_filename: &str) -> Result<CacheIndexWriter, Box<dyn Error>> {
+        let final_filename = index_filename(cache_filename);
+        let temporary_filename = format!("{}.writing", final_filename);
+        let mut iw = CacheIndexWriter {
+            output_bufwriter: io::BufWriter::new(fs::File::create(&temporary_filename)?),
+            temporary_filename: temporary_filename,
+            final_filename: final_filename,
+            num_records: 0,
+        };
+        // Lengths are not known yet, they are filled in by finish()
+        iw.output_bufwriter.write_all(INDEX_HEADER_MAGIC_STRING)?;
+        iw.output_bufwriter.write_u32::<LittleEndian>(INDEX_HEADER_VERSION)?;
+        iw.output_bufwriter.write_u64::<LittleEndian>(0)?;
+        iw.output_bufwriter.write_u64::<LittleEndian>(0)?;
+        Ok(iw)
+    }
+
+    pub fn push(&mut self, offset: u64, record_buf: &[u32]) -> Result<(), Box<dyn Error>> {
+        let label_code = match record_buf[parser::LABEL_OFFSET] {
+            0 => LABEL_CODE_NEGATIVE,
+            parser::NO_LABEL => LABEL_CODE_NONE,
+            _ => LABEL_CODE_POSITIVE,
+        };
+        self.output_bufwriter.write_u64::<LittleEndian>((offset << 2) | label_code)?;
+        self.num_records += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self, cache_file_len: u64) -> Result<(), Box<dyn Error>> {
+        self.output_bufwriter.seek(SeekFrom::Start(8))?;
+        self.output_bufwriter.write_u64::<LittleEndian>(cache_file_len)?;
+        self.output_bufwriter.write_u64::<LittleEndian>(self.num_records)?;
+        self.output_bufwriter.flush()?;
+        fs::rename(&self.temporary_filename, &self.final_filename)?;
+        Ok(())
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleOptions {
+    pub sample_rate: f32,               // keep each example with this probability
+    pub negative_rate: f32,             // keep each negative example with this probability, importance is scaled by 1/rate
+    pub range: Option<(u64, u64)>,      // only examples with numbers in [start, end)
+    pub seed: u64,
+}
+
+impl SampleOptions {
+    pub fn new_from_cmdline(cl: &clap::ArgMatches) -> Result<Option<SampleOptions>, Box<dyn Error>> {
+        if !cl.is_present("cache_sample_rate") && !cl.is_present("cache_negative_rate") && !cl.is_present("cache_range") {
+            return Ok(None);
+        }
+        let mut so = SampleOptions {
+            sample_rate: 1.0,
+            negative_rate: 1.0,
+            range: None,
+            seed: 0,
+        };
+        if let Some(val) = cl.value_of("cache_sample_rate") {
+            so.sample_rate = parse_rate(val, "--cache_sample_rate")?;
+        }
+        if let Some(val) = cl.value_of("cache_negative_rate") {
+            so.negative_rate = parse_rate(val, "--cache_negative_rate")?;
+        }
+        if let Some(val) = cl.value_of("cache_range") {
+            so.range = Some(parse_range(val)?);
+        }
+        if let Some(val) = cl.value_of("random_seed") {
+            so.seed = val.parse()?;
+        }
+        Ok(Some(so))
+    }
+}
+
+fn parse_rate(val: &str, arg_name: &str) -> Result<f32, Box<dyn Error>> {
+    match val.parse::<f32>() {
+        Ok(rate) if rate > 0.0 && rate <= 1.0 => Ok(rate),
+        _ => Err(index_error(format!("{} has to be a number in (0, 1], got: {}", arg_name, val)))
+    }
+}
+
+// Parses "start:end", either side can be left out
+fn parse_range(val: &str) -> Result<(u64, u64), Box<dyn Error>> {
+    let vsplit: Vec<&str> = val.split(":").collect();
+    if vsplit.len() != 2 {
+        return Err(index_error(format!("--cache_range has to be start:end, got: {}", val)));
+    }
+    let start = if vsplit[0].is_empty() { 0 } else { vsplit[0].parse()? };
+    let end = if vsplit[1].is_empty() { u64::MAX } else { vsplit[1].parse()? };
+    if start >= end {
+        return Err(index_error(format!("--cache_range start has to be smaller than end, got: {}", val)));
+    }
+    Ok((start, end))
+}
+
+
+// Reads examples of an uncompressed cache through its index, in file order, skipping the ones not sampled
+pub struct IndexedCacheReader {
+    cache_filename: String,
+    cache_bufreader: io::BufReader<fs::File>,
+    cache_position: u64,
+    index_bufreader: io::BufReader<fs::File>,
+    options: SampleOptions,
+    negative_downsampling: sampling::NegativeDownsampling,
+    record_num: u64,
+    end_record_num: u64,
+    record_buffer: Vec<u32>,
+}
+
+impl IndexedCacheReader {
+    pub fn new(cache_filename: &str, options: SampleOptions) -> Result<IndexedCacheReader, Box<dyn Error>> {
+        let mut cache_file = fs::File::open(cache_filename)?;
+        let codec_id = cache::RecordCache::verify_header_start(&mut cache_file)?;
+        if codec_id != 0 {
+            return Err(index_error(format!("Cache file {} is compressed, random access needs a cache built with --cache_compression none", cache_filename)));
+        }
+        let index_filename = index_filename(cache_filename);
+        let index_file = match fs::File::open(&index_filename) {
+            Ok(f) => f,
+            Err(e) => return Err(index_error(format!("Cannot open cache index {} ({}), build the cache with --cache_index", index_filename, e)))
+        };
+        let mut index_bufreader = io::BufReader::new(index_file);
+        let mut magic_string: [u8; 4] = [0;4];
+        index_bufreader.read_exact(&mut magic_string)?;
+        if &magic_string != INDEX_HEADER_MAGIC_STRING {
+            return Err(index_error(format!("Cache index {} does not begin with magic bytes FWCI", index_filename)));
+        }
+        let version = index_bufreader.read_u32::<LittleEndian>()?;
+        if version != INDEX_HEADER_VERSION {
+            return Err(index_error(format!("Cache index version of this binary: {}, version of the index file: {}", INDEX_HEADER_VERSION, version)));
+        }
+        let cache_file_len = index_bufreader.read_u64::<LittleEndian>()?;
+        if cache_file_len != cache_file.metadata()?.len() {
+            return Err(index_error(format!("Cache index {} was not written for the current cache file {}", index_filename, cache_filename)));
+        }
+        let num_records = index_bufreader.read_u64::<LittleEndian>()?;
+        let (start, end) = options.range.unwrap_or((0, num_records));
+        let start = start.min(num_records);
+        index_bufreader.seek(SeekFrom::Start(INDEX_HEADER_LEN + start * 8))?;
+
+        Ok(IndexedCacheReader {
+            cache_filename: cache_filename.to_string(),
+            cache_bufreader: io::BufReader::new(cache_file),
+            cache_position: 12,
+            index_bufreader: index_bufreader,
+            negative_downsampling: sampling::NegativeDownsampling::new(options.negative_rate, false),
+            options: options,
+            record_num: start,
+            end_record_num: end.min(num_records),
+            record_buffer: Vec::new(),
+        })
+    }
+
+    // Same decision for the same example and seed, regardless of the range being read
+    fn keep(&self, label_code: u64) -> bool {
+        let seed = self.options.seed.wrapping_add(self.record_num.wrapping_mul(2));
+        if self.options.sample_rate < 1.0 && merand48(seed) >= self.options.sample_rate {
+            return false;
+        }
+        if label_code == LABEL_CODE_NEGATIVE && self.options.negative_rate < 1.0 {
+            return self.negative_downsampling.sample_negative(merand48(seed + 1)).is_some();
+        }
+        true
+    }
+
+    pub fn get_next_record(&mut self) -> Result<&[u32], Box<dyn Error>> {
+        let entry = loop {
+            if self.record_num >= self.end_record_num {
+                return Ok(&[]);
+            }
+            let entry = self.index_bufreader.read_u64::<LittleEndian>()?;
+            let keep = self.keep(entry & 3);
+            self.record_num += 1;
+            if keep {
+                break entry;
+            }
+        };
+        let offset = entry >> 2;
+        // Examples are read in file order, so seeking is always forward and mostly within the buffer
+        self.cache_bufreader.seek_relative(offset as i64 - self.cache_position as i64)?;
+        let record_len = self.cache_bufreader.read_u32::<LittleEndian>()?;
+        if record_len == 0 || record_len > MAX_RECORD_LEN {
+            return Err(index_error(format!("Cache file {} is corrupted: example {} at offset {} has length {}", self.cache_filename, self.record_num - 1, offset, record_len)));
+        }
+        self.record_buffer.resize(record_len as usize, 0);
+        self.record_buffer[0] = record_len;
+        self.cache_bufreader.read_u32_into::<LittleEndian>(&mut self.record_buffer[1..])?;
+        self.cache_position = offset + record_len as u64 * 4;
+
+        if entry & 3 == LABEL_CODE_NEGATIVE && self.options.negative_rate < 1.0 {
+            let importance = f32::from_bits(self.record_buffer[parser::EXAMPLE_IMPORTANCE_OFFSET]) * self.negative_downsampling.importance_multiplier;
+            self.record_buffer[parser::EXAMPLE_IMPORTANCE_OFFSET] = importance.to_bits();
+        }
+        Ok(&self.record_buffer)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::vwmap;
+
+    fn options(sample_rate: f32, negative_rate: f32, range: Option<(u64, u64)>) -> SampleOptions {
+        SampleOptions {sample_rate: sample_rate, negative_rate: negative_rate, range: range, seed: 7}
+    }
+
+    fn read_all(cache_filename: &str, so: SampleOptions) -> Vec<Vec<u32>> {
+        let mut ir = IndexedCacheReader::new(cache_filename, so).unwrap();
+        let mut records: Vec<Vec<u32>> = Vec::new();
+        loop {
+            match ir.get_next_record().unwrap() {
+                [] => break,
+                buffer => records.push(buffer.to_vec())
+            }
+        }
+        records
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("10:20").unwrap(), (10, 20));
+        assert_eq!(parse_range(":20").unwrap(), (0, 20));
+        assert_eq!(parse_range("10:").unwrap(), (10, u64::MAX));
+        assert!(parse_range("20:10").is_err());
+        assert!(parse_range("20").is_err());
+        assert!(parse_rate("0", "--x").is_err());
+        assert!(parse_rate("1.5", "--x").is_err());
+        assert_eq!(parse_rate("0.5", "--x").unwrap(), 0.5);
+    }
+
+    #[test]
+    fn test_indexed_cache() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cache_filename = dir.path().join("train.vw.fwcache");
+        let cache_filename = cache_filename.to_str().unwrap();
+        // Every third example is positive, examples have different lengths
+        let records: Vec<Vec<u32>> = (0..50000u32).map(|i| {
+            let mut r = vec![0, (i % 3 == 0) as u32, parser::FLOAT32_ONE, i];
+            r.resize(4 + (i % 5) as usize, i);
+            r[0] = r.len() as u32;
+            r
+        }).collect();
+
+        // Index can only be written for uncompressed caches
+        let mut rc = cache::RecordCache::new(cache_filename, true, true, &vw, None, cache::CacheCompression::Lz4).unwrap();
+        assert!(rc.write_index().is_err());
+        rc.write_finish().unwrap();
+        fs::remove_file(cache_filename).unwrap();
+        assert!(IndexedCacheReader::new(cache_filename, options(1.0, 1.0, None)).is_err());
+
+        let mut rc = cache::RecordCache::new(cache_filename, true, true, &vw, None, cache::CacheCompression::None).unwrap();
+        rc.write_index().unwrap();
+        for record in &records {
+            rc.push_record(record).unwrap();
+        }
+        rc.write_finish().unwrap();
+
+        // Full read returns the same examples
+        assert_eq!(read_all(cache_filename, options(1.0, 1.0, None)), records);
+
+        // Range
+        assert_eq!(read_all(cache_filename, options(1.0, 1.0, Some((49990, 60000)))), records[49990..].to_vec());
+        assert_eq!(read_all(cache_filename, options(1.0, 1.0, Some((100, 200)))), records[100..200].to_vec());
+
+        // Random sampling keeps roughly the right share and is reproducible
+        let sampled = read_all(cache_filename, options(0.1, 1.0, None));
+        assert!(sampled.len() > 4500 && sampled.len() < 5500, "{}", sampled.len());
+        assert_eq!(sampled, read_all(cache_filename, options(0.1, 1.0, None)));
+        // The same example is sampled the same way when reading a range
+        let sampled_range = read_all(cache_filename, options(0.1, 1.0, Some((1000, 2000))));
+        let expected: Vec<Vec<u32>> = sampled.iter().filter(|r| r[3] >= 1000 && r[3] < 2000).cloned().collect();
+        assert_eq!(sampled_range, expected);
+
+        // Negative downsampling keeps all positives and corrects importance of negatives
+        let sampled = read_all(cache_filename, options(1.0, 0.25, None));
+        let positives: Vec<&Vec<u32>> = sampled.iter().filter(|r| r[1] == 1).collect();
+        let negatives: Vec<&Vec<u32>> = sampled.iter().filter(|r| r[1] == 0).collect();
+        assert_eq!(positives.len(), 16667);
+        assert!(negatives.len() > 7800 && negatives.len() < 8900, "{}", negatives.len());
+        assert!(positives.iter().all(|r| r[2] == parser::FLOAT32_ONE));
+        assert!(negatives.iter().all(|r| r[2] == 4.0f32.to_bits()));
+
+        // Index of a different cache file is refused
+        let mut rc = cache::RecordCache::new(&format!("{}.new", cache_filename), true, true, &vw, None, cache::CacheCompression::None).unwrap();
+        rc.push_record(&records[0]).unwrap();
+        rc.write_finish().unwrap();
+        fs::rename(format!("{}.new", cache_filename), cache_filename).unwrap();
+        assert!(IndexedCacheReader::new(cache_filename, options(1.0, 1.0, None)).is_err());
+    }
+}
@@ -0,0 +1,432 @@
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use merand48::*;
+
+use crate::feature_buffer::FeatureBufferTranslator;
+use crate::ldf::LdfExample;
+use crate::model_instance;
+use crate::model_instance::CbType;
+use crate::parser;
+use crate::regressor::ImmutableRegressor;
+use crate::regressor::RegressorTrait;
+
+/*
+Contextual bandit with action dependent features (vowpal's --cb_explore_adf).
+Examples are read like --csoaa_ldf ones, but only the logged action has a label: its cost and the probability
+the logging policy chose it with. The regressor is the reward (cost) model of every action, the policy chooses
+the action with the lowest predicted cost and explores around it.
+What the reward model learns from the logged action depends on --cb_type (c is cost, p probability of the logged
+action, c' predicted cost, all of them between 0 and 1):
+ - ips: the logged action is learned with target c and importance 1/p (clipped to IPS_MAX_IMPORTANCE), so in
+        expectation over the logging policy every action is learned towards its cost. Vowpal's c/p targets would
+        mostly be above 1, out of reach of logistic loss.
+ - dr:  the logged action is learned with target c' + (c - c')/p, clipped to [0, 1]
+ - dm:  the logged action is learned with target c
+The same estimator evaluates the exploring policy on the logged data, progressively, before each example is learned.
+Exploration is seeded from entropy, unless --random_seed asks for a reproducible run.
+*/
+
+// Rarely logged actions would otherwise take huge learning steps
+pub const IPS_MAX_IMPORTANCE: f32 = 100.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exploration {
+    EpsilonGreedy(f32),     // epsilon
+    Softmax(f32),           // lambda
+}
+
+impl Exploration {
+    pub fn new_from_cmdline<'a>(cl: &clap::ArgMatches<'a>) -> Result<Exploration, Box<dyn Error>> {
+        if cl.is_present("cb_softmax") {
+            if cl.is_present("epsilon") {
+                return Err(Box::new(IOError::new(ErrorKind::Other, "Use either --epsilon or --cb_softmax, not both")))
+            }
+            let lambda: f32 = match cl.value_of("lambda") {
+                Some(val) => val.parse()?,
+                None => 10.0
+            };
+            return Ok(Exploration::Softmax(lambda));
+        }
+        if cl.is_present("lambda") {
+            return Err(Box::new(IOError::new(ErrorKind::Other, "--lambda needs --cb_softmax")))
+        }
+        let epsilon: f32 = match cl.value_of("epsilon") {
+            Some(val) => val.parse()?,
+            None => 0.05
+        };
+        if !(0.0..=1.0).contains(&epsilon) {
+            return Err(Box::new(IOError::new(ErrorKind::Other, format!("--epsilon has to be between 0 and 1, got: {}", epsilon))))
+        }
+        Ok(Exploration::EpsilonGreedy(epsilon))
+    }
+
+    // Probabilities of choosing each of the actions, given their predicted costs
+    pub fn pmf(&self, costs: &[f32], pmf: &mut Vec<f32>) {
+        pmf.truncate(0);
+        if costs.is_empty() {
+            return;
+        }
+        let mut best = 0;
+        for (action, cost) in costs.iter().enumerate() {
+            if *cost < costs[best] {
+                best = action;
+            }
+        }
+        match *self {
+            Exploration::EpsilonGreedy(epsilon) => {
+                pmf.resize(costs.len(), epsilon / costs.len() as f32);
+                pmf[best] += 1.0 - epsilon;
+            },
+            Exploration::Softmax(lambda) => {
+                let lowest = costs[best];
+                pmf.extend(costs.iter().map(|c| (-lambda * (c - lowest)).exp()));
+                let sum: f32 = pmf.iter().sum();
+                for p in pmf.iter_mut() {
+                    *p /= sum;
+                }
+            }
+        }
+    }
+}
+
+// Action where r (uniform on [0, 1)) falls in the cumulative distribution
+pub fn sample(pmf: &[f32], r: f32) -> usize {
+    let mut cumulative = 0.0;
+    for (action, p) in pmf.iter().enumerate() {
+        cumulative += p;
+        if r < cumulative {
+            return action;
+        }
+    }
+    pmf.len() - 1   // rounding
+}
+
+// Logged action of the example: (action, cost, probability)
+pub fn logged_action(example: &LdfExample) -> Option<(usize, f32, f32)> {
+    for action in 0..example.len() {
+        let record = example.action_record(action);
+        if record[parser::LABEL_OFFSET] != parser::NO_LABEL {
+            return Some((action,
+                         f32::from_bits(record[parser::LABEL_OFFSET]),
+                         f32::from_bits(record[parser::EXAMPLE_IMPORTANCE_OFFSET])));
+        }
+    }
+    None
+}
+
+#[derive(Clone)]
+pub struct ContextualBandit {
+    cb_type: CbType,
+    exploration: Exploration,
+    seed: u64,
+    pmf: Vec<f32>,
+    chosen_action: usize,
+    estimate_sum: f64,
+    estimate_count: u64,
+}
+
+impl ContextualBandit {
+    // Every stream (daemon's worker thread) explores with its own random sequence
+    pub fn new_from_cmdline<'a>(cl: &clap::ArgMatches<'a>, mi: &model_instance::ModelInstance, stream: u64) -> Result<Option<ContextualBandit>, Box<dyn Error>> {
+        if !mi.cb_explore_adf {
+            if cl.is_present("cb_softmax") || cl.is_present("epsilon") || cl.is_present("lambda") {
+                return Err(Box::new(IOError::new(ErrorKind::Other, "--cb_softmax, --epsilon and --lambda need --cb_explore_adf")))
+            }
+            return Ok(None);
+        }
+        let seed: u64 = match cl.value_of("random_seed") {
+            Some(seed) => seed.parse::<u64>()? + (stream << 32),
+            None => RandomState::new().build_hasher().finish(),
+        };
+        Ok(ContextualBandit::new(mi, Exploration::new_from_cmdline(cl)?, seed))
+    }
+
+    pub fn new(mi: &model_instance::ModelInstance, exploration: Exploration, seed: u64) -> Option<ContextualBandit> {
+        if !mi.cb_explore_adf {
+            return None;
+        }
+        Some(ContextualBandit {
+            cb_type: mi.cb_type,
+            exploration: exploration,
+            seed: seed,
+            pmf: Vec::new(),
+            chosen_action: 0,
+            estimate_sum: 0.0,
+            estimate_count: 0,
+        })
+    }
+
+    // Chooses an action given predicted costs, returns the probability it was chosen with
+    fn explore(&mut self, costs: &[f32]) -> f32 {
+        self.exploration.pmf(costs, &mut self.pmf);
+        if self.pmf.is_empty() {
+            return 0.0;
+        }
+        self.chosen_action = sample(&self.pmf, merand48(self.seed));
+        self.seed = self.seed.wrapping_add(1);
+        self.pmf[self.chosen_action]
+    }
+
+    // Estimated cost of the exploring policy (whose probabilities are in pmf) on the logged action
+    fn estimate(&self, costs: &[f32], logged: usize, cost: f32, probability: f32) -> f32 {
+        let direct: f32 = self.pmf.iter().zip(costs.iter()).map(|(p, c)| p * c).sum();
+        match self.cb_type {
+            CbType::IPS => self.pmf[logged] * cost / probability,
+            CbType::DR => direct + self.pmf[logged] * (cost - costs[logged]) / probability,
+            CbType::DM => direct,
+        }
+    }
+
+    // Predicts costs of all actions into costs, chooses an action and, when update is set, learns from
+    // the logged action. The example is consumed. Returns the probability of the chosen action.
+    pub fn learn(&mut self, example: &mut LdfExample, re: &mut dyn RegressorTrait, fbt: &mut FeatureBufferTranslator, update: bool, example_num: u32, costs: &mut Vec<f32>) -> f32 {
+        costs.truncate(0);
+        for action in 0..example.len() {
+            example.translate_action(action, fbt);
+            costs.push(re.learn(&fbt.feature_buffer, false, example_num));
+        }
+        let probability = self.explore(costs);
+        if let Some((logged, cost, logged_probability)) = logged_action(example) {
+            self.estimate_sum += self.estimate(costs, logged, cost, logged_probability) as f64;
+            self.estimate_count += 1;
+            if update {
+                let (target, importance) = match self.cb_type {
+                    CbType::IPS => (cost, (1.0 / logged_probability).min(IPS_MAX_IMPORTANCE)),
+                    CbType::DR => ((costs[logged] + (cost - costs[logged]) / logged_probability).clamp(0.0, 1.0), 1.0),
+                    CbType::DM => (cost, 1.0),
+                };
+                example.translate_action(logged, fbt);
+                fbt.feature_buffer.label = target;
+                fbt.feature_buffer.example_importance = importance;
+                re.learn(&fbt.feature_buffer, true, example_num);
+            }
+        }
+        example.clear();
+        probability
+    }
+
+    pub fn predict(&mut self, example: &mut LdfExample, re: &ImmutableRegressor, fbt: &mut FeatureBufferTranslator, example_num: u32, costs: &mut Vec<f32>) -> f32 {
+        costs.truncate(0);
+        for action in 0..example.len() {
+            example.translate_action(action, fbt);
+            costs.push(re.predict(&fbt.feature_buffer, example_num));
+        }
+        example.clear();
+        self.explore(costs)
+    }
+
+    // Chosen action (counted from 1) and its probability
+    pub fn format_prediction(&self) -> String {
+        if self.pmf.is_empty() {
+            return "\n".to_string();
+        }
+        format!("{}:{:.6}\n", self.chosen_action + 1, self.pmf[self.chosen_action])
+    }
+
+    pub fn print_policy_cost_estimate(&self) {
+        if self.estimate_count > 0 {
+            println!("Policy cost estimate ({:?}): {:.6} over {} logged examples", self.cb_type, self.estimate_sum / self.estimate_count as f64, self.estimate_count);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::optimizer;
+    use crate::regressor::Regressor;
+    use crate::vwmap;
+    use std::io::Cursor;
+
+    fn vw_map() -> vwmap::VwNamespaceMap {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        vwmap::VwNamespaceMap::new(vw_map_string).unwrap()
+    }
+
+    fn read_example(pa: &mut parser::VowpalParser, example: &mut LdfExample, s: &str) {
+        let mut buf = Cursor::new(s.as_bytes().to_vec());
+        loop {
+            let record = pa.next(&mut buf).unwrap();
+            if record.is_empty() || example.push(record).unwrap() {
+                break;
+            }
+        }
+    }
+
+    fn cb_mi(cb_type: CbType) -> model_instance::ModelInstance {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.cb_explore_adf = true;
+        mi.cb_type = cb_type;
+        // Shared features interact with action features
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {feature_indices: vec![0, 1], weight: 1.0});
+        mi
+    }
+
+    #[test]
+    fn test_exploration_pmf() {
+        let mut pmf = Vec::new();
+        Exploration::EpsilonGreedy(0.3).pmf(&[0.5, 0.2, 0.2], &mut pmf);
+        assert_eq!(pmf, vec![0.1, 0.8, 0.1]);
+        Exploration::EpsilonGreedy(0.0).pmf(&[0.5, 0.2], &mut pmf);
+        assert_eq!(pmf, vec![0.0, 1.0]);
+        Exploration::Softmax(0.0).pmf(&[0.5, 0.2], &mut pmf);
+        assert_eq!(pmf, vec![0.5, 0.5]);
+        // Lower cost is more probable
+        Exploration::Softmax(10.0).pmf(&[0.5, 0.2, 0.5], &mut pmf);
+        assert!(pmf[1] > 0.9 && (pmf[0] - pmf[2]).abs() < 1e-6);
+        assert!((pmf.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        Exploration::Softmax(10.0).pmf(&[], &mut pmf);
+        assert!(pmf.is_empty());
+    }
+
+    #[test]
+    fn test_sample() {
+        assert_eq!(sample(&[0.25, 0.5, 0.25], 0.0), 0);
+        assert_eq!(sample(&[0.25, 0.5, 0.25], 0.3), 1);
+        assert_eq!(sample(&[0.25, 0.5, 0.25], 0.8), 2);
+        assert_eq!(sample(&[0.0, 1.0], 0.0), 1);
+    }
+
+    #[test]
+    fn test_policy_cost_estimate() {
+        let vw = vw_map();
+        let mut pa = parser::VowpalParser::new(&vw);
+        pa.ldf = true;
+        pa.cb = true;
+        let mut example = LdfExample::new(&vw);
+        let mut costs = Vec::new();
+        // Untrained model predicts cost 0.5 of both actions, the greedy policy chooses the first one
+        for (cb_type, estimate) in [(CbType::IPS, 1.6), (CbType::DR, 1.1), (CbType::DM, 0.5)].iter() {
+            let mi = cb_mi(*cb_type);
+            let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+            let mut fbt = FeatureBufferTranslator::new(&mi);
+            let mut cb = ContextualBandit::new(&mi, Exploration::EpsilonGreedy(0.0), 0).unwrap();
+            read_example(&mut pa, &mut example, "shared |A u\n0:0.8:0.5 |B x\n|B y\n\n");
+            assert_eq!(logged_action(&example), Some((0, 0.8, 0.5)));
+            assert_eq!(cb.learn(&mut example, &mut re, &mut fbt, false, 0, &mut costs), 1.0);
+            assert_eq!(costs, vec![0.5, 0.5]);
+            assert_eq!(cb.format_prediction(), "1:1.000000\n");
+            assert!((cb.estimate_sum - estimate).abs() < 1e-6);
+            // The logged action is the one the policy doesn't choose, so it contributes only to the direct part
+            read_example(&mut pa, &mut example, "shared |A u\n|B x\n0:0.8:0.5 |B y\n\n");
+            cb.learn(&mut example, &mut re, &mut fbt, false, 0, &mut costs);
+            assert!((cb.estimate_sum - estimate - if *cb_type == CbType::IPS {0.0} else {0.5}).abs() < 1e-6);
+            assert_eq!(cb.estimate_count, 2);
+            assert!(example.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_ips() {
+        let vw = vw_map();
+        let mut pa = parser::VowpalParser::new(&vw);
+        pa.ldf = true;
+        pa.cb = true;
+        let mut example = LdfExample::new(&vw);
+        let mut costs = Vec::new();
+        let mi = cb_mi(CbType::IPS);
+        let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let mut cb = ContextualBandit::new(&mi, Exploration::EpsilonGreedy(0.2), 0).unwrap();
+        // Policy chooses the first of three equally good actions with 0.8 + 0.2 / 3 and the others with 0.2 / 3,
+        // the logged one is the second, chosen with 0.25: 0.2 / 3 * 0.6 / 0.25
+        read_example(&mut pa, &mut example, "shared |A u
+|B x
+0:0.6:0.25 |B y
+|B z
+\n");
+        cb.learn(&mut example, &mut re, &mut fbt, false, 0, &mut costs);
+        assert!((cb.estimate_sum - 0.16).abs() < 1e-6);
+
+        // Logged action is learned towards its cost with importance 1 / probability, the others are not learned
+        read_example(&mut pa, &mut example, "shared |A u
+|B x
+0:0.6:0.25 |B y
+|B z
+\n");
+        let mut re_weighted = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        cb.learn(&mut example, &mut re_weighted, &mut fbt, true, 0, &mut costs);
+        let mut re_plain = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        read_example(&mut pa, &mut example, "shared |A u
+|B x
+0:0.6:0.25 |B y
+|B z
+\n");
+        example.translate_action(1, &mut fbt);
+        fbt.feature_buffer.label = 0.6;
+        fbt.feature_buffer.example_importance = 4.0;
+        re_plain.learn(&fbt.feature_buffer, true, 0);
+        example.clear();
+        assert!(re_weighted.weights.iter().map(|w| w.weight).eq(re_plain.weights.iter().map(|w| w.weight)));
+        // Importance of rarely logged actions is clipped
+        read_example(&mut pa, &mut example, "shared |A u
+|B x
+0:0.6:0.0001 |B y
+\n");
+        cb.learn(&mut example, &mut re_weighted, &mut fbt, true, 0, &mut costs);
+        read_example(&mut pa, &mut example, "shared |A u
+|B x
+0:0.6:0.0001 |B y
+\n");
+        example.translate_action(1, &mut fbt);
+        fbt.feature_buffer.label = 0.6;
+        fbt.feature_buffer.example_importance = IPS_MAX_IMPORTANCE;
+        re_plain.learn(&fbt.feature_buffer, true, 0);
+        example.clear();
+        assert!(re_weighted.weights.iter().map(|w| w.weight).eq(re_plain.weights.iter().map(|w| w.weight)));
+    }
+
+    #[test]
+    fn test_cb_learning() {
+        let vw = vw_map();
+        let mut pa = parser::VowpalParser::new(&vw);
+        pa.ldf = true;
+        pa.cb = true;
+        let mut example = LdfExample::new(&vw);
+        let mut costs = Vec::new();
+        for cb_type in [CbType::IPS, CbType::DR, CbType::DM].iter() {
+            let mi = cb_mi(*cb_type);
+            let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+            let mut fbt = FeatureBufferTranslator::new(&mi);
+            let mut cb = ContextualBandit::new(&mi, Exploration::EpsilonGreedy(0.1), 0).unwrap();
+            // Logging policy chose uniformly, x is good for u1 and y for u2
+            for _ in 0..20 {
+                read_example(&mut pa, &mut example, "shared |A u1\n0:1:0.5 |B x\n|B y\n\n");
+                cb.learn(&mut example, &mut re, &mut fbt, true, 0, &mut costs);
+                read_example(&mut pa, &mut example, "shared |A u1\n|B x\n0:0:0.5 |B y\n\n");
+                cb.learn(&mut example, &mut re, &mut fbt, true, 0, &mut costs);
+                read_example(&mut pa, &mut example, "shared |A u2\n0:0:0.5 |B x\n|B y\n\n");
+                cb.learn(&mut example, &mut re, &mut fbt, true, 0, &mut costs);
+                read_example(&mut pa, &mut example, "shared |A u2\n|B x\n0:1:0.5 |B y\n\n");
+                cb.learn(&mut example, &mut re, &mut fbt, true, 0, &mut costs);
+            }
+            read_example(&mut pa, &mut example, "shared |A u1\n|B x\n|B y\n\n");
+            cb.learn(&mut example, &mut re, &mut fbt, false, 0, &mut costs);
+            assert!(costs[0] > costs[1]);
+            let re_fixed = re.immutable_regressor().unwrap();
+            read_example(&mut pa, &mut example, "shared |A u2\n|B x\n|B y\n\n");
+            let mut fixed_costs = Vec::new();
+            let probability = cb.predict(&mut example, &re_fixed, &mut fbt, 0, &mut fixed_costs);
+            assert!(fixed_costs[0] < fixed_costs[1]);
+            // Greedy action gets 1 - epsilon + epsilon / 2
+            assert!(probability == 0.95 || probability == 0.05);
+            assert_eq!(cb.pmf, vec![0.95, 0.05]);
+            // Examples without a logged action are not learned from
+            read_example(&mut pa, &mut example, "shared |A u3\n|B z\n\n");
+            let before = re.weights.iter().map(|w| w.weight).collect::<Vec<f32>>();
+            cb.learn(&mut example, &mut re, &mut fbt, true, 0, &mut costs);
+            assert!(re.weights.iter().map(|w| w.weight).eq(before.into_iter()));
+        }
+    }
+}
@@ -13,6 +13,16 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .value_name("filename")
                      .help("File with input examples")
                      .takes_value(true))
+                    .arg(Arg::with_name("input_format")
+                     .long("input_format")
+                     .value_name("vw")
+                     .help("Format of input examples: vw, json (one json object per line), tsv or csv")
+                     .takes_value(true))
+                    .arg(Arg::with_name("input_schema")
+                     .long("input_schema")
+                     .value_name("label,weight,A,B:name,-,...")
+                     .help("For tsv and csv input: what each column is - label, weight, namespace letter (value is feature name), namespace:feature_name (value is feature value) or - to skip")
+                     .takes_value(true))
                     .arg(Arg::with_name("quiet")
                      .long("quiet")
                      .help("Quiet mode, does nothing currently (as we don't output diagnostic data anyway)")
@@ -27,10 +37,87 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .long("cache")
                      .help("Use cache file")
                      .takes_value(false))
+                    .arg(Arg::with_name("cache_file")
+                     .long("cache_file")
+                     .value_name("filename")
+                     .help("Use this cache file instead of the one next to --data (implies --cache). Training can run from the cache file alone.")
+                     .takes_value(true))
+                    .arg(Arg::with_name("cache_only")
+                     .long("cache_only")
+                     .help("Only parse the input and write the cache file, do not train")
+                     .takes_value(false))
+                    .arg(Arg::with_name("cache_verify")
+                     .long("cache_verify")
+                     .value_name("filename")
+                     .help("Check the integrity of a cache file, report where it is damaged and exit")
+                     .takes_value(true))
+                    .arg(Arg::with_name("cache_index")
+                     .long("cache_index")
+                     .help("When writing the cache, also write an index of example offsets ({cache_file}.index), needs --cache_compression none")
+                     .takes_value(false))
+                    .arg(Arg::with_name("cache_sample_rate")
+                     .long("cache_sample_rate")
+                     .value_name("rate")
+                     .help("Read only a random sample of examples from an indexed cache")
+                     .takes_value(true))
+                    .arg(Arg::with_name("cache_negative_rate")
+                     .long("cache_negative_rate")
+                     .value_name("rate")
+                     .help("Read only a random sample of negative examples from an indexed cache, their importance is scaled by 1/rate")
+                     .takes_value(true))
+                    .arg(Arg::with_name("cache_range")
+                     .long("cache_range")
+                     .value_name("start:end")
+                     .help("Read only examples start (inclusive) to end (exclusive) from an indexed cache")
+                     .takes_value(true))
+                    .arg(Arg::with_name("negative_sampling_rate")
+                     .long("negative_sampling_rate")
+                     .value_name("rate")
+                     .help("Learn only from a random sample of negative examples, importance of kept negatives is scaled by 1/rate")
+                     .takes_value(true))
+                    .arg(Arg::with_name("negative_sampling_calibrate_predictions")
+                     .long("negative_sampling_calibrate_predictions")
+                     .help("With --negative_sampling_rate, keep importance of negatives and correct the predictions instead")
+                     .takes_value(false))
+                    .arg(Arg::with_name("shuffle_buffer")
+                     .long("shuffle_buffer")
+                     .value_name("examples")
+                     .help("Shuffle examples through a buffer of this many examples before learning")
+                     .takes_value(true))
+                    .arg(Arg::with_name("random_seed")
+                     .long("random_seed")
+                     .value_name("seed")
+                     .help("Seed for shuffling and sampling (default 0), and for --cb_explore_adf exploration (default from entropy)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("cache_compression")
+                     .long("cache_compression")
+                     .value_name("none|lz4|zstd[:level]")
+                     .help("Compression of the cache file (default: lz4 for gzipped input, none otherwise)")
+                     .takes_value(true))
                     .arg(Arg::with_name("save_resume")
                      .long("save_resume")
                      .help("save extra state so learning can be resumed later with new data")
                      .takes_value(false))
+                    .arg(Arg::with_name("checkpoint_every")
+                     .long("checkpoint_every")
+                     .value_name("examples")
+                     .help("Save a checkpoint of the model every this many examples")
+                     .takes_value(true))
+                    .arg(Arg::with_name("checkpoint_every_seconds")
+                     .long("checkpoint_every_seconds")
+                     .value_name("seconds")
+                     .help("Save a checkpoint of the model every this many seconds")
+                     .takes_value(true))
+                    .arg(Arg::with_name("checkpoint_file")
+                     .long("checkpoint_file")
+                     .value_name("filename")
+                     .help("Where to save checkpoints (default: final regressor filename + .checkpoint)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("resume_from")
+                     .long("resume_from")
+                     .value_name("checkpoint")
+                     .help("Continue training from a checkpoint, skipping the examples it has already seen")
+                     .takes_value(true))
                     .arg(Arg::with_name("interactions")
                      .long("interactions")
                      .value_name("namespace,namespace")
@@ -44,6 +131,24 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .multiple(true)
                      .takes_value(true))
 
+                    .arg(Arg::with_name("bin")
+                     .long("bin")
+                     .value_name("namespace:boundary,boundary,...")
+                     .help("Treat feature values of a namespace as continuous and bucketize them by given boundaries")
+                     .multiple(true)
+                     .takes_value(true))
+                    .arg(Arg::with_name("bin_quantiles")
+                     .long("bin_quantiles")
+                     .value_name("namespace:N")
+                     .help("Treat feature values of a namespace as continuous and bucketize them into N quantiles learned from input")
+                     .multiple(true)
+                     .takes_value(true))
+                    .arg(Arg::with_name("bin_sample")
+                     .long("bin_sample")
+                     .value_name("examples (=1000000)")
+                     .help("Number of examples from the start of input used to learn --bin_quantiles boundaries")
+                     .takes_value(true))
+
                     .arg(Arg::with_name("learning_rate")
                      .short("l")
                      .long("learning_rate")
@@ -55,6 +160,16 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .value_name("0.5")
                      .help("Learning rate")
                      .takes_value(true))
+                    .arg(Arg::with_name("fm_learning_rate")
+                     .long("fm_learning_rate")
+                     .value_name("0.5")
+                     .help("Learning rate of the factorization machine")
+                     .takes_value(true))
+                    .arg(Arg::with_name("mlp_learning_rate")
+                     .long("mlp_learning_rate")
+                     .value_name("0.5")
+                     .help("Learning rate of the neural network head")
+                     .takes_value(true))
                     .arg(Arg::with_name("minimum_learning_rate")
                      .long("minimum_learning_rate")
                      .value_name("0.0")
@@ -70,6 +185,11 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .value_name("0.5")
                      .help("How to apply Adagrad (0.5 = sqrt)")
                      .takes_value(true))
+                    .arg(Arg::with_name("fm_power_t")
+                     .long("fm_power_t")
+                     .value_name("0.5")
+                     .help("How to apply Adagrad in the factorization machine (0.5 = sqrt)")
+                     .takes_value(true))
                     .arg(Arg::with_name("l2")
                      .long("l2")
                      .value_name("0.0")
@@ -101,6 +221,51 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .value_name("logistic")
                      .help("What loss function to use")
                      .takes_value(true))
+                    .arg(Arg::with_name("oaa")
+                     .long("oaa")
+                     .value_name("K")
+                     .help("One against all multiclass classification with K classes, labels are 1 to K")
+                     .takes_value(true))
+                    .arg(Arg::with_name("probabilities")
+                     .long("probabilities")
+                     .help("With --oaa, output probabilities of all classes instead of the most probable class")
+                     .takes_value(false))
+                    .arg(Arg::with_name("softmax")
+                     .long("softmax")
+                     .help("With --probabilities, normalize probabilities of classes with softmax over their scores")
+                     .takes_value(false))
+                    .arg(Arg::with_name("csoaa_ldf")
+                     .long("csoaa_ldf")
+                     .value_name("m")
+                     .help("Cost sensitive multiclass with label dependent features: multi-line examples of a shared line and [action:]cost lines, ended by an empty line. Costs have to be between 0 and 1")
+                     .takes_value(true))
+                    .arg(Arg::with_name("action_scores")
+                     .long("action_scores")
+                     .help("With --csoaa_ldf, output predicted costs of all actions instead of the chosen action")
+                     .takes_value(false))
+                    .arg(Arg::with_name("cb_explore_adf")
+                     .long("cb_explore_adf")
+                     .help("Contextual bandit with action dependent features: multi-line examples of a shared line and action lines, the logged action labeled action:cost:probability")
+                     .takes_value(false))
+                    .arg(Arg::with_name("cb_type")
+                     .long("cb_type")
+                     .value_name("dr")
+                     .help("With --cb_explore_adf, how costs of logged actions are learned and the policy is evaluated: ips, dr or dm")
+                     .takes_value(true))
+                    .arg(Arg::with_name("cb_softmax")
+                     .long("cb_softmax")
+                     .help("With --cb_explore_adf, explore with softmax over predicted costs instead of epsilon-greedy")
+                     .takes_value(false))
+                    .arg(Arg::with_name("epsilon")
+                     .long("epsilon")
+                     .value_name("0.05")
+                     .help("With --cb_explore_adf, explore epsilon-greedy: probability of choosing uniformly among all actions")
+                     .takes_value(true))
+                    .arg(Arg::with_name("lambda")
+                     .long("lambda")
+                     .value_name("10.0")
+                     .help("With --cb_explore_adf --cb_softmax, inverse temperature of the softmax over predicted costs")
+                     .takes_value(true))
                     .arg(Arg::with_name("bit_precision")
                      .short("b")
                      .long("bit_precision")
@@ -120,6 +285,31 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .value_name("arg")
                      .help("Final regressor to save (arg is filename)")
                      .takes_value(true))
+                    .arg(Arg::with_name("export_serving_model")
+                     .long("export_serving_model")
+                     .value_name("filename")
+                     .help("Save a compact serving model with only weights (with --initial_regressor and no input, just converts it)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("serving_quantization")
+                     .long("serving_quantization")
+                     .value_name("f32|f16|int8")
+                     .help("Quantize the weights of --export_serving_model (int8 uses a scale per block of 256 weights)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("prune_threshold")
+                     .long("prune_threshold")
+                     .value_name("threshold")
+                     .help("Drop weights with absolute value up to threshold from --export_serving_model and store the rest sparsely")
+                     .takes_value(true))
+                    .arg(Arg::with_name("compare_model")
+                     .long("compare_model")
+                     .value_name("filename")
+                     .help("With --testonly, also predict with this model and compare logloss and AUC of both on the labeled examples (pruned or quantized --export_serving_model is compared automatically)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("export_mmap_model")
+                     .long("export_mmap_model")
+                     .value_name("filename")
+                     .help("Save a serving model with only weights, that daemons can memory map (with --initial_regressor and no input, just converts it)")
+                     .takes_value(true))
                     .arg(Arg::with_name("initial_regressor")
                      .short("i")
                      .long("initial_regressor")
@@ -148,7 +338,7 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                     .arg(Arg::with_name("ffm_field")
                      .long("ffm_field")
                      .value_name("namespace,namespace,...[:value]")
-                     .help("Define a FFM field by listing namespace letters")
+                     .help("Define a FFM field by listing namespace letters, or comma separated members where a member with several letters is a cross of namespaces (\"AB,C\"), optionally followed by :weight that multiplies the field's feature values")
                      .multiple(true)
                      .takes_value(true))
                     .arg(Arg::with_name("ffm_k")
@@ -161,9 +351,36 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .value_name("N")
                      .help("Bits to use for ffm hash space")
                      .takes_value(true))
+                    .arg(Arg::with_name("fm_k")
+                     .long("fm_k")
+                     .value_name("k")
+                     .help("Lenght of a latent vector of a factorization machine over all features (from --keep and --interactions)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("fm_bit_precision")
+                     .long("fm_bit_precision")
+                     .value_name("N")
+                     .help("Bits to use for factorization machine hash space")
+                     .takes_value(true))
+                    .arg(Arg::with_name("fwfm_k")
+                     .long("fwfm_k")
+                     .value_name("k")
+                     .help("Lenght of a latent vector of a field weighted factorization machine over the ffm fields (uses ffm bits and learning rate)")
+                     .takes_value(true))
                     .arg(Arg::with_name("ffm_k_threshold")
                      .long("ffm_k_threshold")
-                     .help("A minum gradient on left and right side to increase k")
+                     .value_name("threshold")
+                     .help("Adaptive k: FFM vectors start with one active dimension and unlock another one each time their accumulated gradient grows by this much")
+                     .multiple(false)
+                     .takes_value(true))
+                    .arg(Arg::with_name("ffm_no_intra_field")
+                     .long("ffm_no_intra_field")
+                     .help("Do not combine features of the same ffm field, like classic FFM (libffm), also applies to fwfm")
+                     .multiple(false)
+                     .takes_value(false))
+                    .arg(Arg::with_name("mlp_hidden")
+                     .long("mlp_hidden")
+                     .value_name("width[,width]")
+                     .help("Neural network head with one or two ReLU hidden layers over the LR sum and ffm interactions of every pair of fields")
                      .multiple(false)
                      .takes_value(true))
                     .arg(Arg::with_name("ffm_init_center")
@@ -221,6 +438,16 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .value_name("examples (0)")
                      .help("Output predictions with a model that is delayed by a number of examples")
                      .takes_value(true))
+                    .arg(Arg::with_name("prediction_model_delay_seconds")
+                     .long("prediction_model_delay_seconds")
+                     .value_name("seconds")
+                     .help("Output predictions with a model that is delayed by a number of seconds of example time (needs --timestamp_namespace)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("timestamp_namespace")
+                     .long("timestamp_namespace")
+                     .value_name("namespace")
+                     .help("Namespace holding the unix timestamp of the example as its only feature, for example |T 1600000000")
+                     .takes_value(true))
                     .arg(Arg::with_name("predictions_after")
                      .long("predictions_after")
                      .value_name("examples (=0)")
@@ -0,0 +1,131 @@
+/*
+Logloss and AUC of predictions on labeled examples, used to compare a model against another one
+(for example a quantized serving model against the full precision one) on a holdout file.
+*/
+
+pub struct BinaryMetrics {
+    predictions: Vec<(f32, bool)>,
+    logloss_sum: f64,
+}
+
+impl BinaryMetrics {
+    pub fn new() -> BinaryMetrics {
+        BinaryMetrics {
+            predictions: Vec::new(),
+            logloss_sum: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, prediction: f32, positive: bool) {
+        let p = (prediction as f64).max(1e-15).min(1.0 - 1e-15);
+        self.logloss_sum -= if positive { p.ln() } else { (1.0 - p).ln() };
+        self.predictions.push((prediction, positive));
+    }
+
+    pub fn count(&self) -> usize {
+        self.predictions.len()
+    }
+
+    pub fn logloss(&self) -> f64 {
+        if self.predictions.is_empty() {
+            return 0.0;
+        }
+        self.logloss_sum / self.predictions.len() as f64
+    }
+
+    // Probability that a random positive gets a higher prediction than a random negative (ties count half)
+    pub fn auc(&mut self) -> f64 {
+        self.predictions.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let mut positives:f64 = 0.0;
+        let mut positive_rank_sum:f64 = 0.0;
+        let mut i = 0;
+        while i < self.predictions.len() {
+            // Examples with equal predictions share their average rank
+            let mut j = i;
+            while j < self.predictions.len() && self.predictions[j].0 == self.predictions[i].0 {
+                j += 1;
+            }
+            let average_rank = (i + j + 1) as f64 / 2.0;
+            for (_, positive) in &self.predictions[i..j] {
+                if *positive {
+                    positives += 1.0;
+                    positive_rank_sum += average_rank;
+                }
+            }
+            i = j;
+        }
+        let negatives = self.predictions.len() as f64 - positives;
+        if positives == 0.0 || negatives == 0.0 {
+            return 0.5;
+        }
+        (positive_rank_sum - positives * (positives + 1.0) / 2.0) / (positives * negatives)
+    }
+}
+
+// Mean and largest absolute difference of predictions of the same examples
+pub fn prediction_difference(metrics: &BinaryMetrics, compare_metrics: &BinaryMetrics) -> (f64, f64) {
+    let mut sum:f64 = 0.0;
+    let mut max:f64 = 0.0;
+    for ((p, _), (compare_p, _)) in metrics.predictions.iter().zip(compare_metrics.predictions.iter()) {
+        let difference = (*p as f64 - *compare_p as f64).abs();
+        sum += difference;
+        max = max.max(difference);
+    }
+    (sum / metrics.count().max(1) as f64, max)
+}
+
+pub fn print_comparison(metrics: &mut BinaryMetrics, compare_metrics: &mut BinaryMetrics, compare_name: &str) {
+    println!("Compared on {} labeled examples", metrics.count());
+    let (mean_difference, max_difference) = prediction_difference(metrics, compare_metrics);
+    println!("prediction difference: mean {:.6} max {:.6}", mean_difference, max_difference);
+    println!("logloss: model {:.6} {} {:.6} change {:+.6}", metrics.logloss(), compare_name, compare_metrics.logloss(), compare_metrics.logloss() - metrics.logloss());
+    let (auc, compare_auc) = (metrics.auc(), compare_metrics.auc());
+    println!("AUC: model {:.6} {} {:.6} change {:+.6}", auc, compare_name, compare_auc, compare_auc - auc);
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_binary_metrics() {
+        let mut m = BinaryMetrics::new();
+        m.push(0.9, true);
+        m.push(0.8, false);
+        m.push(0.3, true);
+        m.push(0.1, false);
+        assert_eq!(m.count(), 4);
+        let expected_logloss = -((0.9f64).ln() + (0.2f64).ln() + (0.3f64).ln() + (0.9f64).ln()) / 4.0;
+        assert!((m.logloss() - expected_logloss).abs() < 1e-6);
+        // 3 of 4 positive-negative pairs are ordered right
+        assert_eq!(m.auc(), 0.75);
+
+        // Predictions are compared in the order of examples
+        let mut m1 = BinaryMetrics::new();
+        let mut m2 = BinaryMetrics::new();
+        for (p1, p2) in &[(0.9, 0.8), (0.8, 0.8), (0.3, 0.3), (0.1, 0.3)] {
+            m1.push(*p1, true);
+            m2.push(*p2, true);
+        }
+        let (mean_difference, max_difference) = prediction_difference(&m1, &m2);
+        assert!((mean_difference - 0.075).abs() < 1e-6);
+        assert!((max_difference - 0.2).abs() < 1e-6);
+
+        // Ties count half
+        let mut m = BinaryMetrics::new();
+        m.push(0.5, true);
+        m.push(0.5, false);
+        assert_eq!(m.auc(), 0.5);
+
+        // Perfect ordering, and only one class
+        let mut m = BinaryMetrics::new();
+        m.push(0.7, true);
+        m.push(0.2, false);
+        assert_eq!(m.auc(), 1.0);
+        let mut m = BinaryMetrics::new();
+        m.push(0.7, true);
+        assert_eq!(m.auc(), 0.5);
+    }
+}
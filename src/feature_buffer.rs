@@ -1,5 +1,6 @@
 use crate::model_instance;
 use crate::parser;
+use crate::binning;
 
 const VOWPAL_FNV_PRIME:u32 = 16777619;	// vowpal magic number
 //const CONSTANT_NAMESPACE:usize = 128;
@@ -26,6 +27,8 @@ pub struct FeatureBuffer {
     pub lr_buffer: Vec<HashAndValue>,
     pub ffm_buffer: Vec<HashAndValueAndSeq>,
     pub ffm_fields_count: u32,
+    pub fm_buffer: Vec<HashAndValue>,
+    pub fwfm_buffer: Vec<HashAndValueAndSeq>,  // contra_field_index is simply the field index
 }
 
 
@@ -35,9 +38,12 @@ pub struct FeatureBufferTranslator {
     // we don't want to keep allocating buffers
     hashes_vec_in: Vec<HashAndValue>,
     hashes_vec_out: Vec<HashAndValue>,
+    binned_record_buffer: Vec<u32>,
     pub feature_buffer: FeatureBuffer,
     pub lr_hash_mask: u32,
     pub ffm_hash_mask: u32,
+    pub fm_hash_mask: u32,
+    pub fwfm_hash_mask: u32,
 }
 
 // A macro that takes care of decoding the individual feature - which can have two different encodings
@@ -65,6 +71,33 @@ macro_rules! feature_reader {
     };
 }
 
+// Crosses features of the listed namespaces with vowpal's FNV combining. Hashes and values of the
+// crossed features end up in $hashes_vec_in, $hashes_vec_out is a scratch buffer
+macro_rules! cross_hashes {
+    ( $record_buffer:ident,
+      $feature_indices:expr,
+      $hashes_vec_in:ident,
+      $hashes_vec_out:ident ) => {
+        let feature_indices = $feature_indices;
+        let num_namespaces:usize = feature_indices.len();
+        let feature_index_offset = *feature_indices.get_unchecked(0);
+        $hashes_vec_in.truncate(0);
+        feature_reader!($record_buffer, feature_index_offset, hash_data, hash_value, {
+                $hashes_vec_in.push(HashAndValue {hash: hash_data, value:hash_value});
+            });
+        for feature_index in feature_indices.get_unchecked(1 as usize .. num_namespaces) {
+            $hashes_vec_out.truncate(0);
+            for handv in &(*$hashes_vec_in) {
+                let half_hash = handv.hash.overflowing_mul(VOWPAL_FNV_PRIME).0;
+                feature_reader!($record_buffer, feature_index, hash_data, hash_value, {
+                    $hashes_vec_out.push(HashAndValue{   hash: hash_data ^ half_hash,
+                                                        value: handv.value * hash_value});
+                });
+            }
+            std::mem::swap(&mut $hashes_vec_in, &mut $hashes_vec_out);
+        }
+    };
+}
 
 
 impl FeatureBufferTranslator {
@@ -80,6 +113,18 @@ impl FeatureBufferTranslator {
         let dimensions_mask = (1 << ffm_bits_for_dimensions) - 1;
         // in ffm we will simply mask the lower bits, so we spare them for k
         let ffm_hash_mask = ((1 << mi.ffm_bit_precision) -1) ^ dimensions_mask;
+        // Same for fm
+        let mut fm_bits_for_dimensions = 0;
+        while mi.fm_k > (1 << (fm_bits_for_dimensions)) {
+            fm_bits_for_dimensions += 1;
+        }
+        let fm_hash_mask = ((1 << mi.fm_bit_precision) -1) ^ ((1 << fm_bits_for_dimensions) - 1);
+        // And for fwfm, which uses ffm hash space
+        let mut fwfm_bits_for_dimensions = 0;
+        while mi.fwfm_k > (1 << (fwfm_bits_for_dimensions)) {
+            fwfm_bits_for_dimensions += 1;
+        }
+        let fwfm_hash_mask = ((1 << mi.ffm_bit_precision) -1) ^ ((1 << fwfm_bits_for_dimensions) - 1);
 
 
         let mut fb = FeatureBuffer {
@@ -88,6 +133,8 @@ impl FeatureBufferTranslator {
             lr_buffer: Vec::new(),
             ffm_buffer: Vec::new(),
             ffm_fields_count: 0,
+            fm_buffer: Vec::new(),
+            fwfm_buffer: Vec::new(),
         };
 
         // avoid doing any allocations in translate
@@ -95,9 +142,12 @@ impl FeatureBufferTranslator {
                             model_instance: mi.clone(),
                             hashes_vec_in : Vec::with_capacity(100),
                             hashes_vec_out : Vec::with_capacity(100),
+                            binned_record_buffer: Vec::new(),
                             feature_buffer: fb,
                             lr_hash_mask: lr_hash_mask,
                             ffm_hash_mask: ffm_hash_mask, 
+                            fm_hash_mask: fm_hash_mask,
+                            fwfm_hash_mask: fwfm_hash_mask,
         };
         fbt
     }
@@ -108,9 +158,27 @@ impl FeatureBufferTranslator {
     
     
     pub fn translate(&mut self, record_buffer: &[u32]) -> () {
+        if self.model_instance.binning_descs.is_empty() {
+            self.translate_(record_buffer);
+        } else {
+            // Binned namespaces are rewritten on a copy, since record buffer belongs to parser or cache
+            let mut binned_record_buffer = std::mem::take(&mut self.binned_record_buffer);
+            binned_record_buffer.truncate(0);
+            binned_record_buffer.extend_from_slice(record_buffer);
+            binning::bucketize_record(&self.model_instance.binning_descs, &mut binned_record_buffer);
+            self.translate_(&binned_record_buffer);
+            self.binned_record_buffer = binned_record_buffer;
+        }
+    }
+
+    fn translate_(&mut self, record_buffer: &[u32]) -> () {
         unsafe {
         let lr_buffer = &mut self.feature_buffer.lr_buffer;
         lr_buffer.truncate(0);
+        // Factorization machine uses the same features as LR, except the constant
+        let fm_buffer = &mut self.feature_buffer.fm_buffer;
+        fm_buffer.truncate(0);
+        let use_fm = self.model_instance.fm_k > 0;
         self.feature_buffer.label = record_buffer[parser::LABEL_OFFSET] as f32;  // copy label
         self.feature_buffer.example_importance = f32::from_bits(record_buffer[parser::EXAMPLE_IMPORTANCE_OFFSET]);    
         let mut output_len:usize = 0;
@@ -126,27 +194,21 @@ impl FeatureBufferTranslator {
                 feature_reader!(record_buffer, feature_index_offset, hash_data, hash_value, {
                     lr_buffer.push(HashAndValue {hash: hash_data & self.lr_hash_mask, 
                                                  value: hash_value * feature_combo_weight});
+                    if use_fm {
+                        fm_buffer.push(HashAndValue {hash: hash_data & self.fm_hash_mask, 
+                                                     value: hash_value * feature_combo_weight});
+                    }
                 });
                 continue
             }
-            hashes_vec_in.truncate(0);
-            feature_reader!(record_buffer, feature_index_offset, hash_data, hash_value, {
-                    hashes_vec_in.push(HashAndValue {hash: hash_data, value:hash_value});
-                });
-            for feature_index in feature_combo_desc.feature_indices.get_unchecked(1 as usize .. num_namespaces) {
-                hashes_vec_out.truncate(0);
-                for handv in &(*hashes_vec_in) {
-                    let half_hash = handv.hash.overflowing_mul(VOWPAL_FNV_PRIME).0;
-                    feature_reader!(record_buffer, feature_index, hash_data, hash_value, {
-                        hashes_vec_out.push(HashAndValue{   hash: hash_data ^ half_hash,
-                                                            value: handv.value * hash_value});
-                    });
-                }
-                std::mem::swap(&mut hashes_vec_in, &mut hashes_vec_out);
-            }
+            cross_hashes!(record_buffer, &feature_combo_desc.feature_indices, hashes_vec_in, hashes_vec_out);
             for handv in &(*hashes_vec_in) {
                 lr_buffer.push(HashAndValue{hash: handv.hash & self.lr_hash_mask,
                                             value: handv.value * feature_combo_weight});
+                if use_fm {
+                    fm_buffer.push(HashAndValue{hash: handv.hash & self.fm_hash_mask,
+                                                value: handv.value * feature_combo_weight});
+                }
             }
         }
         // add the constant
@@ -156,22 +218,52 @@ impl FeatureBufferTranslator {
         }
 
         // FFM loops have not been optimized yet
-        if self.model_instance.ffm_k > 0 { 
-            // currently we only support primitive features as namespaces, (from --lrqfa command)
-            // this is for compatibility with vowpal
-            // but in theory we could support also combo features
+        if self.model_instance.ffm_k > 0 || self.model_instance.fwfm_k > 0 { 
+            // Fields have primitive namespaces as members (like vowpal's --lrqfa) and optionally 
+            // crosses of namespaces, hashed the same way as feature combos
+            // Without fwfm hashes are masked for ffm right away, otherwise we first collect unmasked hashes and 
+            // field indexes, ffm and fwfm then each mask them their way
+            let (hash_mask, field_multiplier) = if self.model_instance.fwfm_k == 0 {(self.ffm_hash_mask, self.model_instance.ffm_k)} else {(u32::MAX, 1)};
             let ffm_buffer = &mut self.feature_buffer.ffm_buffer;
             ffm_buffer.truncate(0);
             self.feature_buffer.ffm_fields_count = self.model_instance.ffm_fields.len() as u32;    
             //let feature_len = self.feature_buffer.ffm_fields_count * self.model_instance.ffm_k;
-            for (contra_field_index, ffm_field) in self.model_instance.ffm_fields.iter().enumerate() {
+            for (field_index, ffm_field) in self.model_instance.ffm_fields.iter().enumerate() {
+                let field_weight = *self.model_instance.ffm_field_weights.get(field_index).unwrap_or(&1.0);
                 for feature_index in ffm_field {
                     feature_reader!(record_buffer, feature_index, hash_data, hash_value, {
-                            ffm_buffer.push(HashAndValueAndSeq {hash: hash_data & self.ffm_hash_mask,
-                                                                    value: hash_value,
-                                                                    contra_field_index: contra_field_index as u32 * self.model_instance.ffm_k as u32});
+                            ffm_buffer.push(HashAndValueAndSeq {hash: hash_data & hash_mask,
+                                                                    value: hash_value * field_weight,
+                                                                    contra_field_index: field_index as u32 * field_multiplier});
                     });
                 }
+                if let Some(ffm_field_crosses) = self.model_instance.ffm_field_crosses.get(field_index) {
+                    for feature_indices in ffm_field_crosses {
+                        cross_hashes!(record_buffer, feature_indices, hashes_vec_in, hashes_vec_out);
+                        for handv in &(*hashes_vec_in) {
+                            ffm_buffer.push(HashAndValueAndSeq {hash: handv.hash & hash_mask,
+                                                                value: handv.value * field_weight,
+                                                                contra_field_index: field_index as u32 * field_multiplier});
+                        }
+                    }
+                }
+            }
+            if self.model_instance.fwfm_k > 0 {
+                let fwfm_buffer = &mut self.feature_buffer.fwfm_buffer;
+                fwfm_buffer.truncate(0);
+                for hvs in ffm_buffer.iter() {
+                    fwfm_buffer.push(HashAndValueAndSeq {hash: hvs.hash & self.fwfm_hash_mask,
+                                                         value: hvs.value,
+                                                         contra_field_index: hvs.contra_field_index});
+                }
+                if self.model_instance.ffm_k > 0 {
+                    for hvs in ffm_buffer.iter_mut() {
+                        hvs.hash &= self.ffm_hash_mask;
+                        hvs.contra_field_index *= self.model_instance.ffm_k;
+                    }
+                } else {
+                    ffm_buffer.truncate(0);
+                }
             }
         }
         
@@ -301,6 +393,31 @@ mod tests {
         assert_eq!(fbt.feature_buffer.lr_buffer, vec![HashAndValue {hash: 0xfea, value:2.0}]);
     }
     
+    #[test]
+    fn test_fm_buffer() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.add_constant_feature = true;
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {
+                                                        feature_indices: vec![0], 
+                                                        weight: 2.0});
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {
+                                                        feature_indices: vec![0, 1], 
+                                                        weight: 1.0});
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let rb = add_header(vec![2988156968 & parser::MASK31, 2422381320 & parser::MASK31]);
+        fbt.translate(&rb);
+        assert_eq!(fbt.feature_buffer.fm_buffer, vec![]);
+
+        // Same features as LR without the constant, in fm hash space aligned to k
+        mi.fm_k = 4;
+        mi.fm_bit_precision = 12;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(&rb);
+        assert_eq!(fbt.feature_buffer.lr_buffer.len(), 3);
+        assert_eq!(fbt.feature_buffer.fm_buffer, vec![HashAndValue {hash: 2988156968 & parser::MASK31 & 0xffc, value: 2.0},
+                                                      HashAndValue {hash: 208368 & 0xffc, value: 1.0}]);
+    }
+
     #[test]
     fn test_ffm_empty() {
         let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
@@ -376,6 +493,89 @@ mod tests {
         // one more which we dont test
     }
 
+    #[test]
+    fn test_ffm_cross_members() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.add_constant_feature = false;
+        mi.ffm_fields.push(vec![]);     // field with a cross of two namespaces as its only member
+        mi.ffm_field_crosses.push(vec![vec![0, 1]]);
+        mi.ffm_fields.push(vec![1]);    // single namespace in a field
+        mi.ffm_field_crosses.push(vec![]);
+        mi.ffm_k = 1;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        // Same hashes as in test_double_vowpal, so the cross is the same as of --interactions
+        let rb = add_header(vec![2988156968 & parser::MASK31, 2422381320 & parser::MASK31]);
+        fbt.translate(&rb);
+        assert_eq!(fbt.feature_buffer.ffm_buffer, vec![ HashAndValueAndSeq{hash: 208368, value: 1.0, contra_field_index: 0}, 
+                                                        HashAndValueAndSeq{hash: 2422381320 & parser::MASK31 & fbt.ffm_hash_mask, value: 1.0, contra_field_index: 1},
+                                                     ]);
+
+        // Values of crossed features get multiplied, a missing namespace means there is no cross
+        let rb = add_header(vec![parser::IS_NOT_SINGLE_MASK | nd(5,7), 0xfeb, 0xfea, 2.0f32.to_bits()]);
+        fbt.translate(&rb);
+        let cross_hash = (0xfeb ^ 0xfea_u32.overflowing_mul(VOWPAL_FNV_PRIME).0) & fbt.ffm_hash_mask;
+        assert_eq!(fbt.feature_buffer.ffm_buffer, vec![ HashAndValueAndSeq{hash: cross_hash, value: 2.0, contra_field_index: 0}, 
+                                                        HashAndValueAndSeq{hash: 0xfeb, value: 1.0, contra_field_index: 1},
+                                                     ]);
+        let rb = add_header(vec![0xfea, parser::NULL]);
+        fbt.translate(&rb);
+        assert_eq!(fbt.feature_buffer.ffm_buffer, vec![]);
+    }
+
+    #[test]
+    fn test_fwfm_buffer() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.add_constant_feature = false;
+        mi.ffm_fields.push(vec![0]);
+        mi.ffm_fields.push(vec![1]);
+        mi.fwfm_k = 2;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let rb = add_header(vec![0xfeb, 0xfea]);
+        fbt.translate(&rb);
+        // Fields are plain field indexes and hashes are aligned to fwfm k
+        assert_eq!(fbt.feature_buffer.ffm_buffer, vec![]);
+        assert_eq!(fbt.feature_buffer.fwfm_buffer, vec![ HashAndValueAndSeq{hash: 0xfea, value: 1.0, contra_field_index: 0}, 
+                                                         HashAndValueAndSeq{hash: 0xfea, value: 1.0, contra_field_index: 1},
+                                                      ]);
+        // Together with ffm
+        mi.ffm_k = 4;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(&rb);
+        assert_eq!(fbt.feature_buffer.ffm_buffer, vec![ HashAndValueAndSeq{hash: 0xfe8, value: 1.0, contra_field_index: 0}, 
+                                                        HashAndValueAndSeq{hash: 0xfe8, value: 1.0, contra_field_index: 4},
+                                                     ]);
+        assert_eq!(fbt.feature_buffer.fwfm_buffer[1], HashAndValueAndSeq{hash: 0xfea, value: 1.0, contra_field_index: 1});
+    }
+
+    #[test]
+    fn test_ffm_field_weights() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.add_constant_feature = false;
+        mi.ffm_fields.push(vec![0]);
+        mi.ffm_field_crosses.push(vec![vec![0, 1]]);
+        mi.ffm_field_weights.push(0.5);
+        mi.ffm_fields.push(vec![1]);
+        mi.ffm_field_crosses.push(vec![]);
+        mi.ffm_field_weights.push(3.0);
+        mi.ffm_k = 1;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let rb = add_header(vec![parser::IS_NOT_SINGLE_MASK | nd(5,7), 0xfeb, 0xfea, 2.0f32.to_bits()]);
+        fbt.translate(&rb);
+        let cross_hash = (0xfeb ^ 0xfea_u32.overflowing_mul(VOWPAL_FNV_PRIME).0) & fbt.ffm_hash_mask;
+        // Weight of the field multiplies values of primitive and crossed members
+        assert_eq!(fbt.feature_buffer.ffm_buffer, vec![ HashAndValueAndSeq{hash: 0xfea, value: 1.0, contra_field_index: 0}, 
+                                                        HashAndValueAndSeq{hash: cross_hash, value: 1.0, contra_field_index: 0}, 
+                                                        HashAndValueAndSeq{hash: 0xfeb, value: 3.0, contra_field_index: 1},
+                                                     ]);
+
+        // Models without field weights use 1.0
+        mi.ffm_field_weights = Vec::new();
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        fbt.translate(&rb);
+        assert_eq!(fbt.feature_buffer.ffm_buffer[0].value, 2.0);
+        assert_eq!(fbt.feature_buffer.ffm_buffer[2].value, 1.0);
+    }
+
     #[test]
     fn test_example_importance() {
         let mut mi = model_instance::ModelInstance::new_empty().unwrap();
@@ -390,5 +590,29 @@ mod tests {
         assert_eq!(fbt.feature_buffer.example_importance, 1.0); // Did example importance get parsed correctly
     }
 
+    #[test]
+    fn test_binning() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.add_constant_feature = false;
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {
+                                                        feature_indices: vec![0], 
+                                                        weight: 1.0});
+        mi.binning_descs.push(model_instance::BinningDesc {
+                                                        namespace_index: 0,
+                                                        num_quantiles: 0,
+                                                        boundaries: vec![1.0, 10.0]});
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let rb = add_header(vec![parser::IS_NOT_SINGLE_MASK | nd(4,6), 0xfea, 5.0f32.to_bits()]);
+        fbt.translate(&rb);
+        assert_eq!(fbt.feature_buffer.lr_buffer, vec![HashAndValue {hash: binning::bucket_hash(0xfea, 1) & fbt.lr_hash_mask, value:1.0}]);
+        // Same bucket, same feature
+        let rb = add_header(vec![parser::IS_NOT_SINGLE_MASK | nd(4,6), 0xfea, 7.0f32.to_bits()]);
+        fbt.translate(&rb);
+        assert_eq!(fbt.feature_buffer.lr_buffer, vec![HashAndValue {hash: binning::bucket_hash(0xfea, 1) & fbt.lr_hash_mask, value:1.0}]);
+        let rb = add_header(vec![parser::IS_NOT_SINGLE_MASK | nd(4,6), 0xfea, 70.0f32.to_bits()]);
+        fbt.translate(&rb);
+        assert_eq!(fbt.feature_buffer.lr_buffer, vec![HashAndValue {hash: binning::bucket_hash(0xfea, 2) & fbt.lr_hash_mask, value:1.0}]);
+    }
+
 }
 
@@ -0,0 +1,304 @@
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+
+use crate::feature_buffer::FeatureBufferTranslator;
+use crate::parser;
+use crate::regressor::ImmutableRegressor;
+use crate::regressor::RegressorTrait;
+use crate::vwmap;
+
+/*
+Cost sensitive multiclass with label dependent features (vowpal's --csoaa_ldf).
+An example spans multiple lines: an optional shared line, then one line per action, ended by an empty line.
+Every action is learned as a single example with features of the shared line and of the action line, so with
+--interactions or ffm fields shared features interact with action features. Since fw only knows logistic loss,
+costs (between 0 and 1, the parser rejects others) are learned as soft labels and the predicted cost of an action 
+is its logistic output.
+*/
+
+// Stands for the empty line when input ends without one
+pub const END_RECORD: [u32; parser::HEADER_LEN] = [parser::HEADER_LEN as u32, parser::LDF_END, parser::FLOAT32_ONE];
+
+pub struct LdfExample {
+    num_namespaces: usize,
+    shared: Vec<u32>,           // record of the shared line, empty when there is none
+    actions: Vec<Vec<u32>>,     // records of action lines, only the first actions_len are used
+    actions_len: usize,
+    merged: Vec<u32>,
+}
+
+// Appends hashes and values of the namespace to features
+fn read_namespace(record: &[u32], desc_offset: usize, features: &mut Vec<(u32, u32)>) {
+    let namespace_desc = record[desc_offset];
+    if namespace_desc & parser::IS_NOT_SINGLE_MASK != 0 {
+        let start = ((namespace_desc >> 16) & 0x7fff) as usize;
+        let end = (namespace_desc & 0xffff) as usize;
+        for hash_offset in (start..end).step_by(2) {
+            features.push((record[hash_offset], record[hash_offset + 1]));
+        }
+    } else {
+        features.push((namespace_desc, parser::FLOAT32_ONE));
+    }
+}
+
+// Record with features of both records, as if they were written on the same line. Header is the one of the action.
+pub fn merge_records(shared: &[u32], action: &[u32], num_namespaces: usize, merged: &mut Vec<u32>) {
+    let bufpos = num_namespaces * parser::NAMESPACE_DESC_LEN + parser::HEADER_LEN;
+    merged.truncate(0);
+    merged.extend_from_slice(&action[0..parser::HEADER_LEN]);
+    merged.resize(bufpos, parser::NULL);
+    let mut features: Vec<(u32, u32)> = Vec::new();
+    for desc_offset in parser::HEADER_LEN..bufpos {
+        features.truncate(0);
+        if !shared.is_empty() {
+            read_namespace(shared, desc_offset, &mut features);
+        }
+        read_namespace(action, desc_offset, &mut features);
+        if features.len() == 1 && features[0].1 == parser::FLOAT32_ONE {
+            merged[desc_offset] = features[0].0;
+        } else if !features.is_empty() {
+            let bufpos_namespace_start = merged.len();
+            for (hash, value) in features.iter() {
+                merged.push(*hash);
+                merged.push(*value);
+            }
+            merged[desc_offset] = parser::IS_NOT_SINGLE_MASK | (((bufpos_namespace_start << 16) + merged.len()) as u32);
+        }
+    }
+    merged[0] = merged.len() as u32;
+}
+
+impl LdfExample {
+    pub fn new(vw: &vwmap::VwNamespaceMap) -> LdfExample {
+        LdfExample {
+            num_namespaces: vw.num_namespaces,
+            shared: Vec::new(),
+            actions: Vec::new(),
+            actions_len: 0,
+            merged: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.is_empty() && self.actions_len == 0
+    }
+
+    // Number of actions
+    pub fn len(&self) -> usize {
+        self.actions_len
+    }
+
+    pub fn action_record(&self, action: usize) -> &[u32] {
+        &self.actions[action]
+    }
+
+    pub fn clear(&mut self) {
+        self.shared.truncate(0);
+        self.actions_len = 0;
+    }
+
+    // Adds a line of the example, returns true when the example is complete
+    pub fn push(&mut self, record: &[u32]) -> Result<bool, Box<dyn Error>> {
+        match record[parser::LABEL_OFFSET] {
+            parser::LDF_END => Ok(!self.is_empty()),   // repeated empty lines don't make empty examples
+            parser::LDF_SHARED => {
+                if !self.is_empty() {
+                    return Err(Box::new(IOError::new(ErrorKind::Other, "Shared line has to be the first line of an example")))
+                }
+                self.shared.extend_from_slice(record);
+                Ok(false)
+            },
+            _ => {
+                if self.actions.len() == self.actions_len {
+                    self.actions.push(Vec::new());
+                }
+                let action = &mut self.actions[self.actions_len];
+                action.truncate(0);
+                action.extend_from_slice(record);
+                self.actions_len += 1;
+                Ok(false)
+            }
+        }
+    }
+
+    // Translates the action together with the shared features, label is the cost of the action
+    pub fn translate_action(&mut self, action: usize, fbt: &mut FeatureBufferTranslator) -> bool {
+        merge_records(&self.shared, &self.actions[action], self.num_namespaces, &mut self.merged);
+        fbt.translate(&self.merged);
+        let label = self.actions[action][parser::LABEL_OFFSET];
+        fbt.feature_buffer.label = if label == parser::NO_LABEL {parser::NO_LABEL as f32} else {f32::from_bits(label)};
+        label != parser::NO_LABEL
+    }
+
+    // Learns (when update is set) from actions that have costs and leaves predicted costs of all actions in costs.
+    // The example is consumed. Returns the lowest cost.
+    pub fn learn(&mut self, re: &mut dyn RegressorTrait, fbt: &mut FeatureBufferTranslator, update: bool, example_num: u32, costs: &mut Vec<f32>) -> f32 {
+        costs.truncate(0);
+        for action in 0..self.actions_len {
+            let has_cost = self.translate_action(action, fbt);
+            costs.push(re.learn(&fbt.feature_buffer, update && has_cost, example_num));
+        }
+        self.clear();
+        costs.iter().cloned().fold(1.0, f32::min)
+    }
+
+    pub fn predict(&mut self, re: &ImmutableRegressor, fbt: &mut FeatureBufferTranslator, example_num: u32, costs: &mut Vec<f32>) -> f32 {
+        costs.truncate(0);
+        for action in 0..self.actions_len {
+            self.translate_action(action, fbt);
+            costs.push(re.predict(&fbt.feature_buffer, example_num));
+        }
+        self.clear();
+        costs.iter().cloned().fold(1.0, f32::min)
+    }
+}
+
+// Either the action with the lowest cost (counted from 1), or "action:cost" of every action
+pub fn format_prediction(costs: &[f32], action_scores: bool) -> String {
+    let mut s = String::new();
+    if !action_scores {
+        let mut best = 0;
+        for (action, cost) in costs.iter().enumerate() {
+            if *cost < costs[best] {
+                best = action;
+            }
+        }
+        if !costs.is_empty() {
+            s.push_str(&format!("{}", best + 1));
+        }
+    } else {
+        for (action, cost) in costs.iter().enumerate() {
+            if action > 0 {
+                s.push(' ');
+            }
+            s.push_str(&format!("{}:{:.6}", action + 1, cost));
+        }
+    }
+    s.push('\n');
+    s
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::model_instance;
+    use crate::optimizer;
+    use crate::regressor::Regressor;
+    use std::io::Cursor;
+
+    fn vw_map() -> vwmap::VwNamespaceMap {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        vwmap::VwNamespaceMap::new(vw_map_string).unwrap()
+    }
+
+    fn read_example(pa: &mut parser::VowpalParser, ldf: &mut LdfExample, s: &str) {
+        let mut buf = Cursor::new(s.as_bytes().to_vec());
+        loop {
+            let record = pa.next(&mut buf).unwrap();
+            if record.is_empty() || ldf.push(record).unwrap() {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_records() {
+        let vw = vw_map();
+        let mut pa = parser::VowpalParser::new(&vw);
+        let shared = pa.next(&mut Cursor::new(b"1 |A a |B b:2\n".to_vec())).unwrap().to_vec();
+        let action = pa.next(&mut Cursor::new(b"-1 2.0 |B c\n".to_vec())).unwrap().to_vec();
+        let mut merged = Vec::new();
+        merge_records(&shared, &action, 2, &mut merged);
+        // Same as if features were on one line
+        let expected = pa.next(&mut Cursor::new(b"-1 2.0 |A a |B b:2 c\n".to_vec())).unwrap().to_vec();
+        assert_eq!(merged, expected);
+        // Without a shared line it is just the action
+        merge_records(&[], &action, 2, &mut merged);
+        assert_eq!(merged, action);
+    }
+
+    #[test]
+    fn test_ldf_parsing() {
+        let vw = vw_map();
+        let mut pa = parser::VowpalParser::new(&vw);
+        pa.ldf = true;
+        let mut ldf = LdfExample::new(&vw);
+        let mut buf = Cursor::new(b"shared |A a\n1:0.5 |B b\n0 |B c\n|B d\n\n\nshared |A a\n".to_vec());
+        assert_eq!(ldf.push(pa.next(&mut buf).unwrap()).unwrap(), false);
+        assert_eq!(ldf.push(pa.next(&mut buf).unwrap()).unwrap(), false);
+        assert_eq!(ldf.push(pa.next(&mut buf).unwrap()).unwrap(), false);
+        assert_eq!(ldf.push(pa.next(&mut buf).unwrap()).unwrap(), false);
+        assert_eq!(ldf.actions_len, 3);
+        assert_eq!(ldf.shared[parser::LABEL_OFFSET], parser::LDF_SHARED);
+        assert_eq!(ldf.actions[0][parser::LABEL_OFFSET], 0.5f32.to_bits());
+        assert_eq!(ldf.actions[1][parser::LABEL_OFFSET], 0);
+        assert_eq!(ldf.actions[2][parser::LABEL_OFFSET], parser::NO_LABEL);
+        assert_eq!(ldf.push(pa.next(&mut buf).unwrap()).unwrap(), true);
+        ldf.clear();
+        // Second empty line doesn't make an example
+        assert_eq!(ldf.push(pa.next(&mut buf).unwrap()).unwrap(), false);
+        assert_eq!(ldf.push(pa.next(&mut buf).unwrap()).unwrap(), false);
+        let mut buf = Cursor::new(b"shared |A b\n".to_vec());
+        assert_eq!(format!("{}", ldf.push(pa.next(&mut buf).unwrap()).err().unwrap()), "Shared line has to be the first line of an example");
+
+        let mut buf = Cursor::new(b"1.5 |A a\n".to_vec());
+        assert_eq!(format!("{}", pa.next(&mut buf).err().unwrap()), "Cost has to be between 0 and 1, got: 1.5 (fw learns costs with logistic loss, scale them to that range, for example (cost - min cost) / (max cost - min cost))");
+    }
+
+    #[test]
+    fn test_ldf_learning() {
+        let vw = vw_map();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.csoaa_ldf = true;
+        // Shared features interact with action features
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {feature_indices: vec![0, 1], weight: 1.0});
+        let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        pa.ldf = true;
+        let mut ldf = LdfExample::new(&vw);
+        let mut costs = Vec::new();
+        for _ in 0..20 {
+            read_example(&mut pa, &mut ldf, "shared |A u1\n1:1.0 |B x\n2:0.0 |B y\n\n");
+            ldf.learn(&mut re, &mut fbt, true, 0, &mut costs);
+            read_example(&mut pa, &mut ldf, "shared |A u2\n1:0.0 |B x\n2:1.0 |B y\n\n");
+            ldf.learn(&mut re, &mut fbt, true, 0, &mut costs);
+        }
+        read_example(&mut pa, &mut ldf, "shared |A u1\n|B x\n|B y\n\n");
+        let lowest = ldf.learn(&mut re, &mut fbt, false, 0, &mut costs);
+        assert!(costs[0] > 0.5 && costs[1] < 0.5);
+        assert_eq!(lowest, costs[1]);
+        assert_eq!(format_prediction(&costs, false), "2\n");
+        read_example(&mut pa, &mut ldf, "shared |A u2\n|B x\n|B y\n\n");
+        let mut fixed_costs = Vec::new();
+        ldf.predict(&re.immutable_regressor().unwrap(), &mut fbt, 0, &mut fixed_costs);
+        assert!(fixed_costs[0] < 0.5 && fixed_costs[1] > 0.5);
+        assert_eq!(format_prediction(&fixed_costs, false), "1\n");
+        assert!(ldf.is_empty());
+
+        // Actions without a cost are not learned from
+        read_example(&mut pa, &mut ldf, "shared |A u3\n|B z\n\n");
+        let before = re.weights.iter().map(|w| w.weight).collect::<Vec<f32>>();
+        ldf.learn(&mut re, &mut fbt, true, 0, &mut costs);
+        assert_eq!(costs.len(), 1);
+        assert!(re.weights.iter().map(|w| w.weight).eq(before.into_iter()));
+        // Shared and action feature crossed, and the constant
+        assert_eq!(fbt.feature_buffer.lr_buffer.len(), 2);
+    }
+
+    #[test]
+    fn test_format_prediction() {
+        assert_eq!(format_prediction(&[0.3, 0.1, 0.1], false), "2\n");
+        assert_eq!(format_prediction(&[0.3, 0.1], true), "1:0.300000 2:0.100000\n");
+        assert_eq!(format_prediction(&[], false), "\n");
+    }
+}
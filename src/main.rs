@@ -18,15 +18,27 @@ use flate2::read::MultiGzDecoder;
 
 mod vwmap;
 mod parser;
+mod parser_json;
+mod parser_tsv;
 mod model_instance;
 mod feature_buffer;
 mod regressor;
 mod cmdline;
 mod cache;
+mod cache_index;
+mod shuffle;
+mod sampling;
 mod persistence;
 mod serving;
 mod optimizer;
 mod version;
+mod binning;
+mod quantization;
+mod evaluation;
+mod mlp;
+mod multiclass;
+mod ldf;
+mod cb;
 
 //use crate::regressor::RegressorTrait;
 
@@ -38,6 +50,106 @@ fn main() {
     }    
 }
 
+fn open_input_file(input_filename: &str) -> Result<Box<dyn BufRead>, Box<dyn Error>> {
+    let input = File::open(input_filename)?;
+    match input_filename.ends_with(".gz") {
+        true =>  Ok(Box::new(io::BufReader::new(MultiGzDecoder::new(input)))),
+        false => Ok(Box::new(io::BufReader::new(input)))
+    }
+}
+
+fn open_cache(cl: &clap::ArgMatches, cache_filename: &Option<String>, vw: &vwmap::VwNamespaceMap) -> Result<cache::RecordCache, Box<dyn Error>> {
+    let timestamp_desc_offset = parser::timestamp_desc_offset_from_cmdline(vw, cl)?;
+    let cache_compression = match (cl.value_of("cache_compression"), cl.value_of("data")) {
+        (Some(compression), _) => cache::CacheCompression::from_str(compression)?,
+        (None, Some(input_filename)) => cache::CacheCompression::default_for_input(input_filename),
+        (None, None) => cache::CacheCompression::None
+    };
+    match cache_filename {
+        // Without --data, a missing or unusable cache can't be written
+        Some(cache_filename) => cache::RecordCache::new(cache_filename, true, cl.is_present("data"), vw, timestamp_desc_offset, cache_compression),
+        None => cache::RecordCache::new("", false, false, vw, timestamp_desc_offset, cache_compression)
+    }
+}
+
+// Parses the input and writes the cache, without training
+fn build_cache_only(cl: &clap::ArgMatches, cache_filename: &Option<String>) -> Result<(), Box<dyn Error>> {
+    let input_filename = cl.value_of("data").ok_or("--cache_only needs --data")?;
+    if cache_filename.is_none() {
+        return Err("--cache_only needs --cache or --cache_file")?;
+    }
+    let vw_namespace_map_filepath = Path::new(input_filename).parent().expect("Couldn't access path given by --data").join("vw_namespace_map.csv");
+    let vw = vwmap::VwNamespaceMap::new_from_csv_filepath(vw_namespace_map_filepath)?;
+    let mut cache = open_cache(cl, cache_filename, &vw)?;
+    if cl.is_present("cache_index") {
+        cache.write_index()?;
+    }
+    if cache.reading {
+        println!("Cache file already exists, nothing to do");
+        return Ok(());
+    }
+    let mut bufferred_input = open_input_file(input_filename)?;
+    let mut pa = parser::VowpalParser::new_from_cmdline(&vw, cl)?;
+    let now = Instant::now();
+    let mut example_num = 0;
+    loop {
+        let buffer = match pa.next(&mut bufferred_input) {
+            Ok([]) => break, // EOF
+            Ok(buffer) => buffer,
+            Err(e) => return Err(e)
+        };
+        cache.push_record(buffer)?;
+        example_num += 1;
+    }
+    cache.write_finish()?;
+    let elapsed = now.elapsed();
+    println!("Elapsed: {:.2?} rows: {}", elapsed, example_num);
+    Ok(())
+}
+
+// Regressor as it will be saved by --export_serving_model: pruned, quantized or as it is
+fn serving_regressor(cl: &clap::ArgMatches, re_fixed: &regressor::ImmutableRegressor) -> Result<regressor::ImmutableRegressor, Box<dyn Error>> {
+    match (cl.value_of("prune_threshold"), cl.value_of("serving_quantization")) {
+        (Some(_), Some(_)) => Err("Use either --prune_threshold or --serving_quantization, not both")?,
+        (Some(threshold), None) => re_fixed.pruned(threshold.parse()?),
+        (None, Some(quantization)) => re_fixed.quantized(quantization::WeightQuantization::from_str(quantization)?),
+        (None, None) => Ok(re_fixed.clone())
+    }
+}
+
+fn export_serving_models(cl: &clap::ArgMatches,
+                         mi: &model_instance::ModelInstance,
+                         vw: &vwmap::VwNamespaceMap,
+                         re_fixed: &regressor::ImmutableRegressor) -> Result<(), Box<dyn Error>> {
+    if let Some(filename) = cl.value_of("export_serving_model") {
+        let serving_re = serving_regressor(cl, re_fixed)?;
+        persistence::save_serving_model_to_filename(filename, mi, vw, &serving_re)?;
+        println!("Serving model saved to {}: {} bytes", filename, std::fs::metadata(filename)?.len());
+        if let regressor::ImmutableWeights::Sparse(weights) = &*serving_re.weights {
+            println!("Pruning kept {} of {} weights ({:.3}%)", weights.indices.len(), weights.len, 100.0 * weights.indices.len() as f64 / weights.len as f64);
+        }
+    }
+    if let Some(filename) = cl.value_of("export_mmap_model") {
+        persistence::save_mmap_model_to_filename(filename, mi, vw, re_fixed)?;
+        println!("Memory mapped serving model saved to {}", filename);
+    }
+    Ok(())
+}
+
+// Learns from the example and returns its prediction. With --oaa the example is learned as one binary example
+// per class, probabilities of classes are left in class_predictions
+fn learn(re: &mut dyn regressor::RegressorTrait,
+         oaa: &mut Option<multiclass::OneAgainstAll>,
+         fb: &feature_buffer::FeatureBuffer,
+         update: bool,
+         example_num: u32,
+         class_predictions: &mut Vec<f32>) -> f32 {
+    match oaa {
+        Some(oaa) => oaa.learn(re, fb, update, example_num, class_predictions),
+        None => re.learn(fb, update, example_num)
+    }
+}
+
 fn main2() -> Result<(), Box<dyn Error>>  {
     // We'll parse once the command line into cl and then different objects will examine it
     let cl = cmdline::parse();
@@ -63,6 +175,23 @@ fn main2() -> Result<(), Box<dyn Error>>  {
     };
     
     
+    if let Some(cache_filename) = cl.value_of("cache_verify") {
+        let (num_records, num_blocks) = cache::RecordCache::verify_cache_file(cache_filename)?;
+        println!("Cache file {} is OK: {} examples in {} blocks", cache_filename, num_records, num_blocks);
+        return Ok(());
+    }
+
+    let cache_filename: Option<String> = match (cl.value_of("cache_file"), cl.is_present("cache"), cl.value_of("data")) {
+        (Some(cache_filename), _, _) => Some(cache_filename.to_string()),
+        (None, true, Some(input_filename)) => Some(cache::RecordCache::default_cache_filename(input_filename)),
+        (None, true, None) => return Err("--data expected, or use --cache_file to name the cache")?,
+        (None, false, _) => None
+    };
+
+    if cl.is_present("cache_only") {
+        return build_cache_only(&cl, &cache_filename);
+    }
+
     /* setting up the pipeline, either from command line or from existing regressor */
     // we want heal-allocated objects here
     let vw: vwmap::VwNamespaceMap;
@@ -70,6 +199,14 @@ fn main2() -> Result<(), Box<dyn Error>>  {
     let mi: model_instance::ModelInstance;
 
 
+    let exporting_serving_model = cl.is_present("export_serving_model") || cl.is_present("export_mmap_model");
+    if let (true, Some(initial_filename), None, None) = (exporting_serving_model, cl.value_of("initial_regressor"), cl.value_of("data"), &cache_filename) {
+        // Nothing to learn from, just convert the regressor
+        let (mi, vw, re_fixed) = persistence::new_immutable_regressor_from_filename(initial_filename)?;
+        export_serving_models(&cl, &mi, &vw, &re_fixed)?;
+        return Ok(());
+    }
+
     if cl.is_present("daemon") {
         let filename = cl.value_of("initial_regressor").expect("Daemon mode only supports serving from --initial regressor");
         println!("initial_regressor = {}", filename);
@@ -79,7 +216,16 @@ fn main2() -> Result<(), Box<dyn Error>>  {
         let mut se = serving::Serving::new(&cl, &vw, re_fixed, &mi)?;
         se.serve()?;
     } else {
-        if let Some(filename) = cl.value_of("initial_regressor") {
+        if let Some(filename) = cl.value_of("resume_from") {
+            if cl.is_present("initial_regressor") {
+                return Err("Use either --resume_from or --initial_regressor, not both")?;
+            }
+            println!("resume_from = {}", filename);
+            println!("WARNING: Command line model parameters will be ignored");
+            let (mi2, vw2, re2) = persistence::new_regressor_from_filename(filename, false)?;
+            println!("Skipping {} examples that the checkpoint has already seen", mi2.examples_seen);
+            mi = mi2; vw = vw2; re = re2;
+        } else if let Some(filename) = cl.value_of("initial_regressor") {
             println!("initial_regressor = {}", filename);
             println!("WARNING: Command line model parameters will be ignored");
             let (mi2, vw2, re2) = persistence::new_regressor_from_filename(filename, testonly)?;
@@ -87,15 +233,63 @@ fn main2() -> Result<(), Box<dyn Error>>  {
         } else {
             // We load vw_namespace_map.csv just so we know all the namespaces ahead of time
             // This is one of the major differences from vowpal
-            let input_filename = cl.value_of("data").expect("--data expected");
-            let vw_namespace_map_filepath = Path::new(input_filename).parent().expect("Couldn't access path given by --data").join("vw_namespace_map.csv");
-            vw = vwmap::VwNamespaceMap::new_from_csv_filepath(vw_namespace_map_filepath)?;
-            mi = model_instance::ModelInstance::new_from_cmdline(&cl, &vw)?;
+            // When training only from a cache file, the namespace map stored in the cache is used
+            vw = match (cl.value_of("data"), &cache_filename) {
+                (Some(input_filename), _) => {
+                    let vw_namespace_map_filepath = Path::new(input_filename).parent().expect("Couldn't access path given by --data").join("vw_namespace_map.csv");
+                    vwmap::VwNamespaceMap::new_from_csv_filepath(vw_namespace_map_filepath)?
+                },
+                (None, Some(cache_filename)) => match cache::RecordCache::vwmap_from_cache_file(cache_filename) {
+                    Ok(vw) => vw,
+                    Err(e) => return Err(format!("Without --data, training needs a usable cache file {}: {}", cache_filename, e))?
+                },
+                (None, None) => return Err("--data expected")?
+            };
+            let mut mi2 = model_instance::ModelInstance::new_from_cmdline(&cl, &vw)?;
+            if mi2.binning_descs.iter().any(|bd| bd.num_quantiles > 0) {
+                // Quantile boundaries need a look at the data before the training starts
+                let input_filename = cl.value_of("data").ok_or("Learning bin quantiles needs text input, use --data")?;
+                let bin_sample:u64 = match cl.value_of("bin_sample") {
+                    Some(examples) => examples.parse()?,
+                    None => 1000000
+                };
+                let mut pa = parser::VowpalParser::new_from_cmdline(&vw, &cl)?;
+                pa.oaa = mi2.oaa;
+                pa.ldf = mi2.csoaa_ldf || mi2.cb_explore_adf;
+                pa.cb = mi2.cb_explore_adf;
+                binning::learn_quantile_boundaries(&mut mi2, &mut pa, &mut open_input_file(input_filename)?, bin_sample)?;
+            }
+            mi = mi2;
             re = regressor::get_regressor(&mi);
         };
-        let input_filename = cl.value_of("data").expect("--data expected");
-        let mut cache = cache::RecordCache::new(input_filename, cl.is_present("cache"), &vw);
+        let mut cache = open_cache(&cl, &cache_filename, &vw)?;
+        if cl.is_present("cache_index") {
+            cache.write_index()?;
+        }
+        let mut indexed_reader = match cache_index::SampleOptions::new_from_cmdline(&cl)? {
+            Some(options) => {
+                if !cache.reading {
+                    return Err("--cache_sample_rate, --cache_negative_rate and --cache_range need an existing indexed cache, build it with --cache_only --cache_index")?;
+                }
+                Some(cache_index::IndexedCacheReader::new(cache_filename.as_ref().unwrap(), options)?)
+            },
+            None => None
+        };
+        let mut bufferred_input: Box<dyn BufRead> = match (cache.reading, cl.value_of("data")) {
+            (true, _) => Box::new(io::empty()),     // no need for text input
+            (false, Some(input_filename)) => open_input_file(input_filename)?,
+            (false, None) => return Err(format!("Cache file {} could not be used and there is no --data to read from", cache_filename.unwrap_or_default()))?
+        };
         let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let mut oaa = multiclass::OneAgainstAll::new(&mi);
+        let multiclass_output = multiclass::MulticlassOutput::new_from_cmdline(&cl)?;
+        let mut class_predictions: Vec<f32> = Vec::new();
+        let mut delayed_class_predictions: Vec<f32> = Vec::new();
+        let mut ldf_example = match mi.csoaa_ldf || mi.cb_explore_adf {
+            true => Some(ldf::LdfExample::new(&vw)),
+            false => None
+        };
+        let action_scores = cl.is_present("action_scores");
 
         let predictions_after:u32 = match cl.value_of("predictions_after") {
             Some(examples) => examples.parse()?,
@@ -111,76 +305,288 @@ fn main2() -> Result<(), Box<dyn Error>>  {
         
         let mut delayed_learning_fbs: VecDeque<feature_buffer::FeatureBuffer> = VecDeque::with_capacity(prediction_model_delay as usize);
 
-        // Setup Parser, is rust forcing this disguisting way to do it, or I just don't know the pattern?
-        let input = File::open(input_filename)?;
-        let mut aa;
-        let mut bb;
-        let mut bufferred_input: &mut dyn BufRead = match input_filename.ends_with(".gz") {
-            true =>  { aa = io::BufReader::new(MultiGzDecoder::new(input)); &mut aa },
-            false => { bb = io::BufReader::new(input); &mut bb}
+        // Time based delay: an example is learned from only once the simulated clock (the latest example timestamp) passes its timestamp + delay
+        let prediction_model_delay_seconds:Option<u32> = match cl.value_of("prediction_model_delay_seconds") {
+            Some(delay) => Some(delay.parse()?),
+            None => None
         };
+        let timestamp_desc_offset = parser::timestamp_desc_offset_from_cmdline(&vw, &cl)?;
+        if prediction_model_delay_seconds.is_some() {
+            if prediction_model_delay != 0 {
+                return Err("Use either --prediction_model_delay or --prediction_model_delay_seconds, not both")?;
+            }
+            if timestamp_desc_offset.is_none() {
+                return Err("--prediction_model_delay_seconds needs example timestamps, use --timestamp_namespace")?;
+            }
+        }
+        let mut delayed_learning_timestamped_fbs: VecDeque<(u32, feature_buffer::FeatureBuffer)> = VecDeque::new();
+        let mut simulated_clock:u32 = 0;
 
-        let mut pa = parser::VowpalParser::new(&vw);
+        let mut pa = parser::VowpalParser::new_from_cmdline(&vw, &cl)?;
+        pa.oaa = mi.oaa;
+        pa.ldf = mi.csoaa_ldf || mi.cb_explore_adf;
+        pa.cb = mi.cb_explore_adf;
+
+        let random_seed:u64 = match cl.value_of("random_seed") {
+            Some(seed) => seed.parse()?,
+            None => 0
+        };
+        let mut cb = cb::ContextualBandit::new_from_cmdline(&cl, &mi, 0)?;
+        let mut shuffle_buffer = match cl.value_of("shuffle_buffer") {
+            Some(capacity) => Some(shuffle::ShuffleBuffer::new(capacity.parse()?, random_seed)),
+            None => None
+        };
+        let mut negative_sampler = sampling::NegativeSampler::new(&mi, random_seed);
+        if negative_sampler.is_active() && cl.is_present("cache_negative_rate") {
+            // Negatives would be downsampled twice, with importance or predictions corrected only for one of the rates
+            return Err("Use either --negative_sampling_rate or --cache_negative_rate, not both")?;
+        }
+
+        // Second model predicting the same examples, to compare how well both do
+        // When exporting a pruned or quantized serving model with --testonly, it is compared to the model it is made from
+        let exporting_lossy_serving_model = cl.is_present("export_serving_model") && (cl.is_present("prune_threshold") || cl.is_present("serving_quantization"));
+        if (oaa.is_some() || ldf_example.is_some()) && (cl.is_present("compare_model") || (testonly && exporting_lossy_serving_model)) {
+            return Err("Comparing models is not supported with --oaa, --csoaa_ldf or --cb_explore_adf")?;
+        }
+        if ldf_example.is_some() {
+            if shuffle_buffer.is_some() || indexed_reader.is_some() || prediction_model_delay != 0 || prediction_model_delay_seconds.is_some() {
+                return Err("--csoaa_ldf and --cb_explore_adf examples span multiple lines, so they can't be shuffled, sampled from the cache or delayed")?;
+            }
+            if pa.input_format != parser::InputFormat::Vowpal {
+                return Err("--csoaa_ldf and --cb_explore_adf need vw input format")?;
+            }
+        }
+        let (compare_model_name, compare_mi_re) = match cl.value_of("compare_model") {
+            Some(filename) => {
+                if !testonly {
+                    return Err("--compare_model needs --testonly")?;
+                }
+                let (compare_mi, _compare_vw, compare_re) = persistence::new_immutable_regressor_from_filename(filename)?;
+                ("compare_model", Some((compare_mi, compare_re)))
+            },
+            None if testonly && exporting_lossy_serving_model => ("serving_model", Some((mi.clone(), serving_regressor(&cl, &re.immutable_regressor()?)?))),
+            None => ("", None)
+        };
+        let mut compare_model = match compare_mi_re {
+            Some((compare_mi, compare_re)) => {
+                let compare_fbt = feature_buffer::FeatureBufferTranslator::new(&compare_mi);
+                let compare_calibration_rate = sampling::prediction_calibration_rate(&compare_mi);
+                Some((compare_re, compare_fbt, compare_calibration_rate, evaluation::BinaryMetrics::new(), evaluation::BinaryMetrics::new()))
+            },
+            None => None
+        };
+        let calibration_rate = sampling::prediction_calibration_rate(&mi);
+
+        let checkpoint_filename: Option<String> = match (cl.value_of("checkpoint_file"), final_regressor_filename) {
+            (Some(filename), _) => Some(filename.to_string()),
+            (None, Some(filename)) => Some(format!("{}.checkpoint", filename)),
+            (None, None) => None
+        };
+        let checkpoint_every:Option<u64> = match cl.value_of("checkpoint_every") {
+            Some(examples) => Some(examples.parse()?),
+            None => None
+        };
+        let checkpoint_every_seconds:Option<u64> = match cl.value_of("checkpoint_every_seconds") {
+            Some(seconds) => Some(seconds.parse()?),
+            None => None
+        };
+        if (checkpoint_every.is_some() || checkpoint_every_seconds.is_some()) && checkpoint_filename.is_none() {
+            return Err("Checkpoints need --checkpoint_file or --final_regressor")?;
+        }
+        // Checkpoint counts examples read, so examples still waiting to be learned would be skipped on resume
+        if (checkpoint_every.is_some() || checkpoint_every_seconds.is_some()) 
+           && (shuffle_buffer.is_some() || prediction_model_delay != 0 || prediction_model_delay_seconds.is_some()) {
+            return Err("Checkpoints can't be taken with --shuffle_buffer, --prediction_model_delay or --prediction_model_delay_seconds: examples waiting in the buffer or the delay queue would be lost on --resume_from")?;
+        }
+        let mut last_checkpoint = Instant::now();
+
+        // When resuming, examples that the checkpoint has already seen are read (and cached), but not learned from
+        let skip_records = match cl.is_present("resume_from") {
+            true => mi.examples_seen,
+            false => 0
+        };
+        let mut records_read:u64 = 0;
+        let mut last_checkpoint_records = skip_records;
 
         let now = Instant::now();
-        let mut example_num = 0;
+        let mut example_num = skip_records as u32;
+        let mut input_finished = false;
         loop {
 
             let reading_result;
             let buffer:&[u32];
-            if !cache.reading {
-                reading_result = pa.next_vowpal(&mut bufferred_input);
-                buffer = match reading_result {
-                        Ok([]) => break, // EOF
-                        Ok(buffer2) => buffer2,
-                        Err(_e) => return Err("Error")?
+            if input_finished {
+                // Only the shuffle buffer can still hold examples
+                buffer = match shuffle_buffer.as_mut().and_then(|sb| sb.pop()) {
+                    Some(buffer) => buffer,
+                    None => match ldf_example.as_ref() {
+                        // Last multi-line example doesn't need an empty line after it
+                        Some(ldf_example) if !ldf_example.is_empty() => &ldf::END_RECORD,
+                        _ => break
+                    }
                 };
-                if cache.writing {
-                        cache.push_record(buffer)?;
-                }
             } else {
-                reading_result = cache.get_next_record();
-                buffer = match reading_result {
-                        Ok([]) => break, // EOF
-                        Ok(buffer) => buffer,
-                        Err(_e) => return Err("Error")?
+                let record:&[u32];
+                if let Some(ir) = indexed_reader.as_mut() {
+                    reading_result = ir.get_next_record();
+                    record = match reading_result {
+                            Ok([]) => { input_finished = true; continue }, // EOF
+                            Ok(buffer) => buffer,
+                            Err(e) => return Err(e)
+                    };
+                } else if !cache.reading {
+                    reading_result = pa.next(&mut bufferred_input);
+                    record = match reading_result {
+                            Ok([]) => { input_finished = true; continue }, // EOF
+                            Ok(buffer2) => buffer2,
+                            Err(e) => return Err(e)
+                    };
+                    if cache.writing {
+                            cache.push_record(record)?;
+                    }
+                } else {
+                    reading_result = cache.get_next_record();
+                    record = match reading_result {
+                            Ok([]) => { input_finished = true; continue }, // EOF
+                            Ok(buffer) => buffer,
+                            Err(e) => return Err(e)
+                    };
+                }
+                records_read += 1;
+                if records_read <= skip_records {
+                    continue;
+                }
+                buffer = match shuffle_buffer.as_mut() {
+                    Some(sb) => match sb.push(record) {
+                        Some(buffer) => buffer,
+                        None => continue
+                    },
+                    None => record
                 };
             }
+            if let Some(ldf_example) = ldf_example.as_mut() {
+                // Lines of a multi-line example are collected until the empty line that ends it
+                if !ldf_example.push(buffer)? {
+                    continue;
+                }
+            }
             example_num += 1;
-            fbt.translate(buffer);
+            if ldf_example.is_none() {
+                fbt.translate(buffer);
+            }
             let mut prediction: f32 = 0.0;
 
-            if prediction_model_delay == 0 {
-                let update = match holdout_after_option {
+            // Dropped negatives are not learned from, but are still predicted when predictions are asked for
+            let sampled = match negative_sampler.is_active() && !testonly {
+                true => match negative_sampler.sample(buffer) {
+                    Some(importance_multiplier) => {
+                        fbt.feature_buffer.example_importance *= importance_multiplier;
+                        true
+                    },
+                    None => false
+                },
+                false => true
+            };
+
+            if let Some(delay_seconds) = prediction_model_delay_seconds {
+                let timestamp = match parser::get_timestamp(buffer, timestamp_desc_offset.unwrap()) {
+                    Some(timestamp) => timestamp,
+                    None => return Err(format!("Example {} has no timestamp", example_num))?
+                };
+                simulated_clock = simulated_clock.max(timestamp);
+                while let Some((delayed_timestamp, _)) = delayed_learning_timestamped_fbs.front() {
+                    if delayed_timestamp.saturating_add(delay_seconds) > simulated_clock {
+                        break;
+                    }
+                    let (_, delayed_buffer) = delayed_learning_timestamped_fbs.pop_front().unwrap();
+                    learn(&mut *re, &mut oaa, &delayed_buffer, !testonly, example_num, &mut delayed_class_predictions);
+                }
+                if example_num > predictions_after {
+                    prediction = learn(&mut *re, &mut oaa, &fbt.feature_buffer, false, example_num, &mut class_predictions);
+                }
+                if sampled {
+                    delayed_learning_timestamped_fbs.push_back((timestamp, fbt.feature_buffer.clone()));
+                }
+            } else if prediction_model_delay == 0 {
+                let update = sampled && match holdout_after_option {
                     Some(holdout_after) => !testonly && example_num < holdout_after,
                     None => !testonly
                 };
-                prediction = re.learn(&fbt.feature_buffer, update, example_num);
+                if sampled || example_num > predictions_after {
+                    prediction = match (ldf_example.as_mut(), cb.as_mut()) {
+                        (Some(ldf_example), Some(cb)) => cb.learn(ldf_example, &mut *re, &mut fbt, update, example_num, &mut class_predictions),
+                        (Some(ldf_example), None) => ldf_example.learn(&mut *re, &mut fbt, update, example_num, &mut class_predictions),
+                        (None, _) => learn(&mut *re, &mut oaa, &fbt.feature_buffer, update, example_num, &mut class_predictions)
+                    };
+                }
             } else {
                 if example_num > predictions_after {
-                    prediction = re.learn(&fbt.feature_buffer, false, example_num);
+                    prediction = learn(&mut *re, &mut oaa, &fbt.feature_buffer, false, example_num, &mut class_predictions);
+                }
+                if sampled {
+                    delayed_learning_fbs.push_back(fbt.feature_buffer.clone());
                 }
-                delayed_learning_fbs.push_back(fbt.feature_buffer.clone());
                 if (prediction_model_delay as usize) < delayed_learning_fbs.len() {
                     let delayed_buffer = delayed_learning_fbs.pop_front().unwrap();
-                    re.learn(&delayed_buffer, !testonly, example_num);
+                    learn(&mut *re, &mut oaa, &delayed_buffer, !testonly, example_num, &mut delayed_class_predictions);
                 }
             } 
             
             if example_num > predictions_after {
-                match predictions_file.as_mut() {
-                    Some(file) =>  write!(file, "{:.6}\n", prediction)?,
-                    None => {}
+                let prediction = sampling::calibrate_prediction(prediction, calibration_rate);
+                match (predictions_file.as_mut(), &oaa, &ldf_example) {
+                    (Some(file), Some(_), _) => file.write_all(multiclass_output.format(&class_predictions).as_bytes())?,
+                    (Some(file), None, Some(_)) => match cb.as_ref() {
+                        Some(cb) if !action_scores => file.write_all(cb.format_prediction().as_bytes())?,
+                        _ => file.write_all(ldf::format_prediction(&class_predictions, action_scores).as_bytes())?
+                    },
+                    (Some(file), None, None) =>  write!(file, "{:.6}\n", prediction)?,
+                    (None, _, _) => {}
+                }
+                if let Some((compare_re, compare_fbt, compare_calibration_rate, metrics, compare_metrics)) = compare_model.as_mut() {
+                    if buffer[parser::LABEL_OFFSET] != parser::NO_LABEL {
+                        compare_fbt.translate(buffer);
+                        let compare_prediction = compare_re.predict(&compare_fbt.feature_buffer, example_num);
+                        let compare_prediction = sampling::calibrate_prediction(compare_prediction, *compare_calibration_rate);
+                        let positive = buffer[parser::LABEL_OFFSET] == 1;
+                        metrics.push(prediction, positive);
+                        compare_metrics.push(compare_prediction, positive);
+                    }
                 }
             }
+
+            let checkpoint_due = !input_finished && match (checkpoint_every, checkpoint_every_seconds) {
+                (Some(examples), _) if records_read >= last_checkpoint_records + examples => true,
+                (_, Some(seconds)) => example_num % 1000 == 0 && last_checkpoint.elapsed().as_secs() >= seconds,
+                _ => false
+            };
+            if checkpoint_due {
+                let mut checkpoint_mi = mi.clone();
+                checkpoint_mi.examples_seen = records_read;
+                persistence::save_regressor_checkpoint(checkpoint_filename.as_ref().unwrap(), &checkpoint_mi, &vw, &*re)?;
+                last_checkpoint = Instant::now();
+                last_checkpoint_records = records_read;
+            }
             
         }
         cache.write_finish()?;
+        let mut final_mi = mi.clone();
+        final_mi.examples_seen = records_read;
         match final_regressor_filename {
-            Some(filename) => persistence::save_regressor_to_filename(filename, &mi, &vw, re).unwrap(),
+            Some(filename) => persistence::save_regressor_to_filename(filename, &final_mi, &vw, &*re)?,
             None => {}
         }
+        if exporting_serving_model {
+            export_serving_models(&cl, &final_mi, &vw, &re.immutable_regressor()?)?;
+        }
     
+        if let Some((_, _, _, metrics, compare_metrics)) = compare_model.as_mut() {
+            evaluation::print_comparison(metrics, compare_metrics, compare_model_name);
+        }
+        if let Some(cb) = cb.as_ref() {
+            cb.print_policy_cost_estimate();
+        }
+
         let elapsed = now.elapsed();
         println!("Elapsed: {:.2?} rows: {}", elapsed, example_num);
     }
@@ -0,0 +1,282 @@
+use merand48::*;
+
+use crate::optimizer::OptimizerTrait;
+use crate::quantization::WeightLookup;
+use crate::regressor::WeightAndOptimizerData;
+
+/*
+Small neural network on top of the LR sum and the sums of ffm interactions of every pair of fields.
+Its output is added to the plain sum of those inputs, so it learns a correction of the linear model and, since
+the output layer is initialized to zero, it starts as exactly the linear model.
+Hidden layers use ReLU. Weights of a layer are stored one row per neuron, followed by the biases of the layer.
+*/
+
+pub const MLP_MAX_LAYERS: usize = 2;
+pub const MLP_MAX_WIDTH: u32 = 256;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MlpLayout {
+    pub inputs: usize,
+    pub hidden: [usize; MLP_MAX_LAYERS],    // widths of hidden layers, 0 when there is no second layer
+    pub offset: usize,                      // index of the first weight
+}
+
+impl MlpLayout {
+    pub fn new(inputs: usize, hidden: &[u32], offset: usize) -> MlpLayout {
+        let mut layout = MlpLayout {
+            inputs: inputs,
+            hidden: [0; MLP_MAX_LAYERS],
+            offset: offset,
+        };
+        for (i, width) in hidden.iter().enumerate() {
+            layout.hidden[i] = *width as usize;
+        }
+        layout
+    }
+
+    pub fn layers(&self) -> usize {
+        if self.hidden[1] > 0 {2} else {1}
+    }
+
+    pub fn weights_len(&self) -> usize {
+        let mut len = 0;
+        let mut fan_in = self.inputs;
+        for layer in 0..self.layers() {
+            len += self.hidden[layer] * (fan_in + 1);
+            fan_in = self.hidden[layer];
+        }
+        len + fan_in + 1
+    }
+
+    pub fn activations_len(&self) -> usize {
+        self.hidden[0] + self.hidden[1]
+    }
+}
+
+// Per example state of training: inputs and activations filled by forward(), the rest by backward_and_update()
+pub struct MlpBuffers {
+    pub inputs: Vec<f32>,
+    pub activations: Vec<f32>,
+    pub deltas: Vec<f32>,               // derivatives of the output by pre-activations of neurons
+    pub input_gradients: Vec<f32>,      // derivatives of the output by the inputs
+}
+
+impl MlpBuffers {
+    pub fn new(layout: &MlpLayout) -> MlpBuffers {
+        MlpBuffers {
+            inputs: vec![0.0; layout.inputs],
+            activations: vec![0.0; layout.activations_len()],
+            deltas: vec![0.0; layout.activations_len()],
+            input_gradients: vec![0.0; layout.inputs],
+        }
+    }
+}
+
+// Number of inputs: LR sum and every pair of fields, including a field with itself
+pub fn inputs_len(fields_count: usize) -> usize {
+    1 + fields_count * (fields_count + 1) / 2
+}
+
+// Input index of interactions of fields a <= b
+#[inline(always)]
+pub fn field_pair_input(a: usize, b: usize, fields_count: usize) -> usize {
+    1 + a * (2 * fields_count - a + 1) / 2 + (b - a)
+}
+
+pub fn init_weights<L: OptimizerTrait>(weights: &mut [WeightAndOptimizerData<L>], layout: &MlpLayout, optimizer: &L) {
+    let mut index = layout.offset;
+    let mut fan_in = layout.inputs;
+    for layer in 0..layout.layers() {
+        // He initialization for ReLU, biases are zero
+        let width = (6.0 / fan_in as f32).sqrt();
+        let width_len = layout.hidden[layer] * fan_in;
+        for i in 0..layout.hidden[layer] * (fan_in + 1) {
+            weights[index + i].weight = if i < width_len {(merand48((index + i) as u64) - 0.5) * 2.0 * width} else {0.0};
+            weights[index + i].optimizer_data = optimizer.initial_data();
+        }
+        index += layout.hidden[layer] * (fan_in + 1);
+        fan_in = layout.hidden[layer];
+    }
+    for i in 0..fan_in + 1 {
+        weights[index + i].weight = 0.0;
+        weights[index + i].optimizer_data = optimizer.initial_data();
+    }
+}
+
+#[inline(always)]
+unsafe fn dense_relu<W: WeightLookup + ?Sized>(weights: &W, index: usize, input: &[f32], output: &mut [f32]) {
+    let fan_in = input.len();
+    let biases_index = index + output.len() * fan_in;
+    for (j, out) in output.iter_mut().enumerate() {
+        let row_index = index + j * fan_in;
+        let mut sum = weights.weight(biases_index + j);
+        for (i, x) in input.iter().enumerate() {
+            sum += weights.weight(row_index + i) * x;
+        }
+        *out = sum.max(0.0);
+    }
+}
+
+// Returns the output, activations of hidden layers are left in activations
+pub unsafe fn forward<W: WeightLookup + ?Sized>(weights: &W, layout: &MlpLayout, inputs: &[f32], activations: &mut [f32]) -> f32 {
+    let (h1, h2) = (layout.hidden[0], layout.hidden[1]);
+    let (first, rest) = activations.split_at_mut(h1);
+    dense_relu(weights, layout.offset, &inputs[..layout.inputs], first);
+    let mut index = layout.offset + h1 * (layout.inputs + 1);
+    let last: &[f32] = if layout.layers() == 2 {
+        dense_relu(weights, index, first, &mut rest[..h2]);
+        index += h2 * (h1 + 1);
+        &rest[..h2]
+    } else {
+        first
+    };
+    let mut output = weights.weight(index + last.len());
+    for (j, a) in last.iter().enumerate() {
+        output += weights.weight(index + j) * a;
+    }
+    output
+}
+
+// Computes derivatives of the output by the inputs and updates weights, must follow forward() of buffers.inputs
+pub unsafe fn backward_and_update<L: OptimizerTrait>(weights: &mut [WeightAndOptimizerData<L>],
+                                                     optimizer: &L,
+                                                     layout: &MlpLayout,
+                                                     buffers: &mut MlpBuffers,
+                                                     general_gradient: f32) {
+    let MlpBuffers {inputs, activations, deltas, input_gradients} = buffers;
+    let (n, h1, h2) = (layout.inputs, layout.hidden[0], layout.hidden[1]);
+    let layer2_index = layout.offset + h1 * (n + 1);
+    let (output_index, last_start, last_len) = if layout.layers() == 2 {
+        (layer2_index + h2 * (h1 + 1), h1, h2)
+    } else {
+        (layer2_index, 0, h1)
+    };
+
+    // Derivatives of the output by pre-activations of neurons
+    for j in 0..last_len {
+        let a = *activations.get_unchecked(last_start + j);
+        *deltas.get_unchecked_mut(last_start + j) = if a > 0.0 {weights.get_unchecked(output_index + j).weight} else {0.0};
+    }
+    if layout.layers() == 2 {
+        for k in 0..h1 {
+            let mut delta = 0.0;
+            if *activations.get_unchecked(k) > 0.0 {
+                for j in 0..h2 {
+                    delta += weights.get_unchecked(layer2_index + j * h1 + k).weight * *deltas.get_unchecked(h1 + j);
+                }
+            }
+            *deltas.get_unchecked_mut(k) = delta;
+        }
+    }
+    for i in 0..n {
+        *input_gradients.get_unchecked_mut(i) = 0.0;
+    }
+    for k in 0..h1 {
+        let delta = *deltas.get_unchecked(k);
+        if delta != 0.0 {
+            for i in 0..n {
+                *input_gradients.get_unchecked_mut(i) += weights.get_unchecked(layout.offset + k * n + i).weight * delta;
+            }
+        }
+    }
+
+    macro_rules! update {
+        ($index:expr, $derivative:expr) => {
+            let index = $index;
+            let update = optimizer.calculate_update(general_gradient * $derivative, &mut weights.get_unchecked_mut(index).optimizer_data);
+            weights.get_unchecked_mut(index).weight += update;
+        };
+    }
+    // Output layer
+    for j in 0..last_len {
+        update!(output_index + j, *activations.get_unchecked(last_start + j));
+    }
+    update!(output_index + last_len, 1.0);
+    // Second hidden layer
+    if layout.layers() == 2 {
+        for j in 0..h2 {
+            let delta = *deltas.get_unchecked(h1 + j);
+            if delta != 0.0 {
+                for k in 0..h1 {
+                    update!(layer2_index + j * h1 + k, delta * *activations.get_unchecked(k));
+                }
+                update!(layer2_index + h2 * h1 + j, delta);
+            }
+        }
+    }
+    // First hidden layer
+    for k in 0..h1 {
+        let delta = *deltas.get_unchecked(k);
+        if delta != 0.0 {
+            for i in 0..n {
+                update!(layout.offset + k * n + i, delta * *inputs.get_unchecked(i));
+            }
+            update!(layout.offset + h1 * n + k, delta);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::optimizer;
+    use crate::regressor::Weight;
+
+    #[test]
+    fn test_mlp_layout() {
+        assert_eq!(inputs_len(3), 7);
+        assert_eq!(field_pair_input(0, 0, 3), 1);
+        assert_eq!(field_pair_input(0, 2, 3), 3);
+        assert_eq!(field_pair_input(1, 1, 3), 4);
+        assert_eq!(field_pair_input(2, 2, 3), 6);
+        let layout = MlpLayout::new(7, &[4], 10);
+        assert_eq!(layout.layers(), 1);
+        assert_eq!(layout.weights_len(), 4 * 8 + 5);
+        let layout = MlpLayout::new(7, &[4, 3], 10);
+        assert_eq!(layout.layers(), 2);
+        assert_eq!(layout.weights_len(), 4 * 8 + 3 * 5 + 4);
+        assert_eq!(layout.activations_len(), 7);
+    }
+
+    #[test]
+    fn test_mlp_gradients() {
+        // Numerical check of derivatives by the inputs and by the weights
+        let layout = MlpLayout::new(3, &[4, 3], 0);
+        let optimizer = optimizer::OptimizerSGD::new();
+        let mut weights = vec![WeightAndOptimizerData::<optimizer::OptimizerSGD>{weight: 0.0, optimizer_data: optimizer.initial_data()}; layout.weights_len()];
+        init_weights(&mut weights, &layout, &optimizer);
+        for i in layout.weights_len() - 4..layout.weights_len() {
+            weights[i].weight = 0.3 * i as f32 - 10.0;  // output layer
+        }
+        let as_weights = |w: &Vec<WeightAndOptimizerData<optimizer::OptimizerSGD>>| w.iter().map(|w| Weight{weight: w.weight}).collect::<Vec<Weight>>();
+        let inputs = [0.5, -1.0, 2.0];
+        let mut buffers = MlpBuffers::new(&layout);
+        buffers.inputs.copy_from_slice(&inputs);
+        let mut activations = [0.0; 7];
+        unsafe {
+            let output = forward(&as_weights(&weights)[..], &layout, &buffers.inputs, &mut buffers.activations);
+            // With learning rate 1.0 and general gradient 1.0 updates are the derivatives themselves
+            let mut optimizer = optimizer::OptimizerSGD::new();
+            optimizer.init(1.0, 0.0, 0.0);
+            let mut updated = weights.clone();
+            backward_and_update(&mut updated, &optimizer, &layout, &mut buffers, 1.0);
+            let input_gradients = &buffers.input_gradients;
+            let epsilon = 1e-3;
+            for i in 0..3 {
+                let mut moved = inputs;
+                moved[i] += epsilon;
+                let numerical = (forward(&as_weights(&weights)[..], &layout, &moved, &mut activations) - output) / epsilon;
+                assert!((numerical - input_gradients[i]).abs() < 1e-2, "input {} {} {}", i, numerical, input_gradients[i]);
+            }
+            for i in 0..layout.weights_len() {
+                let mut moved = as_weights(&weights);
+                moved[i].weight += epsilon;
+                let numerical = (forward(&moved[..], &layout, &inputs, &mut activations) - output) / epsilon;
+                let derivative = updated[i].weight - weights[i].weight;
+                assert!((numerical - derivative).abs() < 1e-2, "weight {} {} {}", i, numerical, derivative);
+            }
+        }
+    }
+}
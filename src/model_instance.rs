@@ -8,6 +8,8 @@ use serde::{Serialize,Deserialize};//, Deserialize};
 use serde_json::{Value};
 
 use crate::vwmap;
+use crate::mlp;
+use crate::multiclass;
 
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -16,12 +18,28 @@ pub struct FeatureComboDesc {
     pub weight:f32,
 }
 
+// A namespace whose feature values are continuous and get bucketized into categorical features
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BinningDesc {
+    pub namespace_index: usize,
+    pub num_quantiles: u32,     // 0 when boundaries were given explicitly on command line
+    pub boundaries: Vec<f32>,   // sorted, bucket i covers [boundaries[i-1], boundaries[i])
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Copy)]
 pub enum Optimizer {
     SGD = 1,
     Adagrad = 2,
 }
 
+// How contextual bandit costs of logged actions are turned into regression targets (vowpal's --cb_type)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Copy)]
+pub enum CbType {
+    IPS = 1,
+    DR = 2,
+    DM = 3,
+}
+
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModelInstance {
@@ -34,6 +52,12 @@ pub struct ModelInstance {
     pub add_constant_feature: bool,
     pub feature_combo_descs: Vec<FeatureComboDesc>,
     pub ffm_fields: Vec<Vec<usize>>,
+    // for every ffm field, its members that are crosses of several namespaces
+    #[serde(default = "default_ffm_field_crosses")]
+    pub ffm_field_crosses: Vec<Vec<Vec<usize>>>,
+    // for every ffm field, multiplier of its feature values (missing means 1.0)
+    #[serde(default = "default_ffm_field_weights")]
+    pub ffm_field_weights: Vec<f32>,
     #[serde(default = "default_u32_zero")]
     pub ffm_k: u32,
     #[serde(default = "default_u32_zero")]
@@ -45,6 +69,19 @@ pub struct ModelInstance {
 
     #[serde(default = "default_f32_zero")]
     pub ffm_k_threshold: f32,
+    // factorization machine over the LR features (one latent vector per feature)
+    #[serde(default = "default_u32_zero")]
+    pub fm_k: u32,
+    #[serde(default = "default_u32_zero")]
+    pub fm_bit_precision: u32,
+    // field weighted factorization machine over the ffm fields (one latent vector per feature, a weight per field pair)
+    #[serde(default = "default_u32_zero")]
+    pub fwfm_k: u32,
+    #[serde(default = "default_bool_false")]
+    pub ffm_no_intra_field: bool,   // classic FFM, features of the same field don't interact
+    // widths of hidden layers of the neural network head over LR sum and ffm field pairs, empty when not used
+    #[serde(default = "default_mlp_hidden")]
+    pub mlp_hidden: Vec<u32>,
     #[serde(default = "default_f32_zero")]
     pub ffm_init_center: f32,
     #[serde(default = "default_f32_zero")]
@@ -60,17 +97,54 @@ pub struct ModelInstance {
     pub ffm_learning_rate: f32,    
     #[serde(default = "default_f32_zero")]
     pub ffm_power_t: f32,
+    #[serde(default = "default_f32_zero")]
+    pub fm_learning_rate: f32,    
+    #[serde(default = "default_f32_zero")]
+    pub fm_power_t: f32,
+    #[serde(default = "default_f32_zero")]
+    pub mlp_learning_rate: f32,
 
     #[serde(default = "default_optimizer_adagrad")]
     pub optimizer: Optimizer,
+
+    #[serde(default = "default_binning_descs")]
+    pub binning_descs: Vec<BinningDesc>,
+
+    #[serde(default = "default_f32_one")]
+    pub negative_sampling_rate: f32,
+    #[serde(default = "default_bool_false")]
+    pub negative_sampling_calibrate_predictions: bool,
+
+    // number of classes of one against all multiclass, 0 for a binary model
+    #[serde(default = "default_u32_zero")]
+    pub oaa: u32,
+    // cost sensitive multiclass with label dependent features, examples span multiple lines
+    #[serde(default = "default_bool_false")]
+    pub csoaa_ldf: bool,
+    // contextual bandit with action dependent features, examples span multiple lines like with csoaa_ldf
+    #[serde(default = "default_bool_false")]
+    pub cb_explore_adf: bool,
+    #[serde(default = "default_cb_type_dr")]
+    pub cb_type: CbType,
+
+    // number of input examples the model has seen, used to resume from a checkpoint
+    #[serde(default = "default_u64_zero")]
+    pub examples_seen: u64,
     
  
 }
 
 fn default_u32_zero() -> u32{0}
+fn default_u64_zero() -> u64{0}
 fn default_f32_zero() -> f32{0.0}
+fn default_f32_one() -> f32{1.0}
 fn default_bool_false() -> bool{false}
 fn default_optimizer_adagrad() -> Optimizer{Optimizer::Adagrad}
+fn default_cb_type_dr() -> CbType{CbType::DR}
+fn default_binning_descs() -> Vec<BinningDesc>{Vec::new()}
+fn default_ffm_field_crosses() -> Vec<Vec<Vec<usize>>>{Vec::new()}
+fn default_ffm_field_weights() -> Vec<f32>{Vec::new()}
+fn default_mlp_hidden() -> Vec<u32>{Vec::new()}
 
 
 fn create_feature_combo_desc(vw: &vwmap::VwNamespaceMap, s: &str) -> Result<FeatureComboDesc, Box<dyn Error>> {
@@ -102,6 +176,39 @@ fn create_feature_combo_desc(vw: &vwmap::VwNamespaceMap, s: &str) -> Result<Feat
                         })
 }
 
+fn create_binning_desc(vw: &vwmap::VwNamespaceMap, s: &str, learned: bool) -> Result<BinningDesc, Box<dyn Error>> {
+    // Format is namespace:boundary,boundary,... or namespace:number_of_quantiles for learned boundaries
+    let vsplit: Vec<&str> = s.split(":").collect();
+    if vsplit.len() != 2 || vsplit[0].chars().count() != 1 {
+        return Err(Box::new(IOError::new(ErrorKind::Other, format!("binning takes namespace:value, example: \"A:0.5,1.0,2.5\" or \"A:10\", your string was: \"{}\"", s))))
+    }
+    let char = vsplit[0].chars().next().unwrap();
+    let namespace_index = match vw.map_char_to_index.get(&char) {
+        Some(index) => *index,
+        None => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Unknown namespace char in command line: {}", char))))
+    };
+    let mut bd = BinningDesc {
+        namespace_index: namespace_index,
+        num_quantiles: 0,
+        boundaries: Vec::new(),
+    };
+    if learned {
+        bd.num_quantiles = vsplit[1].parse()?;
+        if bd.num_quantiles < 2 {
+            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Number of quantiles has to be at least 2: \"{}\"", s))))
+        }
+    } else {
+        for boundary_str in vsplit[1].split(",") {
+            let boundary: f32 = boundary_str.parse()?;
+            bd.boundaries.push(boundary);
+        }
+        if bd.boundaries.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Bin boundaries have to be strictly increasing: \"{}\"", s))))
+        }
+    }
+    Ok(bd)
+}
+
 impl ModelInstance {
     pub fn new_empty() -> Result<ModelInstance, Box<dyn Error>> {
         let mi = ModelInstance {
@@ -112,20 +219,38 @@ impl ModelInstance {
             hash_mask: 0, // DEPRECATED, UNUSED
             power_t: 0.5,
             ffm_power_t: 0.5,
+            fm_learning_rate: 0.5,
+            fm_power_t: 0.5,
+            mlp_learning_rate: 0.5,
             add_constant_feature: true,
             feature_combo_descs: Vec::new(),
             ffm_fields: Vec::new(),
+            ffm_field_crosses: Vec::new(),
+            ffm_field_weights: Vec::new(),
             ffm_k: 0,
             ffm_bit_precision: 18,
             ffm_separate_vectors: false, // DEPRECATED, UNUSED
             fastmath: true,
             ffm_k_threshold: 0.0,
+            fm_k: 0,
+            fm_bit_precision: 18,
+            fwfm_k: 0,
+            ffm_no_intra_field: false,
+            mlp_hidden: Vec::new(),
             ffm_init_center: 0.0,
             ffm_init_width: 0.0,
             ffm_init_zero_band: 0.0,
             ffm_init_acc_gradient: 0.0,
             init_acc_gradient: 1.0,
             optimizer: Optimizer::SGD,
+            binning_descs: Vec::new(),
+            negative_sampling_rate: 1.0,
+            oaa: 0,
+            csoaa_ldf: false,
+            cb_explore_adf: false,
+            cb_type: CbType::DR,
+            negative_sampling_calibrate_predictions: false,
+            examples_seen: 0,
         };
         Ok(mi)
     }
@@ -193,6 +318,8 @@ impl ModelInstance {
                     None => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Unknown namespace char in command line: {}", char))))
                 };
                 mi.ffm_fields.push(vec![index]);
+                mi.ffm_field_crosses.push(Vec::new());
+                mi.ffm_field_weights.push(1.0);
             }
             mi.ffm_k = k_str.parse().expect("Number expected");
         }
@@ -201,6 +328,30 @@ impl ModelInstance {
             mi.ffm_k = val.parse()?;
         }        
 
+        if let Some(val) = cl.value_of("ffm_k_threshold") {
+            mi.ffm_k_threshold = val.parse()?;
+        }
+
+        if cl.is_present("ffm_no_intra_field") {
+            mi.ffm_no_intra_field = true;
+        }
+
+        if let Some(val) = cl.value_of("mlp_hidden") {
+            for width_str in val.split(",") {
+                let width: u32 = width_str.parse()?;
+                if width == 0 || width > mlp::MLP_MAX_WIDTH {
+                    return Err(Box::new(IOError::new(ErrorKind::Other, format!("--mlp_hidden layer width must be between 1 and {}: \"{}\"", mlp::MLP_MAX_WIDTH, val))))
+                }
+                mi.mlp_hidden.push(width);
+            }
+            if mi.mlp_hidden.len() > mlp::MLP_MAX_LAYERS {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--mlp_hidden supports at most {} hidden layers: \"{}\"", mlp::MLP_MAX_LAYERS, val))))
+            }
+            if mi.ffm_k_threshold > 0.0 {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--mlp_hidden can not be used together with --ffm_k_threshold"))))
+            }
+        }
+
         if let Some(val) = cl.value_of("ffm_init_center") {
             mi.ffm_init_center = val.parse()?;
         }
@@ -223,25 +374,74 @@ impl ModelInstance {
         }
 
         if let Some(in_v) = cl.values_of("ffm_field") {
-            for namespaces_str in in_v {          
+            for field_str in in_v {          
+                let vsplit: Vec<&str> = field_str.split(":").collect(); // We use : as a delimiter for weight
+                let mut field_weight: f32 = 1.0;
+                if vsplit.len() > 2 {
+                    return Err(Box::new(IOError::new(ErrorKind::Other, format!("only one value parameter allowed (denoted with \":\"): \"{}\"", field_str))))
+                }
+                if vsplit.len() == 2 {
+                    field_weight = vsplit[1].parse()?;
+                }
+                let namespaces_str = vsplit[0];
                 let mut field: Vec<usize>= Vec::new();
-                for char in namespaces_str.chars() {
-                    //println!("K: {}", char);
-                    let index = match vw.map_char_to_index.get(&char) {
-                        Some(index) => *index,
-                        None => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Unknown namespace char in command line: {}", char))))
-                    };
-                    field.push(index);
+                let mut field_crosses: Vec<Vec<usize>> = Vec::new();
+                if namespaces_str.contains(",") {
+                    // Comma separated members, a member with several namespaces is their cross: "AB,C"
+                    for member_str in namespaces_str.split(",").filter(|m| !m.is_empty()) {
+                        let feature_indices = create_feature_combo_desc(vw, member_str)?.feature_indices;
+                        if feature_indices.len() == 1 {
+                            field.push(feature_indices[0]);
+                        } else {
+                            field_crosses.push(feature_indices);
+                        }
+                    }
+                } else {
+                    // Every letter is a member namespace
+                    for char in namespaces_str.chars() {
+                        //println!("K: {}", char);
+                        let index = match vw.map_char_to_index.get(&char) {
+                            Some(index) => *index,
+                            None => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Unknown namespace char in command line: {}", char))))
+                        };
+                        field.push(index);
+                    }
                 }
                 mi.ffm_fields.push(field);
+                mi.ffm_field_crosses.push(field_crosses);
+                mi.ffm_field_weights.push(field_weight);
             }
         }
         
+        if let Some(in_v) = cl.values_of("bin") {
+            for value_str in in_v {
+                mi.binning_descs.push(create_binning_desc(vw, value_str, false)?);
+            }
+        }
+
+        if let Some(in_v) = cl.values_of("bin_quantiles") {
+            for value_str in in_v {
+                mi.binning_descs.push(create_binning_desc(vw, value_str, true)?);
+            }
+        }
+
         if let Some(val) = cl.value_of("ffm_bit_precision") {
             mi.ffm_bit_precision = val.parse()?;
             println!("FFM num weight bits = {}", mi.ffm_bit_precision); // vwcompat
         }
 
+        if let Some(val) = cl.value_of("fm_k") {
+            mi.fm_k = val.parse()?;
+        }
+
+        if let Some(val) = cl.value_of("fwfm_k") {
+            mi.fwfm_k = val.parse()?;
+        }
+
+        if let Some(val) = cl.value_of("fm_bit_precision") {
+            mi.fm_bit_precision = val.parse()?;
+        }
+
         if let Some(val) = cl.value_of("bit_precision") {
             mi.bit_precision = val.parse()?;
             println!("Num weight bits = {}", mi.bit_precision); // vwcompat
@@ -255,6 +455,16 @@ impl ModelInstance {
         } else {
             mi.ffm_learning_rate = mi.learning_rate;
         }
+        if let Some(val) = cl.value_of("fm_learning_rate") {
+            mi.fm_learning_rate = val.parse()?;
+        } else {
+            mi.fm_learning_rate = mi.learning_rate;
+        }
+        if let Some(val) = cl.value_of("mlp_learning_rate") {
+            mi.mlp_learning_rate = val.parse()?;
+        } else {
+            mi.mlp_learning_rate = mi.learning_rate;
+        }
 
 
 
@@ -271,6 +481,11 @@ impl ModelInstance {
         } else {
             mi.ffm_power_t = mi.power_t;
         }
+        if let Some(val) = cl.value_of("fm_power_t") {
+            mi.fm_power_t = val.parse()?;
+        } else {
+            mi.fm_power_t = mi.power_t;
+        }
         
         if let Some(val) = cl.value_of("link") {
             if val != "logistic" {
@@ -302,7 +517,74 @@ impl ModelInstance {
             mi.optimizer = Optimizer::Adagrad;
         }
 
-        
+        if let Some(val) = cl.value_of("negative_sampling_rate") {
+            mi.negative_sampling_rate = val.parse()?;
+            if !(mi.negative_sampling_rate > 0.0 && mi.negative_sampling_rate <= 1.0) {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--negative_sampling_rate has to be in (0, 1], got: {}", val))))
+            }
+        }
+
+        if cl.is_present("negative_sampling_calibrate_predictions") {
+            if !cl.is_present("negative_sampling_rate") {
+                return Err(Box::new(IOError::new(ErrorKind::Other, "--negative_sampling_calibrate_predictions needs --negative_sampling_rate")))
+            }
+            mi.negative_sampling_calibrate_predictions = true;
+        }
+
+        if let Some(val) = cl.value_of("oaa") {
+            mi.oaa = val.parse()?;
+            if mi.oaa < 2 || mi.oaa > multiclass::OAA_MAX_CLASSES {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--oaa needs between 2 and {} classes, got: {}", multiclass::OAA_MAX_CLASSES, val))))
+            }
+            if mi.fwfm_k > 0 || !mi.mlp_hidden.is_empty() {
+                return Err(Box::new(IOError::new(ErrorKind::Other, "--oaa can not be used together with --fwfm_k or --mlp_hidden")))
+            }
+            if cl.is_present("negative_sampling_rate") {
+                return Err(Box::new(IOError::new(ErrorKind::Other, "--oaa can not be used together with --negative_sampling_rate")))
+            }
+        }
+
+        if let Some(val) = cl.value_of("csoaa_ldf") {
+            if val != "m" {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--csoaa_ldf only supports 'm' (multiline), got: \"{}\"", val))))
+            }
+            if mi.oaa > 0 || cl.is_present("negative_sampling_rate") {
+                return Err(Box::new(IOError::new(ErrorKind::Other, "--csoaa_ldf can not be used together with --oaa or --negative_sampling_rate")))
+            }
+            mi.csoaa_ldf = true;
+        }
+
+        if cl.is_present("cb_explore_adf") {
+            if mi.oaa > 0 || mi.csoaa_ldf || cl.is_present("negative_sampling_rate") {
+                return Err(Box::new(IOError::new(ErrorKind::Other, "--cb_explore_adf can not be used together with --oaa, --csoaa_ldf or --negative_sampling_rate")))
+            }
+            mi.cb_explore_adf = true;
+        }
+
+        if let Some(val) = cl.value_of("cb_type") {
+            if !mi.cb_explore_adf {
+                return Err(Box::new(IOError::new(ErrorKind::Other, "--cb_type needs --cb_explore_adf")))
+            }
+            mi.cb_type = match val {
+                "ips" => CbType::IPS,
+                "dr" => CbType::DR,
+                "dm" => CbType::DM,
+                _ => return Err(Box::new(IOError::new(ErrorKind::Other, format!("--cb_type only supports 'ips', 'dr' and 'dm', got: \"{}\"", val))))
+            };
+        }
+
+        // Timestamp namespace does not hold a hashed feature, so it can't be used by the model
+        if let Some(val) = cl.value_of("timestamp_namespace") {
+            if let Some(index) = val.chars().next().and_then(|c| vw.map_char_to_index.get(&c)) {
+                let used_in_combos = mi.feature_combo_descs.iter().any(|fc| fc.feature_indices.contains(index));
+                let used_in_ffm = mi.ffm_fields.iter().any(|field| field.contains(index)) 
+                                  || mi.ffm_field_crosses.iter().flatten().any(|cross| cross.contains(index));
+                let used_in_binning = mi.binning_descs.iter().any(|bd| bd.namespace_index == *index);
+                if used_in_combos || used_in_ffm || used_in_binning {
+                    return Err(Box::new(IOError::new(ErrorKind::Other, format!("Timestamp namespace {} cannot be used as a feature", val))))
+                }
+            }
+        }
         
         Ok(mi)
     }
@@ -385,7 +667,30 @@ B,featureB:3
                                 
     }
 
-
+    #[test]
+    fn test_binning_parsing() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let bd = create_binning_desc(&vw, "B:0.5,1,2.5", false).unwrap();
+        assert_eq!(bd, BinningDesc {
+                                namespace_index: 1,
+                                num_quantiles: 0,
+                                boundaries: vec![0.5, 1.0, 2.5],
+                                });
+        let bd = create_binning_desc(&vw, "A:10", true).unwrap();
+        assert_eq!(bd, BinningDesc {
+                                namespace_index: 0,
+                                num_quantiles: 10,
+                                boundaries: vec![],
+                                });
+        assert!(create_binning_desc(&vw, "A:2,1", false).is_err());
+        assert!(create_binning_desc(&vw, "A:1", true).is_err());
+        assert!(create_binning_desc(&vw, "X:1,2", false).is_err());
+        assert!(create_binning_desc(&vw, "AB:1,2", false).is_err());
+    }
 
 
 }
@@ -0,0 +1,301 @@
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+
+use crate::feature_buffer::FeatureBuffer;
+use crate::model_instance;
+use crate::parser;
+use crate::regressor::ImmutableRegressor;
+use crate::regressor::RegressorTrait;
+
+/*
+One against all (vowpal's --oaa): every class is its own binary problem, learned by the same regressor.
+Classes share the weights array the way vowpal strides weights: weights of class c of a feature are at
+(hash << class_bits) + c * stride, where stride is the (aligned) length of the feature's latent vectors: in ffm 
+a feature has one vector for every field. So the regressor just has class_bits more bits in each of its hash 
+spaces (and room for the ffm strides of all classes at the end of the ffm one).
+*/
+
+pub const OAA_MAX_CLASSES: u32 = parser::NO_LABEL - 1;
+
+// Number of bits needed to stride the classes
+pub fn class_bits(classes: u32) -> u32 {
+    let mut bits = 0;
+    while classes > (1 << bits) {
+        bits += 1;
+    }
+    bits
+}
+
+// Distance between ffm weights of two classes of a feature, all its field vectors fit in between
+pub fn ffm_class_stride(mi: &model_instance::ModelInstance) -> u32 {
+    (mi.ffm_fields.len() as u32 * mi.ffm_k).next_power_of_two()
+}
+
+#[derive(Clone)]
+pub struct OneAgainstAll {
+    pub classes: u32,
+    class_bits: u32,
+    ffm_stride: u32,
+    fm_stride: u32,
+    class_feature_buffer: FeatureBuffer,
+}
+
+impl OneAgainstAll {
+    pub fn new(mi: &model_instance::ModelInstance) -> Option<OneAgainstAll> {
+        if mi.oaa == 0 {
+            return None;
+        }
+        Some(OneAgainstAll {
+            classes: mi.oaa,
+            class_bits: class_bits(mi.oaa),
+            ffm_stride: ffm_class_stride(mi),
+            fm_stride: mi.fm_k.next_power_of_two(),
+            class_feature_buffer: FeatureBuffer {
+                label: 0.0,
+                example_importance: 1.0,
+                lr_buffer: Vec::new(),
+                ffm_buffer: Vec::new(),
+                ffm_fields_count: 0,
+                fm_buffer: Vec::new(),
+                fwfm_buffer: Vec::new(),
+            },
+        })
+    }
+
+    // Binary example of class (counted from 0): its label is 1.0 when the example is of that class
+    fn class_feature_buffer(&mut self, fb: &FeatureBuffer, class: u32) -> &FeatureBuffer {
+        let cfb = &mut self.class_feature_buffer;
+        cfb.clone_from(fb);
+        if fb.label != parser::NO_LABEL as f32 {
+            cfb.label = if fb.label == (class + 1) as f32 {1.0} else {0.0};
+        }
+        for hv in cfb.lr_buffer.iter_mut() {
+            hv.hash = (hv.hash << self.class_bits) + class;
+        }
+        for hvs in cfb.ffm_buffer.iter_mut() {
+            hvs.hash = (hvs.hash << self.class_bits) + class * self.ffm_stride;
+        }
+        for hv in cfb.fm_buffer.iter_mut() {
+            hv.hash = (hv.hash << self.class_bits) + class * self.fm_stride;
+        }
+        cfb
+    }
+
+    // Learns (when update is set) from the example and leaves probabilities of all classes in predictions.
+    // Returns the highest one.
+    pub fn learn(&mut self, re: &mut dyn RegressorTrait, fb: &FeatureBuffer, update: bool, example_num: u32, predictions: &mut Vec<f32>) -> f32 {
+        predictions.truncate(0);
+        for class in 0..self.classes {
+            let cfb = self.class_feature_buffer(fb, class);
+            predictions.push(re.learn(cfb, update, example_num));
+        }
+        predictions.iter().cloned().fold(0.0, f32::max)
+    }
+
+    pub fn predict(&mut self, re: &ImmutableRegressor, fb: &FeatureBuffer, example_num: u32, predictions: &mut Vec<f32>) -> f32 {
+        predictions.truncate(0);
+        for class in 0..self.classes {
+            let cfb = self.class_feature_buffer(fb, class);
+            predictions.push(re.predict(cfb, example_num));
+        }
+        predictions.iter().cloned().fold(0.0, f32::max)
+    }
+}
+
+// How predictions of a multiclass model are written out
+#[derive(Clone, Copy, Debug)]
+pub struct MulticlassOutput {
+    pub probabilities: bool,
+    pub softmax: bool,
+}
+
+impl MulticlassOutput {
+    pub fn new_from_cmdline<'a>(cl: &clap::ArgMatches<'a>) -> Result<MulticlassOutput, Box<dyn Error>> {
+        let output = MulticlassOutput {
+            probabilities: cl.is_present("probabilities"),
+            softmax: cl.is_present("softmax"),
+        };
+        if output.softmax && !output.probabilities {
+            return Err(Box::new(IOError::new(ErrorKind::Other, "--softmax needs --probabilities")))
+        }
+        Ok(output)
+    }
+
+    // Either the most probable class (counted from 1), or "class:probability" of every class
+    pub fn format(&self, predictions: &[f32]) -> String {
+        if !self.probabilities {
+            let mut best = 0;
+            for (class, p) in predictions.iter().enumerate() {
+                if *p > predictions[best] {
+                    best = class;
+                }
+            }
+            return format!("{}\n", best + 1);
+        }
+        let mut probabilities: Vec<f32> = predictions.to_vec();
+        if self.softmax {
+            // Regressor gives logistic outputs, softmax is taken over the scores they come from
+            let scores: Vec<f32> = predictions.iter().map(|p| {
+                let p = p.clamp(f32::EPSILON, 1.0 - f32::EPSILON);
+                (p / (1.0 - p)).ln()
+            }).collect();
+            let max_score = scores.iter().cloned().fold(f32::MIN, f32::max);
+            let sum: f32 = scores.iter().map(|s| (s - max_score).exp()).sum();
+            for (p, s) in probabilities.iter_mut().zip(scores.iter()) {
+                *p = (s - max_score).exp() / sum;
+            }
+        }
+        let mut s = String::new();
+        for (class, p) in probabilities.iter().enumerate() {
+            if class > 0 {
+                s.push(' ');
+            }
+            s.push_str(&format!("{}:{:.6}", class + 1, p));
+        }
+        s.push('\n');
+        s
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::feature_buffer::{HashAndValue, HashAndValueAndSeq};
+    use crate::optimizer;
+    use crate::regressor::Regressor;
+
+    #[test]
+    fn test_class_bits() {
+        assert_eq!(class_bits(1), 0);
+        assert_eq!(class_bits(2), 1);
+        assert_eq!(class_bits(3), 2);
+        assert_eq!(class_bits(4), 2);
+        assert_eq!(class_bits(5), 3);
+    }
+
+    #[test]
+    fn test_class_feature_buffer() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.oaa = 3;
+        mi.ffm_k = 3;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        let mut oaa = OneAgainstAll::new(&mi).unwrap();
+        let fb = FeatureBuffer {
+            label: 2.0,
+            example_importance: 1.0,
+            lr_buffer: vec![HashAndValue{hash: 5, value: 1.0}],
+            ffm_buffer: vec![HashAndValueAndSeq{hash: 8, value: 1.0, contra_field_index: 3}],
+            ffm_fields_count: 2,
+            fm_buffer: Vec::new(),
+            fwfm_buffer: Vec::new(),
+        };
+        let cfb = oaa.class_feature_buffer(&fb, 0);
+        assert_eq!(cfb.label, 0.0);
+        assert_eq!(cfb.lr_buffer[0].hash, 20);
+        assert_eq!(cfb.ffm_buffer[0].hash, 32);
+        assert_eq!(cfb.ffm_buffer[0].contra_field_index, 3);
+        let cfb = oaa.class_feature_buffer(&fb, 1);
+        assert_eq!(cfb.label, 1.0);
+        assert_eq!(cfb.lr_buffer[0].hash, 21);
+        assert_eq!(cfb.ffm_buffer[0].hash, 40);
+        // Unlabeled examples stay unlabeled
+        let mut fb = fb;
+        fb.label = parser::NO_LABEL as f32;
+        assert_eq!(oaa.class_feature_buffer(&fb, 2).label, parser::NO_LABEL as f32);
+    }
+
+    #[test]
+    fn test_oaa_learning() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.bit_precision = 10;
+        mi.oaa = 3;
+        let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        assert_eq!(re.weights_len, 1 << 12);
+        let mut oaa = OneAgainstAll::new(&mi).unwrap();
+        let mut fb = FeatureBuffer {
+            label: 2.0,
+            example_importance: 1.0,
+            lr_buffer: vec![HashAndValue{hash: 5, value: 1.0}],
+            ffm_buffer: Vec::new(),
+            ffm_fields_count: 0,
+            fm_buffer: Vec::new(),
+            fwfm_buffer: Vec::new(),
+        };
+        let mut predictions = Vec::new();
+        assert_eq!(oaa.learn(&mut re, &fb, false, 0, &mut predictions), 0.5);
+        assert_eq!(predictions, vec![0.5, 0.5, 0.5]);
+        for _ in 0..10 {
+            oaa.learn(&mut re, &fb, true, 0, &mut predictions);
+        }
+        let best = oaa.learn(&mut re, &fb, false, 0, &mut predictions);
+        assert!(predictions[1] > 0.5 && predictions[0] < 0.5 && predictions[2] < 0.5);
+        assert_eq!(best, predictions[1]);
+        let output = MulticlassOutput {probabilities: false, softmax: false};
+        assert_eq!(output.format(&predictions), "2\n");
+
+        // Immutable regressor predicts the same
+        let mut fixed_predictions = Vec::new();
+        oaa.predict(&re.immutable_regressor().unwrap(), &fb, 0, &mut fixed_predictions);
+        assert_eq!(fixed_predictions, predictions);
+        fb.label = 1.0;
+        oaa.learn(&mut re, &fb, false, 0, &mut fixed_predictions);
+        assert_eq!(fixed_predictions, predictions);
+    }
+
+    #[test]
+    fn test_oaa_ffm_classes_are_independent() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.bit_precision = 10;
+        mi.ffm_bit_precision = 10;
+        mi.ffm_k = 2;
+        mi.ffm_fields = vec![vec![], vec![], vec![]]; // This isn't really used
+        mi.oaa = 3;
+        let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        // Every class has room for the vectors of all fields
+        assert_eq!(ffm_class_stride(&mi), 8);
+        assert_eq!(re.ffm_weights_len, (1 << 12) + 3 * 2 + 3 * 8);
+        let mut oaa = OneAgainstAll::new(&mi).unwrap();
+        let fb = FeatureBuffer {
+            label: 1.0,
+            example_importance: 1.0,
+            lr_buffer: Vec::new(),
+            ffm_buffer: vec![HashAndValueAndSeq{hash: 8, value: 1.0, contra_field_index: 0},
+                             HashAndValueAndSeq{hash: 20, value: 1.0, contra_field_index: 2},
+                             HashAndValueAndSeq{hash: 1022, value: 1.0, contra_field_index: 4}],
+            ffm_fields_count: 3,
+            fm_buffer: Vec::new(),
+            fwfm_buffer: Vec::new(),
+        };
+        let mut predictions = Vec::new();
+        oaa.learn(&mut re, &fb, false, 0, &mut predictions);
+        // Updating only the first class doesn't change predictions of the others
+        for _ in 0..5 {
+            let cfb = oaa.class_feature_buffer(&fb, 0).clone();
+            re.learn(&cfb, true, 0);
+        }
+        let mut new_predictions = Vec::new();
+        oaa.learn(&mut re, &fb, false, 0, &mut new_predictions);
+        assert!(new_predictions[0] != predictions[0]);
+        assert_eq!(new_predictions[1..], predictions[1..]);
+    }
+
+    #[test]
+    fn test_multiclass_output() {
+        let output = MulticlassOutput {probabilities: false, softmax: false};
+        assert_eq!(output.format(&[0.2, 0.7, 0.7]), "2\n");
+        let output = MulticlassOutput {probabilities: true, softmax: false};
+        assert_eq!(output.format(&[0.2, 0.5]), "1:0.200000 2:0.500000\n");
+        // Softmax of scores ln(1/3) and 0
+        let output = MulticlassOutput {probabilities: true, softmax: true};
+        assert_eq!(output.format(&[0.25, 0.5]), "1:0.250000 2:0.750000\n");
+    }
+}
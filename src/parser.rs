@@ -7,6 +7,7 @@ use std::io::ErrorKind;
 use std::str;
 use std::string::String;
 use crate::vwmap;
+use crate::parser_tsv;
 
 const RECBUF_LEN:usize = 2048;
 pub const HEADER_LEN:usize = 3;
@@ -18,13 +19,40 @@ pub const MASK31: u32 = !IS_NOT_SINGLE_MASK;
 pub const NULL: u32= IS_NOT_SINGLE_MASK; // null is just an exact IS_NOT_SINGLE_MASK
 pub const NO_LABEL: u32 = 0xff;
 pub const FLOAT32_ONE: u32 = 1065353216;  // 1.0f32.to_bits()
+// With --csoaa_ldf the label of an action line holds bits of its cost, these two mark the other lines
+// (costs are never as small as floats with these bits)
+pub const LDF_SHARED: u32 = 0xfe;
+pub const LDF_END: u32 = 0xfd;
+
+#[derive (Clone, Copy, Debug, PartialEq)]
+pub enum InputFormat {
+    Vowpal,
+    Json,
+    Tsv,
+    Csv,
+}
 
 #[derive (Clone)]
 pub struct VowpalParser {
     vw_map: vwmap::VwNamespaceMap,
-    tmp_read_buf: Vec<u8>,
+    pub(crate) tmp_read_buf: Vec<u8>,
     namespace_hash_seeds: [u32; 256],     // Each namespace has its hash seed
     pub output_buffer: Vec<u32>,
+    pub input_format: InputFormat,
+    pub tsv_schema: Option<parser_tsv::TsvSchema>,
+    pub(crate) tsv_reader: Option<parser_tsv::TsvReader>,
+    timestamp_desc_offset: usize,   // usize::MAX when there is no timestamp namespace
+    pub oaa: u32,                   // with --oaa labels are classes from 1 to oaa
+    pub ldf: bool,                  // with --csoaa_ldf examples span multiple lines
+    pub cb: bool,                   // with --cb_explore_adf action lines are labeled action:cost:probability
+}
+
+// State of the namespace that is currently being written by the non-vowpal parsers
+pub struct NamespaceWriter {
+    desc_offset: usize,
+    hash_seed: u32,
+    bufpos_namespace_start: usize,
+    num_features: u32,
 }
 
 
@@ -61,6 +89,13 @@ impl VowpalParser {
                             tmp_read_buf: Vec::with_capacity(RECBUF_LEN),
                             output_buffer: Vec::with_capacity(RECBUF_LEN*2),
                             namespace_hash_seeds: [0; 256],
+                            input_format: InputFormat::Vowpal,
+                            tsv_schema: None,
+                            tsv_reader: None,
+                            timestamp_desc_offset: usize::MAX,
+                            oaa: 0,
+                            ldf: false,
+                            cb: false,
                         };
         rr.output_buffer.resize(vw.num_namespaces as usize * NAMESPACE_DESC_LEN + HEADER_LEN, 0);
         for i in 0..=255 {
@@ -69,10 +104,155 @@ impl VowpalParser {
         rr
     }
     
+    pub fn new_from_cmdline<'a>(vw: &vwmap::VwNamespaceMap, cl: &clap::ArgMatches<'a>) -> Result<VowpalParser, Box<dyn Error>> {
+        let mut pa = VowpalParser::new(vw);
+        if let Some(val) = cl.value_of("input_format") {
+            pa.input_format = match val {
+                "vw" => InputFormat::Vowpal,
+                "json" => InputFormat::Json,
+                "tsv" => InputFormat::Tsv,
+                "csv" => InputFormat::Csv,
+                _ => return Err(Box::new(IOError::new(ErrorKind::Other, format!("--input_format only supports 'vw', 'json', 'tsv' and 'csv', got: \"{}\"", val))))
+            };
+        }
+        if pa.input_format == InputFormat::Tsv || pa.input_format == InputFormat::Csv {
+            let schema_str = match cl.value_of("input_schema") {
+                Some(schema_str) => schema_str,
+                None => return Err(Box::new(IOError::new(ErrorKind::Other, "--input_format tsv and csv require --input_schema")))
+            };
+            let delimiter = if pa.input_format == InputFormat::Tsv {b'\t'} else {b','};
+            pa.tsv_schema = Some(parser_tsv::TsvSchema::new(schema_str, delimiter, vw)?);
+        }
+        if let Some(desc_offset) = timestamp_desc_offset_from_cmdline(vw, cl)? {
+            pa.timestamp_desc_offset = desc_offset;
+        }
+        if let Some(val) = cl.value_of("oaa") {
+            pa.oaa = val.parse()?;
+        }
+        pa.ldf = cl.is_present("csoaa_ldf") || cl.is_present("cb_explore_adf");
+        pa.cb = cl.is_present("cb_explore_adf");
+        Ok(pa)
+    }
+
+    // Timestamp namespace holds a single feature, whose name is the unix time of the example.
+    // Instead of a hash, the time is stored directly in the namespace desc.
+    fn write_timestamp(&mut self, feature_name: &[u8], num_features: u32) -> Result<(), Box<dyn Error>> {
+        if num_features > 0 {
+            return Err(Box::new(IOError::new(ErrorKind::Other, "Timestamp namespace can only have one feature")));
+        }
+        let timestamp = match str::from_utf8(feature_name).ok().and_then(|s| s.parse::<u32>().ok()) {
+            Some(timestamp) if timestamp & IS_NOT_SINGLE_MASK == 0 => timestamp,
+            _ => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Failed parsing timestamp: {}", String::from_utf8_lossy(feature_name)))))
+        };
+        self.output_buffer[self.timestamp_desc_offset] = timestamp;
+        Ok(())
+    }
+
     pub fn print(&self) -> () {
         println!("item out {:?}", self.output_buffer);
     }
 
+    // Reads the next example in whatever input format the parser was set up for
+    pub fn next(&mut self, input_bufread: &mut impl BufRead) -> Result<&[u32], Box<dyn Error>> {
+        match self.input_format {
+            InputFormat::Vowpal => self.next_vowpal(input_bufread),
+            InputFormat::Json => self.next_json(input_bufread),
+            InputFormat::Tsv | InputFormat::Csv => self.next_tsv(input_bufread),
+        }
+    }
+
+    /* Helpers for the parsers of other input formats. They produce exactly the same record buffer
+       as next_vowpal() does, however they have to write one namespace at a time */
+    pub(crate) fn start_record(&mut self) {
+        let bufpos: usize = (self.vw_map.num_namespaces as usize) + HEADER_LEN;
+        self.output_buffer.truncate(bufpos);
+        for i in &mut self.output_buffer[0..bufpos] { *i = NULL };
+        self.output_buffer[LABEL_OFFSET] = NO_LABEL;
+        self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = FLOAT32_ONE;
+    }
+
+    pub(crate) fn set_label(&mut self, label: f32) -> Result<(), Box<dyn Error>> {
+        if self.oaa > 0 {
+            if label.fract() != 0.0 || label < 1.0 || label > self.oaa as f32 {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("Label has to be a class from 1 to {}, got: {}", self.oaa, label))))
+            }
+            self.output_buffer[LABEL_OFFSET] = label as u32;
+            return Ok(());
+        }
+        self.output_buffer[LABEL_OFFSET] = if label == 1.0 {
+            1
+        } else if label == -1.0 || label == 0.0 {
+            0
+        } else {
+            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Label can only be 1, -1 or 0, got: {}", label))))
+        };
+        Ok(())
+    }
+
+    pub(crate) fn set_example_importance(&mut self, importance: f32) -> Result<(), Box<dyn Error>> {
+        if importance < 0.0  {
+            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Example importance cannot be negative: {:?}! ", importance))));
+        }
+        self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = importance.to_bits();
+        Ok(())
+    }
+
+    pub(crate) fn start_namespace(&self, namespace_char: char) -> Result<NamespaceWriter, Box<dyn Error>> {
+        let index = match self.vw_map.map_char_to_index.get(&namespace_char) {
+            Some(index) => *index,
+            None => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Unknown namespace: {:?}", namespace_char))))
+        };
+        Ok(NamespaceWriter {
+            desc_offset: index * NAMESPACE_DESC_LEN + HEADER_LEN,
+            hash_seed: self.namespace_hash_seeds[namespace_char as usize & 0xff],
+            bufpos_namespace_start: self.output_buffer.len(),
+            num_features: 0,
+        })
+    }
+
+    pub(crate) fn add_feature(&mut self, nw: &mut NamespaceWriter, feature_name: &[u8], feature_value: f32) -> Result<(), Box<dyn Error>> {
+        if nw.desc_offset == self.timestamp_desc_offset {
+            self.write_timestamp(feature_name, nw.num_features)?;
+            nw.num_features += 1;
+            return Ok(());
+        }
+        let h = murmur3::hash32_with_seed(feature_name, nw.hash_seed) & MASK31;
+        // Same three options as in next_vowpal()
+        if feature_value == 1.0 && nw.num_features == 0 {
+            self.output_buffer[nw.desc_offset] = h;
+        } else {
+            if nw.num_features == 1 && (self.output_buffer[nw.desc_offset] & IS_NOT_SINGLE_MASK) == 0 {
+                let in_place = self.output_buffer[nw.desc_offset];
+                self.output_buffer.push(in_place);
+                self.output_buffer.push(FLOAT32_ONE);
+            }
+            self.output_buffer.push(h);
+            self.output_buffer.push(feature_value.to_bits());
+            self.output_buffer[nw.desc_offset] = IS_NOT_SINGLE_MASK | (((nw.bufpos_namespace_start<<16) + self.output_buffer.len()) as u32);
+        }
+        nw.num_features += 1;
+        Ok(())
+    }
+
+    pub(crate) fn finish_record(&mut self) -> &[u32] {
+        self.output_buffer[0] = self.output_buffer.len() as u32;
+        &self.output_buffer
+    }
+
+    pub(crate) fn is_flush_command(&self) -> bool {
+        let line = &self.tmp_read_buf;
+        line.len() >= 5 && &line[0..5] == b"flush"
+    }
+
+    // Costs are learned with logistic loss, so unlike in vowpal they have to be in its range
+    fn check_cost(cost: f32) -> Result<(), Box<dyn Error>> {
+        if !(0.0..=1.0).contains(&cost) {
+            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Cost has to be between 0 and 1, got: {} (fw learns costs with logistic loss, \
+                                                                        scale them to that range, for example (cost - min cost) / (max cost - min cost))", cost))))
+        }
+        Ok(())
+    }
+
     #[inline(always)]
     pub fn parse_float_or_error(&self, i_start: usize, i_end :usize, error_str: &str) -> Result<f32, Box<dyn Error>> {
         unsafe {
@@ -97,16 +277,62 @@ impl VowpalParser {
 
             let mut current_char_num_of_features = 0;
 
+            if self.ldf && self.tmp_read_buf.iter().all(|c| c.is_ascii_whitespace()) {
+                // Empty line ends a multi-line example
+                self.output_buffer[LABEL_OFFSET] = LDF_END;
+                self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = FLOAT32_ONE;
+                self.output_buffer[0] = self.output_buffer.len() as u32;
+                return Ok(&self.output_buffer);
+            }
+
             unsafe {
                 let p = self.tmp_read_buf.as_ptr();
                 let buf = self.output_buffer.as_mut_ptr();
                 let mut i_start:usize;
                 let mut i_end:usize = 0;
+                let mut logged_probability: Option<f32> = None;
 
                 // first token is a label or "flush" command
                 match *p.add(0) {
+                    c if self.ldf && c != 0x7c && c != 0x66 => {  // "shared" or [action:]cost of an action
+                        while *p.add(i_end) != 0x20 && i_end < rowlen1 - 1 {i_end += 1;};
+                        if &self.tmp_read_buf[0..i_end] == b"shared" {
+                            self.output_buffer[LABEL_OFFSET] = LDF_SHARED;
+                        } else if self.cb {
+                            // action:cost:probability, the action is the line itself so its number is ignored
+                            let colons: Vec<usize> = (0..i_end).filter(|i| self.tmp_read_buf[*i] == 0x3a).collect();
+                            if colons.len() != 2 {
+                                return Err(Box::new(IOError::new(ErrorKind::Other, format!("Logged action label has to be action:cost:probability, got: {}", String::from_utf8_lossy(&self.tmp_read_buf[0..i_end])))))
+                            }
+                            let cost = self.parse_float_or_error(colons[0] + 1, colons[1], "Failed parsing cost")?;
+                            Self::check_cost(cost)?;
+                            let probability = self.parse_float_or_error(colons[1] + 1, i_end, "Failed parsing probability")?;
+                            if !(probability > 0.0 && probability <= 1.0) {
+                                return Err(Box::new(IOError::new(ErrorKind::Other, format!("Probability has to be in (0, 1], got: {}", probability))))
+                            }
+                            let cost = if cost < f32::MIN_POSITIVE {0.0} else {cost};
+                            self.output_buffer[LABEL_OFFSET] = cost.to_bits();
+                            logged_probability = Some(probability);
+                        } else {
+                            let cost_start = self.tmp_read_buf[0..i_end].iter().position(|c| *c == 0x3a).map_or(0, |i| i + 1);
+                            let cost = self.parse_float_or_error(cost_start, i_end, "Failed parsing cost")?;
+                            Self::check_cost(cost)?;
+                            let cost = if cost < f32::MIN_POSITIVE {0.0} else {cost};
+                            self.output_buffer[LABEL_OFFSET] = cost.to_bits();
+                        }
+                        i_end = 0;
+                    },
+                    0x30..=0x39 if self.oaa > 0 => {  // class of a multiclass example
+                        while *p.add(i_end) != 0x20 && i_end < rowlen1 - 1 {i_end += 1;};
+                        let class = str::from_utf8_unchecked(&self.tmp_read_buf[0..i_end]).parse::<u32>().unwrap_or(0);
+                        if class < 1 || class > self.oaa {
+                            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Label has to be a class from 1 to {}, got: {}", self.oaa, String::from_utf8_lossy(&self.tmp_read_buf[0..i_end])))))
+                        }
+                        self.output_buffer[LABEL_OFFSET] = class;
+                        i_end = 0;
+                    },
                     0x31 => self.output_buffer[LABEL_OFFSET] = 1,    // 1
-                    0x2d => self.output_buffer[LABEL_OFFSET] = 0,    // -1
+                    0x2d if self.oaa == 0 => self.output_buffer[LABEL_OFFSET] = 0,    // -1
                     0x7c => self.output_buffer[LABEL_OFFSET] = NO_LABEL, // when first character is |, this means there is no label
                     _ => {
                         // "flush" ascii 66, 6C, 75, 73, 68
@@ -119,7 +345,10 @@ impl VowpalParser {
                 };
                 
                 let rowlen = rowlen1 - 1; // ignore last newline byte
-                if self.output_buffer[LABEL_OFFSET] != NO_LABEL {
+                if let Some(probability) = logged_probability {
+                        // logged action of --cb_explore_adf holds its probability in place of the example importance
+                        self.output_buffer[EXAMPLE_IMPORTANCE_OFFSET] = probability.to_bits();
+                } else if self.output_buffer[LABEL_OFFSET] != NO_LABEL {
                         // if we have a label, let's check if we also have label weight
                         while *p.add(i_end) != 0x20 && i_end < rowlen {i_end += 1;}; // find space
                         while *p.add(i_end) == 0x20 && i_end < rowlen {i_end += 1;}; // find first non-space
@@ -175,6 +404,10 @@ impl VowpalParser {
                         current_char_index = self.vw_map.lookup_char_to_index[current_char] * NAMESPACE_DESC_LEN + HEADER_LEN;
                         current_char_num_of_features = 0;
                         bufpos_namespace_start = self.output_buffer.len(); // this is only used if we will have multiple values
+                    } else if current_char_index == self.timestamp_desc_offset {
+                        let feature_name = self.tmp_read_buf[i_start..i_end_first_part].to_vec();
+                        self.write_timestamp(&feature_name, current_char_num_of_features)?;
+                        current_char_num_of_features += 1;
                     } else { 
                         // We have a feature! Let's hash it and write it to the buffer
                         // println!("item out {:?}", std::str::from_utf8(&rr.tmp_read_buf[i_start..i_end]));
@@ -220,6 +453,34 @@ impl VowpalParser {
 
 
 
+// Desc offset of the namespace given by --timestamp_namespace
+pub fn timestamp_desc_offset_from_cmdline<'a>(vw: &vwmap::VwNamespaceMap, cl: &clap::ArgMatches<'a>) -> Result<Option<usize>, Box<dyn Error>> {
+    match cl.value_of("timestamp_namespace") {
+        Some(val) => {
+            let mut chars = val.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => match vw.map_char_to_index.get(&c) {
+                    Some(index) => Ok(Some(index * NAMESPACE_DESC_LEN + HEADER_LEN)),
+                    None => Err(Box::new(IOError::new(ErrorKind::Other, format!("Unknown namespace char in --timestamp_namespace: {}", c))))
+                },
+                _ => Err(Box::new(IOError::new(ErrorKind::Other, format!("--timestamp_namespace has to be a single letter namespace, got: {}", val))))
+            }
+        },
+        None => Ok(None)
+    }
+}
+
+#[inline(always)]
+pub fn get_timestamp(record_buffer: &[u32], timestamp_desc_offset: usize) -> Option<u32> {
+    let timestamp = record_buffer[timestamp_desc_offset];
+    if timestamp & IS_NOT_SINGLE_MASK != 0 {
+        None    // NULL, example has no timestamp
+    } else {
+        Some(timestamp)
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -448,4 +709,110 @@ C,featureC
  
  
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_timestamp() {
+        let vw_map_string = r#"
+A,featureA
+T,timestamp
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
+          Cursor::new(s.as_bytes().to_vec())
+        }
+        let mut rr = VowpalParser::new(&vw);
+        rr.timestamp_desc_offset = HEADER_LEN + 1;
+
+        let mut buf = str_to_cursor("1 |T 1600000000 |A a\n");
+        let record = rr.next_vowpal(&mut buf).unwrap().to_vec();
+        assert_eq!(record, [5, 1, FLOAT32_ONE, 2988156968 & MASK31, 1600000000]);
+        assert_eq!(get_timestamp(&record, HEADER_LEN + 1), Some(1600000000));
+        assert_eq!(get_timestamp(&record, HEADER_LEN), Some(2988156968 & MASK31));
+
+        // Json goes through add_feature()
+        rr.input_format = InputFormat::Json;
+        let mut buf = str_to_cursor(r#"{"label":1,"ns":{"T":["1600000000"],"A":["a"]}}"#);
+        assert_eq!(rr.next(&mut buf).unwrap(), &record[..]);
+        rr.input_format = InputFormat::Vowpal;
+
+        // No timestamp
+        let mut buf = str_to_cursor("1 |A a\n");
+        assert_eq!(get_timestamp(rr.next_vowpal(&mut buf).unwrap(), HEADER_LEN + 1), None);
+
+        // Errors
+        let mut buf = str_to_cursor("1 |T 1600000000 1600000001\n");
+        assert_eq!(format!("{}", rr.next_vowpal(&mut buf).err().unwrap()), "Timestamp namespace can only have one feature");
+        let mut buf = str_to_cursor("1 |T yesterday\n");
+        assert_eq!(format!("{}", rr.next_vowpal(&mut buf).err().unwrap()), "Failed parsing timestamp: yesterday");
+        let mut buf = str_to_cursor("1 |T 3000000000\n");
+        assert!(rr.next_vowpal(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_oaa_labels() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
+          Cursor::new(s.as_bytes().to_vec())
+        }
+        let mut rr = VowpalParser::new(&vw);
+        rr.oaa = 12;
+
+        let mut buf = str_to_cursor("12 |A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [4, 12, FLOAT32_ONE, 2988156968 & MASK31]);
+        let mut buf = str_to_cursor("3 2.0 |A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [4, 3, 2.0f32.to_bits(), 2988156968 & MASK31]);
+        let mut buf = str_to_cursor("|A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [4, NO_LABEL, FLOAT32_ONE, 2988156968 & MASK31]);
+
+        // Json goes through set_label()
+        rr.input_format = InputFormat::Json;
+        let mut buf = str_to_cursor(r#"{"label":12,"ns":{"A":["a"]}}"#);
+        assert_eq!(rr.next(&mut buf).unwrap(), [4, 12, FLOAT32_ONE, 2988156968 & MASK31]);
+        let mut buf = str_to_cursor(r#"{"label":-1,"ns":{"A":["a"]}}"#);
+        assert_eq!(format!("{}", rr.next(&mut buf).err().unwrap()), "Label has to be a class from 1 to 12, got: -1");
+        rr.input_format = InputFormat::Vowpal;
+
+        // Errors
+        let mut buf = str_to_cursor("13 |A a\n");
+        assert_eq!(format!("{}", rr.next_vowpal(&mut buf).err().unwrap()), "Label has to be a class from 1 to 12, got: 13");
+        let mut buf = str_to_cursor("0 |A a\n");
+        assert_eq!(format!("{}", rr.next_vowpal(&mut buf).err().unwrap()), "Label has to be a class from 1 to 12, got: 0");
+        let mut buf = str_to_cursor("-1 |A a\n");
+        assert!(rr.next_vowpal(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_cb_labels() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
+          Cursor::new(s.as_bytes().to_vec())
+        }
+        let mut rr = VowpalParser::new(&vw);
+        rr.ldf = true;
+        rr.cb = true;
+
+        // Probability of the logged action takes place of the importance
+        let mut buf = str_to_cursor("0:0.5:0.25 |A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [4, 0.5f32.to_bits(), 0.25f32.to_bits(), 2988156968 & MASK31]);
+        let mut buf = str_to_cursor("3:0:1 |A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [4, 0, FLOAT32_ONE, 2988156968 & MASK31]);
+        let mut buf = str_to_cursor("|A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [4, NO_LABEL, FLOAT32_ONE, 2988156968 & MASK31]);
+        let mut buf = str_to_cursor("shared |A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [4, LDF_SHARED, FLOAT32_ONE, 2988156968 & MASK31]);
+
+        // Errors
+        let mut buf = str_to_cursor("0.5 |A a\n");
+        assert_eq!(format!("{}", rr.next_vowpal(&mut buf).err().unwrap()), "Logged action label has to be action:cost:probability, got: 0.5");
+        let mut buf = str_to_cursor("0:2:0.5 |A a\n");
+        assert_eq!(format!("{}", rr.next_vowpal(&mut buf).err().unwrap()), "Cost has to be between 0 and 1, got: 2 (fw learns costs with logistic loss, scale them to that range, for example (cost - min cost) / (max cost - min cost))");
+        let mut buf = str_to_cursor("0:1:0 |A a\n");
+        assert_eq!(format!("{}", rr.next_vowpal(&mut buf).err().unwrap()), "Probability has to be in (0, 1], got: 0");
+    }
+}
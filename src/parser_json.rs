@@ -0,0 +1,158 @@
+use std::error::Error;
+use std::io::BufRead;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use serde_json::Value;
+
+use crate::parser;
+use crate::parser::VowpalParser;
+
+/*
+JSON input format, one example per line:
+{"label":1,"weight":0.5,"ns":{"A":["x","y"],"B":{"price":3.2}}}
+- label: 1, -1 (or 0), missing or null means no label
+- weight: example importance, defaults to 1.0
+- ns: map of single letter namespaces, each is either a list of feature names (value 1.0)
+  or a map from feature name to its value
+Features are hashed exactly as in vowpal format, so "1 |A x y |B price:3.2" produces the same record buffer
+*/
+
+fn json_error(message: String) -> Box<dyn Error> {
+    Box::new(IOError::new(ErrorKind::Other, message))
+}
+
+impl VowpalParser {
+    pub fn next_json(&mut self, input_bufread: &mut impl BufRead) -> Result<&[u32], Box<dyn Error>> {
+        self.tmp_read_buf.truncate(0);
+        match input_bufread.read_until(0x0a, &mut self.tmp_read_buf) {
+            Ok(0) => return Ok(&[]),
+            Ok(_) => {},
+            Err(e) => Err(e)?
+        };
+        if self.is_flush_command() {
+            return Err(Box::new(parser::FlushCommand))
+        }
+
+        let j: Value = match serde_json::from_slice(&self.tmp_read_buf) {
+            Ok(j) => j,
+            Err(e) => return Err(json_error(format!("Failed parsing json example: {}", e)))
+        };
+
+        self.start_record();
+        match &j["label"] {
+            Value::Null => {},
+            Value::Number(n) => self.set_label(n.as_f64().unwrap() as f32)?,
+            v => return Err(json_error(format!("Label has to be a number, got: {}", v)))
+        }
+        match &j["weight"] {
+            Value::Null => {},
+            Value::Number(n) => self.set_example_importance(n.as_f64().unwrap() as f32)?,
+            v => return Err(json_error(format!("Example importance has to be a number, got: {}", v)))
+        }
+
+        let namespaces = match &j["ns"] {
+            Value::Null => return Ok(self.finish_record()),
+            Value::Object(namespaces) => namespaces,
+            v => return Err(json_error(format!("\"ns\" has to be an object of namespaces, got: {}", v)))
+        };
+        for (namespace_str, features) in namespaces {
+            let mut chars = namespace_str.chars();
+            let namespace_char = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(json_error(format!("Only single letter namespaces are allowed, however namespace string is: {:?}", namespace_str)))
+            };
+            let mut nw = self.start_namespace(namespace_char)?;
+            match features {
+                Value::Array(feature_names) => {
+                    for feature_name in feature_names {
+                        match feature_name {
+                            Value::String(s) => self.add_feature(&mut nw, s.as_bytes(), 1.0)?,
+                            v => return Err(json_error(format!("Feature name has to be a string, got: {}", v)))
+                        }
+                    }
+                },
+                Value::Object(feature_values) => {
+                    for (feature_name, feature_value) in feature_values {
+                        match feature_value {
+                            Value::Number(n) => self.add_feature(&mut nw, feature_name.as_bytes(), n.as_f64().unwrap() as f32)?,
+                            v => return Err(json_error(format!("Feature value has to be a number, got: {}", v)))
+                        }
+                    }
+                },
+                v => return Err(json_error(format!("Namespace has to be a list of features or a map of features to values, got: {}", v)))
+            }
+        }
+        Ok(self.finish_record())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::vwmap;
+    use std::io::Cursor;
+
+    fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
+        Cursor::new(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_json() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+C,featureC
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut vowpal = VowpalParser::new(&vw);
+        let mut rr = VowpalParser::new(&vw);
+        rr.input_format = parser::InputFormat::Json;
+
+        // Every json example has to produce exactly the same buffer as its vowpal counterpart
+        let pairs = [
+            (r#"{"label":1,"ns":{"A":["a"]}}"#, "1 |A a"),
+            (r#"{"label":-1,"ns":{"B":["b"]}}"#, "-1 |B b"),
+            (r#"{"label":0,"ns":{"B":["b"]}}"#, "-1 |B b"),
+            (r#"{"ns":{"A":["a"]}}"#, "|A a"),
+            (r#"{"label":1,"ns":{"A":["a","b"]}}"#, "1 |A a b"),
+            (r#"{"label":1,"ns":{"A":["a"],"B":["b"]}}"#, "1 |A a |B b"),
+            (r#"{"label":1,"ns":{"A":{"a":2.0}}}"#, "1 |A a:2.0"),
+            (r#"{"label":1,"ns":{"A":{"a":1.0,"b":2.0,"c":3.0}}}"#, "1 |A a b:2.0 c:3.0"),
+            (r#"{"label":1,"weight":0.5,"ns":{"A":["a"],"B":{"price":3.2}}}"#, "1 0.5 |A a |B price:3.2"),
+            (r#"{"label":1}"#, "1 |C"),
+        ];
+        for (json_str, vowpal_str) in pairs.iter() {
+            let mut buf = str_to_cursor(&format!("{}\n", vowpal_str));
+            let expected = vowpal.next_vowpal(&mut buf).unwrap().to_vec();
+            let mut buf = str_to_cursor(&format!("{}\n", json_str));
+            assert_eq!(rr.next(&mut buf).unwrap(), &expected[..], "json: {}", json_str);
+        }
+
+        // two examples in a stream and end of stream
+        let mut buf = str_to_cursor("{\"label\":1,\"ns\":{\"A\":[\"a\"]}}\n{\"label\":-1,\"ns\":{\"B\":[\"b\"]}}\n");
+        assert_eq!(rr.next(&mut buf).unwrap()[parser::LABEL_OFFSET], 1);
+        assert_eq!(rr.next(&mut buf).unwrap()[parser::LABEL_OFFSET], 0);
+        assert_eq!(rr.next(&mut buf).unwrap().len(), 0);
+
+        let mut buf = str_to_cursor("flush");
+        assert_eq!(rr.next(&mut buf).err().unwrap().is::<parser::FlushCommand>(), true);
+
+        // errors
+        let mut buf = str_to_cursor(r#"{"label":1,"ns":{"AB":["a"]}}"#);
+        assert_eq!(format!("{}", rr.next(&mut buf).err().unwrap()), "Only single letter namespaces are allowed, however namespace string is: \"AB\"");
+        let mut buf = str_to_cursor(r#"{"label":1,"ns":{"X":["a"]}}"#);
+        assert_eq!(format!("{}", rr.next(&mut buf).err().unwrap()), "Unknown namespace: 'X'");
+        let mut buf = str_to_cursor(r#"{"label":2,"ns":{"A":["a"]}}"#);
+        assert_eq!(format!("{}", rr.next(&mut buf).err().unwrap()), "Label can only be 1, -1 or 0, got: 2");
+        let mut buf = str_to_cursor(r#"{"label":1,"weight":-1,"ns":{"A":["a"]}}"#);
+        assert!(rr.next(&mut buf).is_err());
+        let mut buf = str_to_cursor(r#"{"label":1,"ns":{"A":[1]}}"#);
+        assert_eq!(format!("{}", rr.next(&mut buf).err().unwrap()), "Feature name has to be a string, got: 1");
+        let mut buf = str_to_cursor(r#"{"label":1,"ns":{"A":{"a":"b"}}}"#);
+        assert_eq!(format!("{}", rr.next(&mut buf).err().unwrap()), "Feature value has to be a number, got: \"b\"");
+        let mut buf = str_to_cursor("not json\n");
+        assert!(rr.next(&mut buf).is_err());
+    }
+}
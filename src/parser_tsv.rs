@@ -0,0 +1,306 @@
+use std::error::Error;
+use std::io::BufRead;
+use std::io::Cursor;
+use std::io::SeekFrom;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::str;
+
+use crate::parser;
+use crate::parser::VowpalParser;
+use crate::vwmap;
+
+/*
+Columnar input format (tsv or csv), one example per line. Schema lists what each column is:
+  label      - label column: 1, -1 (or 0), empty means no label
+  weight     - example importance column, empty means 1.0
+  A          - column value is a feature name in namespace A (value 1.0)
+  A:name     - column value is a value of feature "name" in namespace A
+  -          - column is ignored (empty string works too)
+Example: "label,weight,A,B,-,C:price"
+Several columns can feed the same namespace. Empty cells are treated as missing features.
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TsvColumn {
+    pub column_index: usize,
+    pub feature_name: Option<String>,   // None means column value is the feature name
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TsvNamespace {
+    pub namespace_char: char,
+    pub columns: Vec<TsvColumn>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TsvSchema {
+    pub delimiter: u8,
+    pub label_column: Option<usize>,
+    pub weight_column: Option<usize>,
+    pub namespaces: Vec<TsvNamespace>,  // grouped by namespace, since parser writes one namespace at a time
+    pub num_columns: usize,
+}
+
+// Building a csv reader is expensive, so we keep one around and feed it one line at a time
+pub struct TsvReader {
+    delimiter: u8,
+    csv_reader: csv::Reader<Cursor<Vec<u8>>>,
+    record: csv::ByteRecord,
+}
+
+impl TsvReader {
+    pub fn new(delimiter: u8) -> TsvReader {
+        TsvReader {
+            delimiter: delimiter,
+            csv_reader: csv::ReaderBuilder::new()
+                            .has_headers(false)
+                            .flexible(true)
+                            .delimiter(delimiter)
+                            // Tsv has no quoting, a quote is just a part of the value
+                            .quoting(delimiter != b'\t')
+                            .from_reader(Cursor::new(Vec::new())),
+            record: csv::ByteRecord::new(),
+        }
+    }
+
+    fn read_line(&mut self, line: &[u8]) -> Result<&csv::ByteRecord, Box<dyn Error>> {
+        let cursor = self.csv_reader.get_mut();
+        cursor.get_mut().clear();
+        cursor.get_mut().extend_from_slice(line);
+        // Rewinding also resets the csv parser state left over from the previous line
+        self.csv_reader.seek_raw(SeekFrom::Start(0), csv::Position::new())?;
+        self.csv_reader.read_byte_record(&mut self.record)?;
+        Ok(&self.record)
+    }
+}
+
+impl Clone for TsvReader {
+    fn clone(&self) -> Self {
+        TsvReader::new(self.delimiter)
+    }
+}
+
+fn schema_error(message: String) -> Box<dyn Error> {
+    Box::new(IOError::new(ErrorKind::Other, message))
+}
+
+impl TsvSchema {
+    pub fn new(schema_str: &str, delimiter: u8, vw: &vwmap::VwNamespaceMap) -> Result<TsvSchema, Box<dyn Error>> {
+        let mut schema = TsvSchema {
+            delimiter: delimiter,
+            label_column: None,
+            weight_column: None,
+            namespaces: Vec::new(),
+            num_columns: 0,
+        };
+        for (column_index, column_str) in schema_str.split(",").enumerate() {
+            schema.num_columns += 1;
+            let column_str = column_str.trim();
+            if column_str.is_empty() || column_str == "-" {
+                continue;
+            }
+            if column_str == "label" {
+                if schema.label_column.is_some() {
+                    return Err(schema_error(format!("Only one label column is allowed in schema: \"{}\"", schema_str)))
+                }
+                schema.label_column = Some(column_index);
+                continue;
+            }
+            if column_str == "weight" {
+                if schema.weight_column.is_some() {
+                    return Err(schema_error(format!("Only one weight column is allowed in schema: \"{}\"", schema_str)))
+                }
+                schema.weight_column = Some(column_index);
+                continue;
+            }
+            let vsplit: Vec<&str> = column_str.splitn(2, ":").collect();
+            let mut chars = vsplit[0].chars();
+            let namespace_char = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(schema_error(format!("Column has to be label, weight, - or a single letter namespace, got: \"{}\"", column_str)))
+            };
+            if !vw.map_char_to_index.contains_key(&namespace_char) {
+                return Err(schema_error(format!("Unknown namespace char in schema: {}", namespace_char)))
+            }
+            let column = TsvColumn {
+                column_index: column_index,
+                feature_name: if vsplit.len() == 2 { Some(vsplit[1].to_string()) } else { None },
+            };
+            match schema.namespaces.iter_mut().find(|ns| ns.namespace_char == namespace_char) {
+                Some(ns) => ns.columns.push(column),
+                None => schema.namespaces.push(TsvNamespace {namespace_char: namespace_char, columns: vec![column]}),
+            }
+        }
+        Ok(schema)
+    }
+}
+
+impl VowpalParser {
+    pub fn next_tsv(&mut self, input_bufread: &mut impl BufRead) -> Result<&[u32], Box<dyn Error>> {
+        self.tmp_read_buf.truncate(0);
+        match input_bufread.read_until(0x0a, &mut self.tmp_read_buf) {
+            Ok(0) => return Ok(&[]),
+            Ok(_) => {},
+            Err(e) => Err(e)?
+        };
+        if self.is_flush_command() {
+            return Err(Box::new(parser::FlushCommand))
+        }
+        // We take the schema out, so we can write to the output buffer while looking at it
+        let schema = match self.tsv_schema.take() {
+            Some(schema) => schema,
+            None => return Err(schema_error("Columnar input format needs a schema, use --input_schema".to_string()))
+        };
+        let mut reader = match self.tsv_reader.take() {
+            Some(reader) => reader,
+            None => TsvReader::new(schema.delimiter),
+        };
+        let result = self.tsv_record_to_buffer(&schema, &mut reader);
+        self.tsv_schema = Some(schema);
+        self.tsv_reader = Some(reader);
+        result?;
+        Ok(self.finish_record())
+    }
+
+    fn tsv_record_to_buffer(&mut self, schema: &TsvSchema, reader: &mut TsvReader) -> Result<(), Box<dyn Error>> {
+        let mut line_len = self.tmp_read_buf.len();
+        while line_len > 0 && (self.tmp_read_buf[line_len - 1] == 0x0a || self.tmp_read_buf[line_len - 1] == 0x0d) {
+            line_len -= 1;
+        }
+        let record = reader.read_line(&self.tmp_read_buf[..line_len])?;
+        if record.len() != schema.num_columns {
+            return Err(schema_error(format!("Expected {} columns, got {}", schema.num_columns, record.len())))
+        }
+
+        self.start_record();
+        if let Some(column_index) = schema.label_column {
+            if !record[column_index].is_empty() {
+                let label = parse_float(&record[column_index], "Failed parsing label")?;
+                self.set_label(label)?;
+            }
+        }
+        if let Some(column_index) = schema.weight_column {
+            if !record[column_index].is_empty() {
+                let importance = parse_float(&record[column_index], "Failed parsing example importance")?;
+                self.set_example_importance(importance)?;
+            }
+        }
+        for ns in &schema.namespaces {
+            let mut nw = self.start_namespace(ns.namespace_char)?;
+            for column in &ns.columns {
+                let cell = &record[column.column_index];
+                if cell.is_empty() {
+                    continue;
+                }
+                match &column.feature_name {
+                    None => self.add_feature(&mut nw, cell, 1.0)?,
+                    Some(feature_name) => {
+                        let feature_value = parse_float(cell, "Failed parsing feature value")?;
+                        self.add_feature(&mut nw, feature_name.as_bytes(), feature_value)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_float(cell: &[u8], error_str: &str) -> Result<f32, Box<dyn Error>> {
+    match str::from_utf8(cell).ok().and_then(|s| s.trim().parse::<f32>().ok()) {
+        Some(f) => Ok(f),
+        None => Err(schema_error(format!("{}: {}", error_str, String::from_utf8_lossy(cell))))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
+        Cursor::new(s.as_bytes().to_vec())
+    }
+
+    fn get_vw() -> vwmap::VwNamespaceMap {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+C,featureC
+"#;
+        vwmap::VwNamespaceMap::new(vw_map_string).unwrap()
+    }
+
+    #[test]
+    fn test_schema() {
+        let vw = get_vw();
+        let schema = TsvSchema::new("label,weight,A,-,B:price,,A", b'\t', &vw).unwrap();
+        assert_eq!(schema, TsvSchema {
+            delimiter: b'\t',
+            label_column: Some(0),
+            weight_column: Some(1),
+            namespaces: vec![
+                TsvNamespace {namespace_char: 'A', columns: vec![TsvColumn {column_index: 2, feature_name: None},
+                                                                 TsvColumn {column_index: 6, feature_name: None}]},
+                TsvNamespace {namespace_char: 'B', columns: vec![TsvColumn {column_index: 4, feature_name: Some("price".to_string())}]},
+            ],
+            num_columns: 7,
+        });
+        assert!(TsvSchema::new("label,label", b'\t', &vw).is_err());
+        assert!(TsvSchema::new("label,X", b'\t', &vw).is_err());
+        assert!(TsvSchema::new("label,AB", b'\t', &vw).is_err());
+    }
+
+    #[test]
+    fn test_tsv() {
+        let vw = get_vw();
+        let mut vowpal = VowpalParser::new(&vw);
+        let mut rr = VowpalParser::new(&vw);
+        rr.input_format = parser::InputFormat::Tsv;
+        rr.tsv_schema = Some(TsvSchema::new("label,weight,A,B,-,B:price,A", b'\t', &vw).unwrap());
+
+        // Every tsv example has to produce exactly the same buffer as its vowpal counterpart
+        let pairs = [
+            ("1\t\ta\t\tignored\t\t", "1 |A a"),
+            ("-1\t\t\tb\t\t\t", "-1 |B b"),
+            ("0\t\t\tb\t\t\t", "-1 |B b"),
+            ("\t\ta\t\t\t\t", "|A a"),
+            ("1\t\ta\t\t\t\tb", "1 |A a b"),
+            ("1\t0.5\ta\tb\t\t3.2\t", "1 0.5 |A a |B b price:3.2"),
+            ("1\t\ta\t\t\t1\t", "1 |A a |B price:1"),
+            ("1\t\t\"a\t\"b\t\t\t", "1 |A \"a |B \"b"),
+        ];
+        for (tsv_str, vowpal_str) in pairs.iter() {
+            let mut buf = str_to_cursor(&format!("{}\n", vowpal_str));
+            let expected = vowpal.next_vowpal(&mut buf).unwrap().to_vec();
+            let mut buf = str_to_cursor(&format!("{}\n", tsv_str));
+            assert_eq!(rr.next(&mut buf).unwrap(), &expected[..], "tsv: {:?}", tsv_str);
+        }
+
+        // windows line endings, two examples and end of stream
+        let mut buf = str_to_cursor("1\t\ta\t\t\t\t\r\n-1\t\ta\t\t\t\t\n");
+        assert_eq!(rr.next(&mut buf).unwrap()[parser::LABEL_OFFSET], 1);
+        assert_eq!(rr.next(&mut buf).unwrap()[parser::LABEL_OFFSET], 0);
+        assert_eq!(rr.next(&mut buf).unwrap().len(), 0);
+
+        // csv
+        let mut rr = VowpalParser::new(&vw);
+        rr.input_format = parser::InputFormat::Csv;
+        rr.tsv_schema = Some(TsvSchema::new("label,A,C:price", b',', &vw).unwrap());
+        let mut buf = str_to_cursor("1,\"a\",2.5\n");
+        let expected = vowpal.next_vowpal(&mut str_to_cursor("1 |A a |C price:2.5\n")).unwrap().to_vec();
+        assert_eq!(rr.next(&mut buf).unwrap(), &expected[..]);
+
+        // errors
+        let mut buf = str_to_cursor("1,a\n");
+        assert_eq!(format!("{}", rr.next(&mut buf).err().unwrap()), "Expected 3 columns, got 2");
+        let mut buf = str_to_cursor("1,a,x\n");
+        assert_eq!(format!("{}", rr.next(&mut buf).err().unwrap()), "Failed parsing feature value: x");
+        let mut buf = str_to_cursor("2,a,1\n");
+        assert_eq!(format!("{}", rr.next(&mut buf).err().unwrap()), "Label can only be 1, -1 or 0, got: 2");
+        // schema survives errors
+        let mut buf = str_to_cursor("1,a,1\n");
+        assert!(rr.next(&mut buf).is_ok());
+    }
+}
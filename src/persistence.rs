@@ -1,27 +1,97 @@
 use std::str;
 use std::error::Error;
 
-use std::io::{Read};
+use std::io::{Read, Write};
+use std::io::{Error as IOError, ErrorKind};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::fs::File;
 use std::io;
 use std::fs;
+use std::path::Path;
+use std::mem;
+use std::slice;
+use std::cmp::min;
 
 use crate::model_instance;
 use crate::regressor;
 use crate::vwmap;
 use crate::optimizer;
+use crate::quantization;
+use half::f16;
 use optimizer::OptimizerTrait;
 use regressor::RegressorTrait;
 
 const REGRESSOR_HEADER_MAGIC_STRING: &[u8; 4] = b"FWRE";    // Fwumious Wabbit REgressor
-const REGRESSOR_HEADER_VERSION:u32 = 4;
+const REGRESSOR_HEADER_VERSION:u32 = 6;
+
+/*
+Version incompatibilites:
+4->5: regressor file ends with a crc32 checksum of everything before it (version 4 files can still be loaded)
+5->6: u32 flags follow the version (version 4 and 5 files can still be loaded)
+*/
+const REGRESSOR_HEADER_VERSION_WITHOUT_CHECKSUM:u32 = 4;
+const REGRESSOR_HEADER_VERSION_WITHOUT_FLAGS:u32 = 5;
+
+// Serving model: weights without optimizer state, so it can only be used for predictions
+const REGRESSOR_FLAG_SERVING_ONLY:u32 = 1;
+// Serving model weights are quantized (see quantization.rs)
+const REGRESSOR_FLAG_WEIGHTS_F16:u32 = 2;
+const REGRESSOR_FLAG_WEIGHTS_INT8:u32 = 4;
+// Serving model weights are pruned: only indices and values of the kept weights are stored
+const REGRESSOR_FLAG_WEIGHTS_SPARSE:u32 = 8;
+
+/*
+Memory mapped serving model: only f32 weights, laid out so that they can be mapped straight into memory
+   4 bytes: magic FWMM
+   u32: version
+   u64 + json: vwmap
+   u64 + json: model instance
+   u64: number of weights
+   u64: offset of weights in the file (page aligned)
+   u32: crc32 of everything before it
+   zero padding up to the weights offset
+   f32 * number of weights
+Weights are not checksummed, since reading them all would defeat the purpose of mapping.
+*/
+const MMAP_MODEL_MAGIC_STRING: &[u8; 4] = b"FWMM";    // Fwumious Wabbit Memory Mapped model
+const MMAP_MODEL_VERSION:u32 = 1;
+const MMAP_MODEL_PAGE_SIZE:u64 = 4096;
+
+// Computes crc32 of everything written through it
+struct ChecksumWriter<W: io::Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: io::Write> io::Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Computes crc32 of everything read through it
+struct ChecksumReader<R: io::Read> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: io::Read> io::Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
 
 impl model_instance::ModelInstance {
     pub fn save_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
         let serialized = serde_json::to_vec_pretty(&self)?;
         output_bufwriter.write_u64::<LittleEndian>(serialized.len() as u64)?;
-        output_bufwriter.write(&serialized)?;
+        output_bufwriter.write_all(&serialized)?;
         Ok(())
     }
     pub fn new_from_buf(input_bufreader: &mut dyn io::Read) -> Result<model_instance::ModelInstance, Box<dyn Error>> {
@@ -35,7 +105,7 @@ impl vwmap::VwNamespaceMap {
     pub fn save_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
         let serialized = serde_json::to_vec_pretty(&self.vw_source)?;
         output_bufwriter.write_u64::<LittleEndian>(serialized.len() as u64)?;
-        output_bufwriter.write(&serialized)?;
+        output_bufwriter.write_all(&serialized)?;
         Ok(())
     }
 
@@ -49,40 +119,252 @@ impl vwmap::VwNamespaceMap {
 
 
 
+// File is written to a temporary file, synced to disk and renamed, so readers never see a half written file
+fn save_atomically(filename: &str,
+                   write_fn: impl FnOnce(&mut io::BufWriter<fs::File>) -> Result<(), Box<dyn Error>>
+                   ) -> Result<(), Box<dyn Error>> {
+    let temporary_filename = format!("{}.writing", filename);
+    let result = (|| -> Result<(), Box<dyn Error>> {
+        let mut output_bufwriter = io::BufWriter::new(fs::File::create(&temporary_filename)?);
+        write_fn(&mut output_bufwriter)?;
+        output_bufwriter.flush()?;
+        let file = output_bufwriter.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temporary_filename, filename)?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temporary_filename);
+        return Err(Box::new(IOError::new(ErrorKind::Other, format!("Cannot save model to {}: {}", filename, e))));
+    }
+    // Make the rename itself durable
+    let parent = match Path::new(filename).parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new(".")
+    };
+    if let Ok(dir) = fs::File::open(parent) {
+        dir.sync_all()?;
+    }
+    Ok(())
+}
+
 pub fn save_regressor_to_filename(
                         filename: &str, 
                         mi: &model_instance::ModelInstance,
                         vwmap: &vwmap::VwNamespaceMap,
-                        re: Box<dyn regressor::RegressorTrait>,
+                        re: &dyn regressor::RegressorTrait,
                         ) -> Result<(), Box<dyn Error>> {
-        let output_bufwriter = &mut io::BufWriter::new(fs::File::create(filename).expect(format!("Cannot open {} to save regressor to", filename).as_str()));
-        write_regressor_header(output_bufwriter)?;
-        vwmap.save_to_buf(output_bufwriter)?;
-        mi.save_to_buf(output_bufwriter)?;
-        re.write_weights_to_buf(output_bufwriter)?;
+    save_atomically(filename, |output_bufwriter| {
+        let mut output = ChecksumWriter {
+            inner: output_bufwriter,
+            hasher: crc32fast::Hasher::new(),
+        };
+        write_regressor_header(&mut output, 0)?;
+        vwmap.save_to_buf(&mut output)?;
+        mi.save_to_buf(&mut output)?;
+        re.write_weights_to_buf(&mut output)?;
+        let checksum = output.hasher.finalize();
+        output.inner.write_u32::<LittleEndian>(checksum)?;
         Ok(())
+    })
+}
+
+pub fn save_regressor_checkpoint(
+                        filename: &str,
+                        mi: &model_instance::ModelInstance,
+                        vwmap: &vwmap::VwNamespaceMap,
+                        re: &dyn regressor::RegressorTrait,
+                        ) -> Result<(), Box<dyn Error>> {
+    save_regressor_to_filename(filename, mi, vwmap, re)?;
+    println!("Checkpoint after {} examples saved to {}", mi.examples_seen, filename);
+    Ok(())
+}
+
+pub fn save_serving_model_to_filename(
+                        filename: &str,
+                        mi: &model_instance::ModelInstance,
+                        vwmap: &vwmap::VwNamespaceMap,
+                        re: &regressor::ImmutableRegressor,
+                        ) -> Result<(), Box<dyn Error>> {
+    save_atomically(filename, |output_bufwriter| {
+        let mut output = ChecksumWriter {
+            inner: output_bufwriter,
+            hasher: crc32fast::Hasher::new(),
+        };
+        let flags = REGRESSOR_FLAG_SERVING_ONLY | match &*re.weights {
+            regressor::ImmutableWeights::Sparse(_) => REGRESSOR_FLAG_WEIGHTS_SPARSE,
+            weights => match weights.quantization() {
+                quantization::WeightQuantization::F32 => 0,
+                quantization::WeightQuantization::F16 => REGRESSOR_FLAG_WEIGHTS_F16,
+                quantization::WeightQuantization::Int8 => REGRESSOR_FLAG_WEIGHTS_INT8,
+            }
+        };
+        write_regressor_header(&mut output, flags)?;
+        vwmap.save_to_buf(&mut output)?;
+        mi.save_to_buf(&mut output)?;
+        write_serving_weights_to_buf(re, &mut output)?;
+        let checksum = output.hasher.finalize();
+        output.inner.write_u32::<LittleEndian>(checksum)?;
+        Ok(())
+    })
+}
+
+fn write_slice_to_buf<T: Copy>(values: &[T], output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+    unsafe {
+        let buf_view:&[u8] = slice::from_raw_parts(values.as_ptr() as *const u8, values.len() * mem::size_of::<T>());
+        output_bufwriter.write_all(buf_view)?;
+    }
+    Ok(())
+}
+
+// Read in chunks, so a damaged length doesn't allocate more than the file has
+fn read_vec_from_buf<T: Copy>(len: usize, zero: T, input_bufreader: &mut dyn io::Read) -> Result<Vec<T>, Box<dyn Error>> {
+    const BUF_LEN:usize = 1024 * 1024;
+    let mut values: Vec<T> = Vec::new();
+    while values.len() < len {
+        let start = values.len();
+        let chunk_size = min(len - start, BUF_LEN);
+        values.resize(start + chunk_size, zero);
+        unsafe {
+            let buf_view:&mut [u8] = slice::from_raw_parts_mut(values[start..].as_mut_ptr() as *mut u8,
+                                             chunk_size * mem::size_of::<T>());
+            input_bufreader.read_exact(buf_view)?;
+        }
+    }
+    Ok(values)
+}
+
+fn write_serving_weights_to_buf(re: &regressor::ImmutableRegressor, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+    output_bufwriter.write_u64::<LittleEndian>(re.weights.len() as u64)?;
+    match &*re.weights {
+        regressor::ImmutableWeights::F16(weights) => write_slice_to_buf(weights, output_bufwriter)?,
+        regressor::ImmutableWeights::Int8(weights) => {
+            output_bufwriter.write_u64::<LittleEndian>(weights.scales.len() as u64)?;
+            write_slice_to_buf(&weights.scales, output_bufwriter)?;
+            write_slice_to_buf(&weights.values, output_bufwriter)?;
+        },
+        regressor::ImmutableWeights::Sparse(weights) => {
+            output_bufwriter.write_u64::<LittleEndian>(weights.indices.len() as u64)?;
+            write_slice_to_buf(&weights.indices, output_bufwriter)?;
+            write_slice_to_buf(&weights.weights, output_bufwriter)?;
+        },
+        weights => write_slice_to_buf(weights.f32_weights().unwrap(), output_bufwriter)?,
+    }
+    Ok(())
+}
+
+fn read_serving_weights_from_buf(flags: u32, input_bufreader: &mut dyn io::Read) -> Result<regressor::ImmutableWeights, Box<dyn Error>> {
+    let len = input_bufreader.read_u64::<LittleEndian>()? as usize;
+    if flags & REGRESSOR_FLAG_WEIGHTS_F16 != 0 {
+        Ok(regressor::ImmutableWeights::F16(read_vec_from_buf(len, f16::ZERO, input_bufreader)?))
+    } else if flags & REGRESSOR_FLAG_WEIGHTS_INT8 != 0 {
+        let num_blocks = input_bufreader.read_u64::<LittleEndian>()? as usize;
+        if num_blocks != (len + quantization::INT8_BLOCK_LEN - 1) / quantization::INT8_BLOCK_LEN {
+            return Err(format!("Number of int8 weight blocks {} does not match {} weights", num_blocks, len))?;
+        }
+        let scales = read_vec_from_buf(num_blocks, 0.0f32, input_bufreader)?;
+        let values = read_vec_from_buf(len, 0i8, input_bufreader)?;
+        Ok(regressor::ImmutableWeights::Int8(quantization::Int8Weights {
+            scales: scales,
+            values: values,
+        }))
+    } else if flags & REGRESSOR_FLAG_WEIGHTS_SPARSE != 0 {
+        let num_kept = input_bufreader.read_u64::<LittleEndian>()? as usize;
+        let indices = read_vec_from_buf(num_kept, 0u32, input_bufreader)?;
+        let weights = read_vec_from_buf(num_kept, 0.0f32, input_bufreader)?;
+        if num_kept > len || indices.windows(2).any(|w| w[0] >= w[1]) || indices.last().map_or(false, |i| *i as usize >= len) {
+            return Err("Indices of pruned weights are not sorted or out of range")?;
+        }
+        Ok(regressor::ImmutableWeights::Sparse(quantization::SparseWeights {
+            len: len,
+            indices: indices,
+            weights: weights,
+        }))
+    } else {
+        Ok(regressor::ImmutableWeights::Owned(read_vec_from_buf(len, regressor::Weight{weight: 0.0}, input_bufreader)?))
     }
+}
 
-fn write_regressor_header(output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
-    // we will write magic string FWFW
-    // And then 32 bit unsigned version of the regressor
-    output_bufwriter.write(REGRESSOR_HEADER_MAGIC_STRING)?;
+pub fn save_mmap_model_to_filename(
+                        filename: &str,
+                        mi: &model_instance::ModelInstance,
+                        vwmap: &vwmap::VwNamespaceMap,
+                        re: &regressor::ImmutableRegressor,
+                        ) -> Result<(), Box<dyn Error>> {
+    let weights = re.weights.f32_weights().ok_or("Memory mapped model needs f32 weights, it cannot be made from a quantized or pruned model")?;
+    save_atomically(filename, |output_bufwriter| {
+        let mut header: Vec<u8> = Vec::new();
+        header.write_all(MMAP_MODEL_MAGIC_STRING)?;
+        header.write_u32::<LittleEndian>(MMAP_MODEL_VERSION)?;
+        vwmap.save_to_buf(&mut header)?;
+        mi.save_to_buf(&mut header)?;
+        header.write_u64::<LittleEndian>(weights.len() as u64)?;
+        let header_len = header.len() as u64 + 8 + 4;
+        let weights_offset = (header_len + MMAP_MODEL_PAGE_SIZE - 1) / MMAP_MODEL_PAGE_SIZE * MMAP_MODEL_PAGE_SIZE;
+        header.write_u64::<LittleEndian>(weights_offset)?;
+        let checksum = crc32fast::hash(&header);
+        header.write_u32::<LittleEndian>(checksum)?;
+        header.resize(weights_offset as usize, 0);
+        output_bufwriter.write_all(&header)?;
+        write_slice_to_buf(weights, output_bufwriter)?;
+        Ok(())
+    })
+}
+
+fn write_regressor_header(output_bufwriter: &mut dyn io::Write, flags: u32) -> Result<(), Box<dyn Error>> {
+    // we will write magic string FWRE
+    // And then 32 bit unsigned version of the regressor and 32 bit flags
+    output_bufwriter.write_all(REGRESSOR_HEADER_MAGIC_STRING)?;
     output_bufwriter.write_u32::<LittleEndian>(REGRESSOR_HEADER_VERSION)?;
+    output_bufwriter.write_u32::<LittleEndian>(flags)?;
     Ok(())
 }
 
-fn load_regressor_without_weights(input_bufreader: &mut io::BufReader::<File>) 
+fn load_regressor_without_weights(input_bufreader: &mut dyn io::Read) 
                         -> Result<(model_instance::ModelInstance,
                                    vwmap::VwNamespaceMap,
                                    Box<dyn regressor::RegressorTrait>,
                                  ), Box<dyn Error>> {
-    verify_header(input_bufreader).expect("Regressor header error");    
-    let vw = vwmap::VwNamespaceMap::new_from_buf(input_bufreader).expect("Loading vwmap from regressor failed");
-    let mi = model_instance::ModelInstance::new_from_buf(input_bufreader).expect("Loading model instance from regressor failed");
-    let re = regressor::get_regressor_without_weights(&mi);//_without_weights(&mi);
+    let vw = vwmap::VwNamespaceMap::new_from_buf(input_bufreader)
+                .map_err(|e| format!("Loading vwmap from regressor failed: {}", e))?;
+    let mi = model_instance::ModelInstance::new_from_buf(input_bufreader)
+                .map_err(|e| format!("Loading model instance from regressor failed: {}", e))?;
+    let re = regressor::get_regressor_without_weights(&mi);
     Ok((mi, vw, re))
 }
 
+// Reads the regressor file with load_fn, then checks the trailing checksum
+fn load_regressor_file<T>(filename: &str, 
+                          for_training: bool,
+                          load_fn: impl FnOnce(&mut dyn io::Read, u32) -> Result<T, Box<dyn Error>>) 
+                        -> Result<T, Box<dyn Error>> {
+    let file = fs::File::open(filename)
+                .map_err(|e| format!("Cannot open regressor file {}: {}", filename, e))?;
+    let mut input = ChecksumReader {
+        inner: io::BufReader::new(file),
+        hasher: crc32fast::Hasher::new(),
+    };
+    let (version, flags) = verify_header(&mut input)
+                .map_err(|e| format!("Regressor file {} header error: {}", filename, e))?;
+    if for_training && flags & REGRESSOR_FLAG_SERVING_ONLY != 0 {
+        return Err(format!("Regressor file {} is a serving model, it has no optimizer state and can only be used for predictions", filename))?;
+    }
+    let loaded = load_fn(&mut input, flags)
+                .map_err(|e| format!("Regressor file {} is damaged: {}", filename, e))?;
+    if version != REGRESSOR_HEADER_VERSION_WITHOUT_CHECKSUM {
+        let checksum = input.hasher.finalize();
+        let stored_checksum = input.inner.read_u32::<LittleEndian>()
+                .map_err(|e| format!("Regressor file {} is truncated: {}", filename, e))?;
+        if checksum != stored_checksum {
+            return Err(format!("Regressor file {} is corrupted: checksum mismatch", filename))?;
+        }
+        let mut trailing = [0u8; 1];
+        if input.inner.read(&mut trailing)? != 0 {
+            return Err(format!("Regressor file {} has unexpected data after the checksum", filename))?;
+        }
+    }
+    Ok(loaded)
+}
 
 pub fn new_regressor_from_filename(filename: &str, immutable: bool) 
                         -> Result<(model_instance::ModelInstance,
@@ -90,13 +372,14 @@ pub fn new_regressor_from_filename(filename: &str, immutable: bool)
                                    Box<dyn regressor::RegressorTrait>), 
                                   Box<dyn Error>> {
     if !immutable {
-        let mut input_bufreader = io::BufReader::new(fs::File::open(filename).unwrap());
-        let (mi, vw, mut re) = load_regressor_without_weights(&mut input_bufreader)?;
-        re.allocate_and_init_weights(&mi);
-        re.overwrite_weights_from_buf(&mut input_bufreader)?;
-        Ok((mi, vw, re))
+        load_regressor_file(filename, true, |input_bufreader, _flags| {
+            let (mi, vw, mut re) = load_regressor_without_weights(input_bufreader)?;
+            re.allocate_and_init_weights(&mi);
+            re.overwrite_weights_from_buf(input_bufreader)?;
+            Ok((mi, vw, re))
+        })
     } else {
-        let (mi, vw, immutable_re) =  new_immutable_regressor_from_filename(filename).unwrap();
+        let (mi, vw, immutable_re) =  new_immutable_regressor_from_filename(filename)?;
         let re = Box::new(immutable_re);
         Ok((mi, vw, re))
     }
@@ -107,27 +390,89 @@ pub fn new_immutable_regressor_from_filename(filename: &str)
                                    vwmap::VwNamespaceMap,
                                    regressor::ImmutableRegressor), 
                                   Box<dyn Error>> {
-    let mut input_bufreader = io::BufReader::new(fs::File::open(filename).unwrap());
-    let (mi, vw, mut re) = load_regressor_without_weights(&mut input_bufreader)?;
-    let immutable_re = re.immutable_regressor_from_buf(&mut input_bufreader)?;
-    Ok((mi, vw, immutable_re))
+    if is_mmap_model_file(filename) {
+        return new_mmap_regressor_from_filename(filename);
+    }
+    load_regressor_file(filename, false, |input_bufreader, flags| {
+        let (mi, vw, mut re) = load_regressor_without_weights(input_bufreader)?;
+        let immutable_re = if flags & REGRESSOR_FLAG_SERVING_ONLY != 0 {
+            re.immutable_regressor_from_weights(read_serving_weights_from_buf(flags, input_bufreader)?)?
+        } else {
+            re.immutable_regressor_from_buf(input_bufreader)?
+        };
+        Ok((mi, vw, immutable_re))
+    })
 }
 
 
+fn is_mmap_model_file(filename: &str) -> bool {
+    let mut magic_string: [u8; 4] = [0;4];
+    match fs::File::open(filename) {
+        Ok(mut file) => file.read_exact(&mut magic_string).is_ok() && &magic_string == MMAP_MODEL_MAGIC_STRING,
+        Err(_) => false
+    }
+}
+
+// Weights are not copied, the operating system pages them in from the file (and shares them between processes)
+pub fn new_mmap_regressor_from_filename(filename: &str)
+                        -> Result<(model_instance::ModelInstance,
+                                   vwmap::VwNamespaceMap,
+                                   regressor::ImmutableRegressor),
+                                  Box<dyn Error>> {
+    let file = fs::File::open(filename)
+                .map_err(|e| format!("Cannot open serving model file {}: {}", filename, e))?;
+    let mut input = ChecksumReader {
+        inner: io::BufReader::new(&file),
+        hasher: crc32fast::Hasher::new(),
+    };
+    let (mi, vw, mut re, num_weights, weights_offset) = (|| -> Result<_, Box<dyn Error>> {
+        let mut magic_string: [u8; 4] = [0;4];
+        input.read_exact(&mut magic_string)?;
+        let version = input.read_u32::<LittleEndian>()?;
+        if version != MMAP_MODEL_VERSION {
+            return Err(format!("Serving model version of this binary: {}, version of the file: {}", MMAP_MODEL_VERSION, version))?;
+        }
+        let (mi, vw, re) = load_regressor_without_weights(&mut input)?;
+        let num_weights = input.read_u64::<LittleEndian>()?;
+        let weights_offset = input.read_u64::<LittleEndian>()?;
+        let checksum = input.hasher.clone().finalize();
+        if input.inner.read_u32::<LittleEndian>()? != checksum {
+            return Err("header checksum mismatch")?;
+        }
+        Ok((mi, vw, re, num_weights, weights_offset))
+    })().map_err(|e| format!("Serving model file {} header is damaged: {}", filename, e))?;
 
+    let weights_bytes = num_weights * mem::size_of::<regressor::Weight>() as u64;
+    if weights_offset % MMAP_MODEL_PAGE_SIZE != 0 || file.metadata()?.len() != weights_offset + weights_bytes {
+        return Err(format!("Serving model file {} is truncated or damaged: expected {} bytes of weights at offset {}", filename, weights_bytes, weights_offset))?;
+    }
+    let mmap = unsafe {
+        memmap2::MmapOptions::new().offset(weights_offset).len(weights_bytes as usize).map(&file)?
+    };
+    let immutable_re = re.immutable_regressor_from_weights(regressor::ImmutableWeights::Mapped(mmap))?;
+    Ok((mi, vw, immutable_re))
+}
 
-fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+// Returns the version and flags of the regressor file
+fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<(u32, u32), Box<dyn Error>> {
     let mut magic_string: [u8; 4] = [0;4];
-    input_bufreader.read(&mut magic_string)?;
+    input_bufreader.read_exact(&mut magic_string)?;
+    if &magic_string == MMAP_MODEL_MAGIC_STRING {
+        return Err("This is a memory mapped serving model, it has no optimizer state and can only be used for predictions")?;
+    }
     if &magic_string != REGRESSOR_HEADER_MAGIC_STRING {
-        return Err("Cache header does not begin with magic bytes FWFW")?;
+        return Err("Regressor header does not begin with magic bytes FWRE")?;
     }
     
     let version = input_bufreader.read_u32::<LittleEndian>()?;
-    if REGRESSOR_HEADER_VERSION != version {
-        return Err(format!("Cache file version of this binary: {}, version of the cache file: {}", REGRESSOR_HEADER_VERSION, version))?;
+    if REGRESSOR_HEADER_VERSION != version && REGRESSOR_HEADER_VERSION_WITHOUT_FLAGS != version && REGRESSOR_HEADER_VERSION_WITHOUT_CHECKSUM != version {
+        return Err(format!("Regressor file version of this binary: {}, version of the regressor file: {}", REGRESSOR_HEADER_VERSION, version))?;
     }
-    Ok(())
+    let flags = match version {
+        REGRESSOR_HEADER_VERSION => input_bufreader.read_u32::<LittleEndian>()?,
+        _ => 0
+    };
+    Ok((version, flags))
 }        
 
 
@@ -143,6 +488,73 @@ mod tests {
     use regressor::Regressor;
 
     use tempfile::{tempdir};
+    #[test]
+    fn save_checkpoint() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.bit_precision = 18;
+        mi.examples_seen = 1234;
+        let rr = regressor::get_regressor(&mi);
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint_filepath = dir.path().join("test_regressor.checkpoint");
+        let checkpoint_filename = checkpoint_filepath.to_str().unwrap();
+        save_regressor_checkpoint(checkpoint_filename, &mi, &vw, &*rr).unwrap();
+        // Temporary file is renamed away
+        assert!(!dir.path().join("test_regressor.checkpoint.writing").exists());
+        let (mi2, _vw2, _re2) = new_regressor_from_filename(checkpoint_filename, false).unwrap();
+        assert_eq!(mi2.examples_seen, 1234);
+        // Overwriting an existing checkpoint
+        mi.examples_seen = 2345;
+        save_regressor_checkpoint(checkpoint_filename, &mi, &vw, &*rr).unwrap();
+        let (mi2, _vw2, _re2) = new_regressor_from_filename(checkpoint_filename, false).unwrap();
+        assert_eq!(mi2.examples_seen, 2345);
+    }
+
+    #[test]
+    fn damaged_regressor_file() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.bit_precision = 10;
+        let rr = regressor::get_regressor(&mi);
+        let dir = tempfile::tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        let regressor_filename = regressor_filepath.to_str().unwrap();
+        save_regressor_to_filename(regressor_filename, &mi, &vw, &*rr).unwrap();
+        assert!(!dir.path().join("test_regressor.fw.writing").exists());
+        let good = fs::read(regressor_filename).unwrap();
+        assert!(new_regressor_from_filename(regressor_filename, false).is_ok());
+        assert!(new_regressor_from_filename(regressor_filename, true).is_ok());
+
+        // Flipped bit in the weights
+        let mut damaged = good.clone();
+        let l = damaged.len();
+        damaged[l - 10] ^= 1;
+        fs::write(regressor_filename, &damaged).unwrap();
+        let e = new_regressor_from_filename(regressor_filename, false).err().unwrap();
+        assert!(e.to_string().contains("checksum mismatch"), "{}", e);
+        assert!(new_regressor_from_filename(regressor_filename, true).is_err());
+
+        // Truncated file
+        for cut in &[4, 20, 200, good.len() - 2] {
+            fs::write(regressor_filename, &good[..*cut]).unwrap();
+            assert!(new_regressor_from_filename(regressor_filename, false).is_err());
+            assert!(new_immutable_regressor_from_filename(regressor_filename).is_err());
+        }
+
+        // Missing file
+        let e = new_regressor_from_filename(dir.path().join("nothere.fw").to_str().unwrap(), false).err().unwrap();
+        assert!(e.to_string().contains("Cannot open regressor file"), "{}", e);
+    }
+
     #[test]
     fn save_empty_model() {
         let vw_map_string = r#"
@@ -159,7 +571,7 @@ B,featureB
         let rr = regressor::get_regressor(&mi);
         let dir = tempfile::tempdir().unwrap();
         let regressor_filepath = dir.path().join("test_regressor.fw");
-        save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, rr).unwrap();
+        save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, &*rr).unwrap();
     }    
 
     fn lr_vec(v:Vec<feature_buffer::HashAndValue>) -> feature_buffer::FeatureBuffer {
@@ -169,6 +581,8 @@ B,featureB
                     lr_buffer: v,
                     ffm_buffer: Vec::new(),
                     ffm_fields_count: 0,
+                    fm_buffer: Vec::new(),
+                    fwfm_buffer: Vec::new(),
         }
     }
 
@@ -206,7 +620,7 @@ B,featureB
         {
             let dir = tempdir().unwrap();
             let regressor_filepath = dir.path().join("test_regressor2.fw");
-            save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, re).unwrap();
+            save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, &*re).unwrap();
 
             // a) load as regular regressor
             let (_mi2, _vw2, mut re2) = new_regressor_from_filename(regressor_filepath.to_str().unwrap(), false).unwrap();
@@ -238,10 +652,86 @@ B,featureB
                     lr_buffer: Vec::new(),
                     ffm_buffer: v,
                     ffm_fields_count: ffm_fields_count,
+                    fm_buffer: Vec::new(),
+                    fwfm_buffer: Vec::new(),
         }
     }
 
 
+    #[test]
+    fn save_load_fwfm() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.bit_precision = 18;
+        mi.fwfm_k = 4;
+        mi.ffm_bit_precision = 12;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![],vec![]]; 
+        mi.optimizer = model_instance::Optimizer::Adagrad;
+        mi.fastmath = false;
+        let mut re = regressor::Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        let mut fbuf = ffm_vec(vec![], 2);
+        fbuf.fwfm_buffer = vec![HashAndValueAndSeq{hash:4, value: 1.0, contra_field_index: 0},
+                                HashAndValueAndSeq{hash:100, value: 2.0, contra_field_index: 1}];
+        fbuf.label = 1.0;
+        re.learn(&fbuf, true, 0);
+        let p = re.learn(&fbuf, false, 0);
+
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor_fwfm.fw");
+        save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, &re).unwrap();
+        let (_mi2, _vw2, mut re2) = new_regressor_from_filename(regressor_filepath.to_str().unwrap(), false).unwrap();
+        assert_eq!(re2.learn(&fbuf, false, 0), p);
+        let (_mi2, _vw2, re_fixed) = new_immutable_regressor_from_filename(regressor_filepath.to_str().unwrap()).unwrap();
+        assert_eq!(re_fixed.predict(&fbuf, 0), p);
+        // Pair weights and their optimizer state are saved too, so learning continues the same way
+        assert_eq!(re2.learn(&fbuf, true, 0), re.learn(&fbuf, true, 0));
+        assert_eq!(re2.learn(&fbuf, false, 0), re.learn(&fbuf, false, 0));
+    }
+
+    #[test]
+    fn save_load_mlp() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.bit_precision = 18;
+        mi.ffm_k = 2;
+        mi.ffm_bit_precision = 12;
+        mi.ffm_learning_rate = 0.1;
+        mi.mlp_learning_rate = 0.1;
+        mi.mlp_hidden = vec![8];
+        mi.ffm_fields = vec![vec![],vec![]]; 
+        mi.optimizer = model_instance::Optimizer::Adagrad;
+        mi.fastmath = false;
+        let mut re = regressor::Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        let mut fbuf = ffm_vec(vec![HashAndValueAndSeq{hash:4, value: 1.0, contra_field_index: 0},
+                                    HashAndValueAndSeq{hash:100, value: 2.0, contra_field_index: 2}], 2);
+        fbuf.label = 1.0;
+        re.learn(&fbuf, true, 0);
+        re.learn(&fbuf, true, 0);
+        let p = re.learn(&fbuf, false, 0);
+
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor_mlp.fw");
+        save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, &re).unwrap();
+        let (mi2, _vw2, mut re2) = new_regressor_from_filename(regressor_filepath.to_str().unwrap(), false).unwrap();
+        assert_eq!(mi2.mlp_hidden, vec![8]);
+        assert_eq!(re2.learn(&fbuf, false, 0), p);
+        let (_mi2, _vw2, re_fixed) = new_immutable_regressor_from_filename(regressor_filepath.to_str().unwrap()).unwrap();
+        assert_eq!(re_fixed.predict(&fbuf, 0), p);
+        assert_eq!(re2.learn(&fbuf, true, 0), re.learn(&fbuf, true, 0));
+        assert_eq!(re2.learn(&fbuf, false, 0), re.learn(&fbuf, false, 0));
+    }
+
     #[test]
     fn save_load_and_test_mode_ffm() {
         let vw_map_string = r#"
@@ -285,7 +775,7 @@ B,featureB
         {
             let dir = tempdir().unwrap();
             let regressor_filepath = dir.path().join("test_regressor2.fw");
-            save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, Box::new(re)).unwrap();
+            save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, &re).unwrap();
 
             // a) load as regular regressor
             let (_mi2, _vw2, mut re2) = new_regressor_from_filename(regressor_filepath.to_str().unwrap(), false).unwrap();
@@ -302,6 +792,81 @@ B,featureB
             assert_eq!(re_fixed.predict(fbuf, 0), CONST_RESULT);
         
         }
+
+        // Serving model
+        {
+            let dir = tempdir().unwrap();
+            let serving_filepath = dir.path().join("test_regressor.fws");
+            let serving_filename = serving_filepath.to_str().unwrap();
+            save_serving_model_to_filename(serving_filename, &mi, &vw, &re.immutable_regressor().unwrap()).unwrap();
+            let (_mi2, _vw2, re_fixed) = new_immutable_regressor_from_filename(serving_filename).unwrap();
+            assert_eq!(re_fixed.predict(fbuf, 0), CONST_RESULT);
+
+            // It cannot be trained further
+            let e = new_regressor_from_filename(serving_filename, false).err().unwrap();
+            assert!(e.to_string().contains("is a serving model"), "{}", e);
+
+            // Quantized weights
+            for quantization in &[quantization::WeightQuantization::F16, quantization::WeightQuantization::Int8] {
+                let re_quantized = re.immutable_regressor().unwrap().quantized(*quantization).unwrap();
+                save_serving_model_to_filename(serving_filename, &mi, &vw, &re_quantized).unwrap();
+                let (_mi2, _vw2, re_fixed) = new_immutable_regressor_from_filename(serving_filename).unwrap();
+                assert_eq!(re_fixed.weights.quantization(), *quantization);
+                assert_eq!(re_fixed.predict(fbuf, 0), re_quantized.predict(fbuf, 0));
+                assert!((re_fixed.predict(fbuf, 0) - CONST_RESULT).abs() < 0.01);
+                // Already quantized weights can't be memory mapped or quantized again
+                assert!(save_mmap_model_to_filename(dir.path().join("x").to_str().unwrap(), &mi, &vw, &re_fixed).is_err());
+                assert!(re_fixed.quantized(quantization::WeightQuantization::F16).is_err());
+            }
+
+            // Pruned weights
+            let re_pruned = re.immutable_regressor().unwrap().pruned(1.0).unwrap();
+            save_serving_model_to_filename(serving_filename, &mi, &vw, &re_pruned).unwrap();
+            let (_mi2, _vw2, re_fixed) = new_immutable_regressor_from_filename(serving_filename).unwrap();
+            assert_eq!(re_fixed.weights.len(), re.weights_len as usize);
+            assert!(re_fixed.weights.f32_weights().is_none());
+            assert_eq!(re_fixed.predict(fbuf, 0), re_pruned.predict(fbuf, 0));
+            assert!(fs::metadata(serving_filename).unwrap().len() < 10000);
+
+            // The serving model file checksums its weights too, unlike the memory mapped one below
+            let mut damaged = fs::read(serving_filename).unwrap();
+            let l = damaged.len();
+            damaged[l / 2] ^= 1;
+            fs::write(serving_filename, &damaged).unwrap();
+            assert!(new_immutable_regressor_from_filename(serving_filename).is_err());
+        }
+
+        // Memory mapped serving model
+        {
+            let dir = tempdir().unwrap();
+            let mmap_filepath = dir.path().join("test_regressor.fwmm");
+            let mmap_filename = mmap_filepath.to_str().unwrap();
+            save_mmap_model_to_filename(mmap_filename, &mi, &vw, &re.immutable_regressor().unwrap()).unwrap();
+            // Weights start at a page boundary
+            assert_eq!((fs::metadata(mmap_filename).unwrap().len() - re.weights_len as u64 * 4) % MMAP_MODEL_PAGE_SIZE, 0);
+
+            let (_mi2, _vw2, re_fixed) = new_immutable_regressor_from_filename(mmap_filename).unwrap();
+            assert_eq!(re_fixed.predict(fbuf, 0), CONST_RESULT);
+            let (_mi2, _vw2, mut re2) = new_regressor_from_filename(mmap_filename, true).unwrap();
+            assert_eq!(re2.learn(fbuf, false, 0), CONST_RESULT);
+
+            // It cannot be trained further
+            let e = new_regressor_from_filename(mmap_filename, false).err().unwrap();
+            assert!(e.to_string().contains("memory mapped serving model"), "{}", e);
+
+            // Damaged header and truncated weights are detected
+            let good = fs::read(mmap_filename).unwrap();
+            let mut damaged = good.clone();
+            damaged[good.len() / 2] ^= 1;   // weights are not checksummed
+            fs::write(mmap_filename, &damaged).unwrap();
+            assert!(new_immutable_regressor_from_filename(mmap_filename).is_ok());
+            let mut damaged = good.clone();
+            damaged[200] ^= 1;
+            fs::write(mmap_filename, &damaged).unwrap();
+            assert!(new_immutable_regressor_from_filename(mmap_filename).is_err());
+            fs::write(mmap_filename, &good[..good.len() - 4]).unwrap();
+            assert!(new_immutable_regressor_from_filename(mmap_filename).is_err());
+        }
         
 
     }    
@@ -0,0 +1,169 @@
+use std::error::Error;
+use std::ops;
+use half::f16;
+
+use crate::regressor::Weight;
+
+/*
+Quantized and pruned weights of serving models. Predictions dequantize weights on the fly.
+ - f16: every weight is stored as a half precision float
+ - int8: weights are split into blocks of INT8_BLOCK_LEN, each block has its own scale (largest absolute
+   weight / 127) and weights are stored as rounded multiples of it
+ - sparse: weights with absolute value up to the prune threshold are dropped, the rest are kept in a table
+   sorted by index, that is binary searched. Dropped weights are 0.0
+*/
+
+pub const INT8_BLOCK_LEN:usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WeightQuantization {
+    F32,
+    F16,
+    Int8,
+}
+
+impl WeightQuantization {
+    pub fn from_str(s: &str) -> Result<WeightQuantization, Box<dyn Error>> {
+        match s {
+            "f32" | "none" => Ok(WeightQuantization::F32),
+            "f16" => Ok(WeightQuantization::F16),
+            "int8" => Ok(WeightQuantization::Int8),
+            _ => Err(format!("Unknown weight quantization {}, use f32, f16 or int8", s))?
+        }
+    }
+}
+
+pub struct Int8Weights {
+    pub scales: Vec<f32>,
+    pub values: Vec<i8>,
+}
+
+pub struct SparseWeights {
+    pub len: usize,              // number of weights before pruning
+    pub indices: Vec<u32>,       // sorted
+    pub weights: Vec<f32>,
+}
+
+// Weight lookup used by the prediction, implemented for every way of storing weights
+pub trait WeightLookup {
+    unsafe fn weight(&self, i: usize) -> f32;
+}
+
+impl WeightLookup for [Weight] {
+    #[inline(always)]
+    unsafe fn weight(&self, i: usize) -> f32 {
+        self.get_unchecked(i).weight
+    }
+}
+
+impl WeightLookup for [f16] {
+    #[inline(always)]
+    unsafe fn weight(&self, i: usize) -> f32 {
+        self.get_unchecked(i).to_f32()
+    }
+}
+
+impl WeightLookup for Int8Weights {
+    #[inline(always)]
+    unsafe fn weight(&self, i: usize) -> f32 {
+        *self.values.get_unchecked(i) as f32 * *self.scales.get_unchecked(i / INT8_BLOCK_LEN)
+    }
+}
+
+impl WeightLookup for SparseWeights {
+    #[inline(always)]
+    unsafe fn weight(&self, i: usize) -> f32 {
+        match self.indices.binary_search(&(i as u32)) {
+            Ok(position) => *self.weights.get_unchecked(position),
+            Err(_) => 0.0
+        }
+    }
+}
+
+pub fn quantize_f16(weights: &[Weight]) -> Vec<f16> {
+    weights.iter().map(|w| f16::from_f32(w.weight)).collect()
+}
+
+pub fn quantize_int8(weights: &[Weight]) -> Int8Weights {
+    let mut scales: Vec<f32> = Vec::with_capacity(weights.len().div_ceil(INT8_BLOCK_LEN));
+    let mut values: Vec<i8> = Vec::with_capacity(weights.len());
+    for block in weights.chunks(INT8_BLOCK_LEN) {
+        let max_abs = block.iter().fold(0.0f32, |max_abs, w| max_abs.max(w.weight.abs()));
+        let scale = if max_abs > 0.0 { max_abs / 127.0 } else { 1.0 };
+        scales.push(scale);
+        values.extend(block.iter().map(|w| (w.weight / scale).round().clamp(-127.0, 127.0) as i8));
+    }
+    Int8Weights {
+        scales: scales,
+        values: values,
+    }
+}
+
+// Weights in the keep range are kept whatever their value
+pub fn prune(weights: &[Weight], threshold: f32, keep: ops::Range<usize>) -> SparseWeights {
+    let mut indices: Vec<u32> = Vec::new();
+    let mut kept_weights: Vec<f32> = Vec::new();
+    for (i, w) in weights.iter().enumerate() {
+        if w.weight.abs() > threshold || keep.contains(&i) {
+            indices.push(i as u32);
+            kept_weights.push(w.weight);
+        }
+    }
+    SparseWeights {
+        len: weights.len(),
+        indices: indices,
+        weights: kept_weights,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_quantization() {
+        assert_eq!(WeightQuantization::from_str("f16").unwrap(), WeightQuantization::F16);
+        assert_eq!(WeightQuantization::from_str("int8").unwrap(), WeightQuantization::Int8);
+        assert_eq!(WeightQuantization::from_str("f32").unwrap(), WeightQuantization::F32);
+        assert!(WeightQuantization::from_str("int4").is_err());
+
+        let weights: Vec<Weight> = (0..1000).map(|i| Weight{weight: ((i as f32) * 0.37).sin() * (1 + i / INT8_BLOCK_LEN) as f32}).collect();
+        let f16_weights = quantize_f16(&weights);
+        let int8_weights = quantize_int8(&weights);
+        assert_eq!(int8_weights.values.len(), 1000);
+        assert_eq!(int8_weights.scales.len(), 4);
+        unsafe {
+            for i in 0..weights.len() {
+                let w = weights.weight(i);
+                assert!((f16_weights.weight(i) - w).abs() <= w.abs() / 1000.0, "{} {}", i, w);
+                // Error is at most half of the block's step
+                let step = int8_weights.scales[i / INT8_BLOCK_LEN];
+                assert!((int8_weights.weight(i) - w).abs() <= step / 2.0 + 1e-6, "{} {}", i, w);
+            }
+        }
+
+        // Pruning
+        let sparse_weights = prune(&weights, 0.5, 0..0);
+        assert_eq!(sparse_weights.len, 1000);
+        assert!(sparse_weights.indices.len() > 0 && sparse_weights.indices.len() < 1000);
+        unsafe {
+            for i in 0..weights.len() {
+                let w = weights.weight(i);
+                if w.abs() > 0.5 {
+                    assert_eq!(sparse_weights.weight(i), w);
+                } else {
+                    assert_eq!(sparse_weights.weight(i), 0.0);
+                }
+            }
+        }
+
+        // Block of zeros
+        let zeros = vec![Weight{weight: 0.0}; 10];
+        let int8_weights = quantize_int8(&zeros);
+        unsafe {
+            assert_eq!(int8_weights.weight(5), 0.0);
+        }
+    }
+}
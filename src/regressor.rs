@@ -4,26 +4,33 @@ use std::mem::{self, MaybeUninit};
 use std::slice;
 //use fastapprox::fast::sigmoid; // surprisingly this doesn't work very well
 use std::sync::Arc;
+use std::cell::RefCell;
+use std::ops;
 use core::arch::x86_64::*;
 use merand48::*;
 use std::io;
 use std::error::Error;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::cmp::min;
+use half::f16;
 
 use crate::model_instance;
 use crate::feature_buffer;
 use crate::feature_buffer::HashAndValue;
 use crate::feature_buffer::HashAndValueAndSeq;
 use crate::optimizer;
+use crate::quantization;
+use crate::mlp;
+use crate::multiclass;
 use optimizer::OptimizerTrait;
+use quantization::WeightLookup;
 
 
 const LR_STACK_BUF_LEN:usize= 256;
 const FFM_STACK_BUF_LEN:usize= 16384;
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Copy)]
 #[repr(C)]
 pub struct Weight {
     pub weight: f32, 
@@ -41,6 +48,13 @@ pub struct WeightAndOptimizerData<L:OptimizerTrait> {
     pub optimizer_data: L::PerWeightStore,
 }
 
+impl <L:OptimizerTrait> WeightLookup for [WeightAndOptimizerData<L>] {
+    #[inline(always)]
+    unsafe fn weight(&self, i: usize) -> f32 {
+        self.get_unchecked(i).weight
+    }
+}
+
 pub struct Regressor<L:OptimizerTrait> {
     pub weights: Vec<WeightAndOptimizerData<L>>,       // all weights and gradients (has sub-spaces)
     pub weights_len: u32,
@@ -50,17 +64,110 @@ pub struct Regressor<L:OptimizerTrait> {
     ffm_one_over_k_root: f32,
     ffm_iw_weights_offset: u32,
     ffm_k_threshold: f32,
+    ffm_k_state_offset: u32,
+    ffm_no_intra_field: bool,
+    pub fm_weights_offset: u32,
+    pub fm_weights_len: u32,
+    fm_k: u32,
+    pub fwfm_weights_offset: u32,
+    pub fwfm_weights_len: u32,
+    pub fwfm_pair_weights_offset: u32,
+    fwfm_k: u32,
+    fwfm_fields: u32,
+    pub mlp_layout: Option<mlp::MlpLayout>,
     optimizer_lr: L,
     pub optimizer_ffm: L,
+    pub optimizer_fm: L,
+    pub optimizer_mlp: L,
     local_data_ffm_indices: Vec<u32>,
     local_data_ffm_values: Vec<f32>,
+    fm_sums: Vec<f32>,
+    fwfm_local_values: Vec<f32>,
+    fwfm_pair_gradients: Vec<f32>,
+    mlp_buffers: mlp::MlpBuffers,
+}
+
+// Weights of the immutable regressor either live on the heap, are memory mapped from a serving model file or are quantized
+pub enum ImmutableWeights {
+    Owned(Vec<Weight>),
+    Mapped(memmap2::Mmap),
+    F16(Vec<f16>),
+    Int8(quantization::Int8Weights),
+    Sparse(quantization::SparseWeights),
+}
+
+impl ImmutableWeights {
+    pub fn len(&self) -> usize {
+        match self {
+            ImmutableWeights::F16(weights) => weights.len(),
+            ImmutableWeights::Int8(weights) => weights.values.len(),
+            ImmutableWeights::Sparse(weights) => weights.len,
+            _ => self.f32_weights().unwrap().len(),
+        }
+    }
+
+    // Full precision weights, None for quantized or pruned weights
+    pub fn f32_weights(&self) -> Option<&[Weight]> {
+        match self {
+            ImmutableWeights::Owned(weights) => Some(weights),
+            ImmutableWeights::Mapped(mmap) => unsafe {
+                // Mapping starts at a page boundary and Weight is a repr(C) f32, so the view is aligned
+                Some(slice::from_raw_parts(mmap.as_ptr() as *const Weight, mmap.len() / mem::size_of::<Weight>()))
+            },
+            _ => None
+        }
+    }
+
+    pub fn quantization(&self) -> quantization::WeightQuantization {
+        match self {
+            ImmutableWeights::F16(_) => quantization::WeightQuantization::F16,
+            ImmutableWeights::Int8(_) => quantization::WeightQuantization::Int8,
+            _ => quantization::WeightQuantization::F32,
+        }
+    }
+
+    pub fn quantize(&self, quantization: quantization::WeightQuantization) -> Result<ImmutableWeights, Box<dyn Error>> {
+        let weights = self.f32_weights().ok_or("Weights are already quantized or pruned")?;
+        Ok(match quantization {
+            quantization::WeightQuantization::F32 => ImmutableWeights::Owned(weights.to_vec()),
+            quantization::WeightQuantization::F16 => ImmutableWeights::F16(quantization::quantize_f16(weights)),
+            quantization::WeightQuantization::Int8 => ImmutableWeights::Int8(quantization::quantize_int8(weights)),
+        })
+    }
+
+    pub fn prune(&self, threshold: f32, keep: ops::Range<usize>) -> Result<ImmutableWeights, Box<dyn Error>> {
+        let weights = self.f32_weights().ok_or("Weights are already quantized or pruned")?;
+        Ok(ImmutableWeights::Sparse(quantization::prune(weights, threshold, keep)))
+    }
+}
+
+thread_local! {
+    static MLP_BUFFER: RefCell<Vec<f32>> = RefCell::new(Vec::new());
 }
 
 #[derive(Clone)]
 pub struct ImmutableRegressor {
-    pub weights: Arc<Vec<Weight>>,
+    pub weights: Arc<ImmutableWeights>,
     ffm_weights_offset: u32, 
     ffm_k: u32,
+    ffm_k_threshold: f32,
+    ffm_k_state_offset: u32,
+    ffm_no_intra_field: bool,
+    fm_weights_offset: u32,
+    fm_k: u32,
+    fwfm_weights_offset: u32,
+    fwfm_pair_weights_offset: u32,
+    fwfm_k: u32,
+    fwfm_fields: u32,
+    mlp_layout: Option<mlp::MlpLayout>,
+}
+
+// Adaptive k: every FFM vector starts with one active dimension and unlocks another one each time 
+// its accumulated absolute gradient grows by ffm_k_threshold. Accumulated gradients are kept in
+// their own weights region, one per vector: at the vector's address in the ffm weights divided by ffm_k.
+#[inline(always)]
+fn ffm_active_k(accumulated_gradient: f32, ffm_k_threshold: f32, ffm_k: u32) -> u32 {
+    min(ffm_k, 1 + (accumulated_gradient / ffm_k_threshold) as u32)
 }
 
 
@@ -77,6 +184,20 @@ macro_rules! specialize_k {
     };
 }
 
+macro_rules! specialize_bool {
+    ( $input_expr:expr, 
+      $output_const:ident,
+      $code_block:block  ) => {
+          if $input_expr {
+              const $output_const:bool = true; 
+              $code_block
+          } else {
+              const $output_const:bool = false; 
+              $code_block
+          }
+      };
+}
+
 macro_rules! specialize_1f32 {
     ( $input_expr:expr, 
       $output_const:ident,
@@ -99,6 +220,7 @@ pub trait RegressorTrait {
     fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance);
     fn immutable_regressor_from_buf(&mut self, input_bufreader: &mut dyn io::Read) -> Result<ImmutableRegressor, Box<dyn Error>>; 
     fn immutable_regressor(&mut self) -> Result<ImmutableRegressor, Box<dyn Error>>;
+    fn immutable_regressor_from_weights(&mut self, weights: ImmutableWeights) -> Result<ImmutableRegressor, Box<dyn Error>>;
 }
 
 
@@ -126,7 +248,9 @@ where <L as optimizer::OptimizerTrait>::PerWeightStore: std::clone::Clone,
 L: std::clone::Clone
 {
     pub fn new_without_weights(mi: &model_instance::ModelInstance) -> Regressor<L> {
-        let lr_weights_len = 1 << mi.bit_precision;
+        // With --oaa classes are strided within each hash space
+        let class_bits = multiclass::class_bits(mi.oaa);
+        let lr_weights_len = 1 << (mi.bit_precision as u32 + class_bits);
         let mut rg = Regressor::<L>{
                             //minimum_optimizer: mi.minimum_optimizer,
                             weights: Vec::new(),
@@ -137,26 +261,82 @@ L: std::clone::Clone
                             ffm_one_over_k_root: 0.0, 
                             optimizer_lr: L::new(),
                             optimizer_ffm: L::new(),
+                            optimizer_fm: L::new(),
                             ffm_iw_weights_offset: 0, ffm_k_threshold:
                             mi.ffm_k_threshold, 
+                            ffm_k_state_offset: 0,
+                            ffm_no_intra_field: mi.ffm_no_intra_field,
+                            fm_weights_offset: 0,
+                            fm_weights_len: 0,
+                            fm_k: 0,
+                            fwfm_weights_offset: 0,
+                            fwfm_weights_len: 0,
+                            fwfm_pair_weights_offset: 0,
+                            fwfm_k: 0,
+                            fwfm_fields: 0,
+                            mlp_layout: None,
+                            optimizer_mlp: L::new(),
                             local_data_ffm_indices: Vec::with_capacity(1024),
                             local_data_ffm_values: Vec::with_capacity(1024),
+                            fm_sums: Vec::new(),
+                            fwfm_local_values: Vec::new(),
+                            fwfm_pair_gradients: Vec::new(),
+                            mlp_buffers: mlp::MlpBuffers {
+                                inputs: Vec::new(),
+                                activations: Vec::new(),
+                                deltas: Vec::new(),
+                                input_gradients: Vec::new(),
+                            },
                      };
 
         rg.optimizer_lr.init(mi.learning_rate, mi.power_t, mi.init_acc_gradient);
         rg.optimizer_ffm.init(mi.ffm_learning_rate, mi.ffm_power_t, mi.ffm_init_acc_gradient);
+        rg.optimizer_fm.init(mi.fm_learning_rate, mi.fm_power_t, mi.init_acc_gradient);
+        rg.optimizer_mlp.init(mi.mlp_learning_rate, mi.power_t, mi.init_acc_gradient);
 
         if mi.ffm_k > 0 {
             
             rg.ffm_weights_offset = lr_weights_len;            // Since we will align our dimensions, we need to know the number of bits for them
             rg.ffm_k = mi.ffm_k;
             // At the end we add "spillover buffer", so we can do modulo only on the base address and add offset
-            rg.ffm_weights_len = (1 << mi.ffm_bit_precision) + (mi.ffm_fields.len() as u32 * rg.ffm_k);
+            // With --oaa classes of a feature are ffm_class_stride apart, so last feature's classes spill over further
+            let class_spillover_len = if class_bits > 0 {((1 << class_bits) - 1) * multiclass::ffm_class_stride(mi)} else {0};
+            rg.ffm_weights_len = (1 << (mi.ffm_bit_precision + class_bits)) + (mi.ffm_fields.len() as u32 * rg.ffm_k) + class_spillover_len;
         }
         // Now allocate weights
         let iw_weights_len = 0;
         rg.ffm_iw_weights_offset = lr_weights_len + rg.ffm_weights_len;        
-        rg.weights_len = lr_weights_len + rg.ffm_weights_len + iw_weights_len;
+        // Adaptive k state is aligned to int8 quantization blocks, so exported models quantize it at its own scale
+        let int8_aligned = |len: u32| (len + quantization::INT8_BLOCK_LEN as u32 - 1) / quantization::INT8_BLOCK_LEN as u32 * quantization::INT8_BLOCK_LEN as u32;
+        let ffm_k_state_len = if rg.ffm_k_threshold > 0.0 {int8_aligned((rg.ffm_weights_len + rg.ffm_k - 1) / rg.ffm_k)} else {0};
+        rg.ffm_k_state_offset = if rg.ffm_k_threshold > 0.0 {int8_aligned(rg.ffm_iw_weights_offset + iw_weights_len)} else {rg.ffm_iw_weights_offset + iw_weights_len};
+        if mi.fm_k > 0 {
+            rg.fm_k = mi.fm_k;
+            // Hashes are aligned to the dimensions, so no spillover buffer is needed
+            rg.fm_weights_len = 1 << (mi.fm_bit_precision + class_bits);
+            rg.fm_sums = vec![0.0; rg.fm_k as usize];
+        }
+        rg.fm_weights_offset = rg.ffm_k_state_offset + ffm_k_state_len;
+        // FwFM latent vectors use ffm hash space, followed by a weight for every pair of fields
+        let mut fwfm_pair_weights_len = 0;
+        if mi.fwfm_k > 0 {
+            rg.fwfm_k = mi.fwfm_k;
+            rg.fwfm_fields = mi.ffm_fields.len() as u32;
+            rg.fwfm_weights_len = 1 << mi.ffm_bit_precision;
+            fwfm_pair_weights_len = rg.fwfm_fields * rg.fwfm_fields;
+            rg.fwfm_pair_gradients = vec![0.0; fwfm_pair_weights_len as usize];
+        }
+        rg.fwfm_weights_offset = rg.fm_weights_offset + rg.fm_weights_len;
+        rg.fwfm_pair_weights_offset = rg.fwfm_weights_offset + rg.fwfm_weights_len;
+        rg.weights_len = rg.fwfm_pair_weights_offset + fwfm_pair_weights_len;
+        // Neural network head goes last
+        if !mi.mlp_hidden.is_empty() {
+            let fields_count = if mi.ffm_k > 0 {mi.ffm_fields.len()} else {0};
+            let layout = mlp::MlpLayout::new(mlp::inputs_len(fields_count), &mi.mlp_hidden, rg.weights_len as usize);
+            rg.weights_len += layout.weights_len() as u32;
+            rg.mlp_buffers = mlp::MlpBuffers::new(&layout);
+            rg.mlp_layout = Some(layout);
+        }
         rg
     }
     
@@ -189,6 +369,32 @@ L: std::clone::Clone
 
             }
         }
+
+        if mi.fm_k > 0 {
+            // Same as default ffm initialization
+            let fm_one_over_k_root = 1.0 / (rg.fm_k as f32).sqrt() / 50.0;
+            for i in 0..rg.fm_weights_len {
+                rg.weights[(rg.fm_weights_offset + i) as usize].weight = (1.0 * merand48((rg.fm_weights_offset+i) as u64)-0.5) * fm_one_over_k_root;
+                rg.weights[(rg.fm_weights_offset + i) as usize].optimizer_data = rg.optimizer_fm.initial_data();
+            }
+        }
+
+        if mi.fwfm_k > 0 {
+            // Latent vectors same as default ffm initialization, field pairs start with weight 1.0 (plain FM)
+            let fwfm_one_over_k_root = 1.0 / (rg.fwfm_k as f32).sqrt() / 50.0;
+            for i in 0..rg.fwfm_weights_len {
+                rg.weights[(rg.fwfm_weights_offset + i) as usize].weight = (1.0 * merand48((rg.fwfm_weights_offset+i) as u64)-0.5) * fwfm_one_over_k_root;
+                rg.weights[(rg.fwfm_weights_offset + i) as usize].optimizer_data = rg.optimizer_ffm.initial_data();
+            }
+            for i in 0..rg.fwfm_fields * rg.fwfm_fields {
+                rg.weights[(rg.fwfm_pair_weights_offset + i) as usize].weight = 1.0;
+                rg.weights[(rg.fwfm_pair_weights_offset + i) as usize].optimizer_data = rg.optimizer_ffm.initial_data();
+            }
+        }
+
+        if let Some(layout) = rg.mlp_layout {
+            mlp::init_weights(&mut rg.weights, &layout, &rg.optimizer_mlp);
+        }
     }
 
     pub fn new(mi: &model_instance::ModelInstance) -> Regressor<L> {
@@ -259,6 +465,7 @@ L: std::clone::Clone
                         wsum += feature_weight * feature_value;
                     }
                 }
+                let lr_wsum = wsum;
                 if self.ffm_k > 0 {
                     let fc = (fb.ffm_fields_count  * self.ffm_k) as usize;
                     let mut ifc:usize = 0;
@@ -273,6 +480,67 @@ L: std::clone::Clone
                        ifc += fc;
                     }
 
+                    if self.mlp_layout.is_some() {
+                    // With neural network head interactions are summed by pairs of fields, as its inputs
+                    // Derivatives depend on the head, so they are calculated only at the update
+                    let ffm_fields_count = fb.ffm_fields_count as usize;
+                    let mlp_inputs = &mut self.mlp_buffers.inputs;
+                    for x in mlp_inputs.iter_mut() {
+                        *x = 0.0;
+                    }
+                    for (i, left_hash) in fb.ffm_buffer.iter().enumerate() {
+                        let left_field = (left_hash.contra_field_index / self.ffm_k) as usize;
+                        for right_hash in fb.ffm_buffer.get_unchecked(i+1 ..).iter() {
+                            if self.ffm_no_intra_field && left_hash.contra_field_index == right_hash.contra_field_index {
+                                continue	// not combining within a field
+                            }
+                            let right_field = (right_hash.contra_field_index / self.ffm_k) as usize;
+                            let lindex = (left_hash.hash + right_hash.contra_field_index) as usize;
+                            let rindex = (right_hash.hash + left_hash.contra_field_index) as usize;
+                            let mut contribution:f32 = 0.0;
+                            for k in 0..self.ffm_k as usize {
+                                contribution += ffm_weights.get_unchecked(lindex + k).weight * ffm_weights.get_unchecked(rindex + k).weight;
+                            }
+                            contribution *= left_hash.value * right_hash.value;
+                            *mlp_inputs.get_unchecked_mut(mlp::field_pair_input(min(left_field, right_field), std::cmp::max(left_field, right_field), ffm_fields_count)) += contribution;
+                            wsum += contribution;
+                        }
+                    }
+                    } else {
+                    specialize_bool!(self.ffm_no_intra_field, NO_INTRA_FIELD, {
+                    if self.ffm_k_threshold > 0.0 {
+                    // Adaptive k: a pair interacts only over the dimensions both of its vectors have unlocked
+                    let k_state_index = (self.ffm_k_state_offset - self.ffm_weights_offset) as usize;
+                    let ffm_k = self.ffm_k as usize;
+                    let mut ifc:usize = 0;
+                    for (i, left_hash) in fb.ffm_buffer.iter().enumerate() {
+                        let mut right_local_index = left_hash.contra_field_index as usize + ifc;
+                        for right_hash in fb.ffm_buffer.get_unchecked(i+1 ..).iter() {
+                            right_local_index += fc;
+                            if NO_INTRA_FIELD && left_hash.contra_field_index == right_hash.contra_field_index {
+                                continue	// not combining within a field
+                            }
+                            let left_local_index = ifc + right_hash.contra_field_index as usize;
+                            let joint_value = left_hash.value * right_hash.value;
+                            let lindex = *local_data_ffm_indices.get_unchecked(left_local_index) as usize;
+                            let rindex = *local_data_ffm_indices.get_unchecked(right_local_index) as usize;
+                            let left_k = ffm_active_k(ffm_weights.get_unchecked(k_state_index + lindex / ffm_k).weight, self.ffm_k_threshold, self.ffm_k);
+                            let right_k = ffm_active_k(ffm_weights.get_unchecked(k_state_index + rindex / ffm_k).weight, self.ffm_k_threshold, self.ffm_k);
+                            for k in 0..min(left_k, right_k) as usize {
+                                let llik = left_local_index + k;
+                                let rlik = right_local_index + k;
+                                let left_hash_weight  = ffm_weights.get_unchecked(lindex+k).weight;
+                                let right_hash_weight = ffm_weights.get_unchecked(rindex+k).weight;
+                                
+                                let right_side = right_hash_weight * joint_value;
+                                *local_data_ffm_values.get_unchecked_mut(llik) += right_side; // first derivate
+                                *local_data_ffm_values.get_unchecked_mut(rlik) += left_hash_weight  * joint_value; // first derivate
+                                wsum += left_hash_weight * right_side;
+                            }
+                        }
+                        ifc += fc;
+                    }
+                    } else {
                     specialize_k!(self.ffm_k, FFMK, {
                     let mut ifc:usize = 0;
                     for (i, left_hash) in fb.ffm_buffer.iter().enumerate() {
@@ -283,9 +551,10 @@ L: std::clone::Clone
                              
                             // Regular FFM implementation would prevent intra-field interactions
                             // But for the use case we tested this is both faster and it decreases logloss
-                            /*if left_hash.contra_field_index == right_hash.contra_field_index {
+                            // so it is only done with --ffm_no_intra_field
+                            if NO_INTRA_FIELD && left_hash.contra_field_index == right_hash.contra_field_index {
                                 continue	// not combining within a field
-                            }*/
+                            }
                             
                             // FYI this is effectively what we calculate:
         //                    let left_local_index =  i*fc + right_hash.contra_field_index as usize;
@@ -316,6 +585,77 @@ L: std::clone::Clone
                     }
                 
                     });
+                    }
+                    });
+                    }
+                }
+                if self.fm_k > 0 {
+                    // Sum of all pairwise interactions is half of (square of sum - sum of squares), for each dimension
+                    // fm weights are in the same slice as ffm weights
+                    let fm_index = (self.fm_weights_offset - self.ffm_weights_offset) as usize;
+                    let fm_sums = &mut self.fm_sums;
+                    for k in 0..self.fm_k as usize {
+                        *fm_sums.get_unchecked_mut(k) = 0.0;
+                    }
+                    let mut squares_sum:f32 = 0.0;
+                    for hashvalue in fb.fm_buffer.iter() {
+                        let feature_index = fm_index + hashvalue.hash as usize;
+                        for k in 0..self.fm_k as usize {
+                            let wx = ffm_weights.get_unchecked(feature_index + k).weight * hashvalue.value;
+                            *fm_sums.get_unchecked_mut(k) += wx;
+                            squares_sum += wx * wx;
+                        }
+                    }
+                    let mut sums_squared:f32 = 0.0;
+                    for k in 0..self.fm_k as usize {
+                        sums_squared += *fm_sums.get_unchecked(k) * *fm_sums.get_unchecked(k);
+                    }
+                    wsum += 0.5 * (sums_squared - squares_sum);
+                }
+                if self.fwfm_k > 0 {
+                    // Like ffm, but with one vector per feature and interactions scaled by the weight of the field pair
+                    let fwfm_index = (self.fwfm_weights_offset - self.ffm_weights_offset) as usize;
+                    let pair_index = (self.fwfm_pair_weights_offset - self.ffm_weights_offset) as usize;
+                    let fwfm_k = self.fwfm_k as usize;
+                    let fwfm_fields = self.fwfm_fields as usize;
+                    let fwfm_local_values = &mut self.fwfm_local_values;
+                    let fwfm_pair_gradients = &mut self.fwfm_pair_gradients;
+                    fwfm_local_values.truncate(0);
+                    fwfm_local_values.resize(fb.fwfm_buffer.len() * fwfm_k, 0.0);
+                    for g in fwfm_pair_gradients.iter_mut() {
+                        *g = 0.0;
+                    }
+                    for (i, left_hash) in fb.fwfm_buffer.iter().enumerate() {
+                        let lindex = fwfm_index + left_hash.hash as usize;
+                        for (j, right_hash) in fb.fwfm_buffer.get_unchecked(i+1 ..).iter().enumerate() {
+                            if self.ffm_no_intra_field && left_hash.contra_field_index == right_hash.contra_field_index {
+                                continue	// not combining within a field
+                            }
+                            let rindex = fwfm_index + right_hash.hash as usize;
+                            let j = i + 1 + j;
+                            let pair = (min(left_hash.contra_field_index, right_hash.contra_field_index) as usize) * fwfm_fields
+                                        + std::cmp::max(left_hash.contra_field_index, right_hash.contra_field_index) as usize;
+                            let pair_weight = ffm_weights.get_unchecked(pair_index + pair).weight;
+                            let joint_value = left_hash.value * right_hash.value;
+                            let mut dot:f32 = 0.0;
+                            for k in 0..fwfm_k {
+                                let left_hash_weight  = ffm_weights.get_unchecked(lindex + k).weight;
+                                let right_hash_weight = ffm_weights.get_unchecked(rindex + k).weight;
+                                dot += left_hash_weight * right_hash_weight;
+                                *fwfm_local_values.get_unchecked_mut(i * fwfm_k + k) += pair_weight * right_hash_weight * joint_value; // first derivate
+                                *fwfm_local_values.get_unchecked_mut(j * fwfm_k + k) += pair_weight * left_hash_weight * joint_value; // first derivate
+                            }
+                            *fwfm_pair_gradients.get_unchecked_mut(pair) += dot * joint_value;
+                            wsum += pair_weight * dot * joint_value;
+                        }
+                    }
+                }
+                if let Some(layout) = self.mlp_layout {
+                    // Head's output is added to the plain sum of its inputs
+                    let mut layout = layout;
+                    layout.offset -= self.ffm_weights_offset as usize;
+                    *self.mlp_buffers.inputs.get_unchecked_mut(0) = lr_wsum;
+                    wsum += mlp::forward(&ffm_weights[..], &layout, &self.mlp_buffers.inputs, &mut self.mlp_buffers.activations);
                 }
                 // Trick: instead of multiply in the updates with learning rate, multiply the result
                 // vowpal compatibility
@@ -336,6 +676,40 @@ L: std::clone::Clone
                 if update && fb.example_importance != 0.0 {
                     let general_gradient = (y - prediction_probability) * fb.example_importance;
         //            println!("General gradient: {}", general_gradient);
+                    let mut lr_general_gradient = general_gradient;
+                    if let Some(layout) = self.mlp_layout {
+                        let mut layout = layout;
+                        layout.offset -= self.ffm_weights_offset as usize;
+                        mlp::backward_and_update(ffm_weights, &self.optimizer_mlp, &layout, &mut self.mlp_buffers, general_gradient);
+                        // Every input reaches the output directly and through the head
+                        lr_general_gradient *= 1.0 + *self.mlp_buffers.input_gradients.get_unchecked(0);
+                        if self.ffm_k > 0 {
+                            let fc = (fb.ffm_fields_count  * self.ffm_k) as usize;
+                            let ffm_fields_count = fb.ffm_fields_count as usize;
+                            let mut ifc:usize = 0;
+                            for (i, left_hash) in fb.ffm_buffer.iter().enumerate() {
+                                let left_field = (left_hash.contra_field_index / self.ffm_k) as usize;
+                                let mut right_local_index = left_hash.contra_field_index as usize + ifc;
+                                for right_hash in fb.ffm_buffer.get_unchecked(i+1 ..).iter() {
+                                    right_local_index += fc;
+                                    if self.ffm_no_intra_field && left_hash.contra_field_index == right_hash.contra_field_index {
+                                        continue	// not combining within a field
+                                    }
+                                    let right_field = (right_hash.contra_field_index / self.ffm_k) as usize;
+                                    let input = mlp::field_pair_input(min(left_field, right_field), std::cmp::max(left_field, right_field), ffm_fields_count);
+                                    let joint_value = left_hash.value * right_hash.value * (1.0 + *self.mlp_buffers.input_gradients.get_unchecked(input));
+                                    let left_local_index = ifc + right_hash.contra_field_index as usize;
+                                    let lindex = *local_data_ffm_indices.get_unchecked(left_local_index) as usize;
+                                    let rindex = *local_data_ffm_indices.get_unchecked(right_local_index) as usize;
+                                    for k in 0..self.ffm_k as usize {
+                                        *local_data_ffm_values.get_unchecked_mut(left_local_index + k) += ffm_weights.get_unchecked(rindex + k).weight * joint_value; // first derivate
+                                        *local_data_ffm_values.get_unchecked_mut(right_local_index + k) += ffm_weights.get_unchecked(lindex + k).weight * joint_value; // first derivate
+                                    }
+                                }
+                                ifc += fc;
+                            }
+                        }
+                    }
 
                     for hashvalue in fb.lr_buffer.iter() {
         //A                _mm_prefetch(mem::transmute::<&f32, &i8>(&weights.get_unchecked((local_data_lr.get_unchecked(i+8)).index as usize).weight), _MM_HINT_T0);  // No benefit for now
@@ -344,7 +718,7 @@ L: std::clone::Clone
                         let feature_index     = hashvalue.hash as usize;
                         let feature_value:f32 = hashvalue.value;
                         
-                        let gradient = general_gradient * feature_value;
+                        let gradient = lr_general_gradient * feature_value;
                         let update = self.optimizer_lr.calculate_update(gradient, &mut weights.get_unchecked_mut(feature_index).optimizer_data);
                         weights.get_unchecked_mut(feature_index).weight += update;
                     }
@@ -356,6 +730,53 @@ L: std::clone::Clone
                         let update = self.optimizer_ffm.calculate_update(gradient, &mut ffm_weights.get_unchecked_mut(feature_index).optimizer_data);
                         ffm_weights.get_unchecked_mut(feature_index).weight += update;
                     }
+                    if self.ffm_k_threshold > 0.0 {
+                        // Locked dimensions got no derivatives, so only active ones add to the accumulated gradient
+                        let k_state_index = (self.ffm_k_state_offset - self.ffm_weights_offset) as usize;
+                        let ffm_k = self.ffm_k as usize;
+                        for i in (0..local_data_ffm_len).step_by(ffm_k) {
+                            let mut accumulated_gradient:f32 = 0.0;
+                            for k in 0..ffm_k {
+                                accumulated_gradient += (general_gradient * *local_data_ffm_values.get_unchecked(i + k)).abs();
+                            }
+                            let vector_index = *local_data_ffm_indices.get_unchecked(i) as usize;
+                            ffm_weights.get_unchecked_mut(k_state_index + vector_index / ffm_k).weight += accumulated_gradient;
+                        }
+                    }
+                    if self.fm_k > 0 {
+                        // Derivative by a weight is the feature value times the sum of other features in that dimension
+                        let fm_index = (self.fm_weights_offset - self.ffm_weights_offset) as usize;
+                        for hashvalue in fb.fm_buffer.iter() {
+                            let feature_index = fm_index + hashvalue.hash as usize;
+                            for k in 0..self.fm_k as usize {
+                                let weight = ffm_weights.get_unchecked(feature_index + k).weight;
+                                let feature_value = hashvalue.value * (*self.fm_sums.get_unchecked(k) - weight * hashvalue.value);
+                                let gradient = general_gradient * feature_value;
+                                let update = self.optimizer_fm.calculate_update(gradient, &mut ffm_weights.get_unchecked_mut(feature_index + k).optimizer_data);
+                                ffm_weights.get_unchecked_mut(feature_index + k).weight += update;
+                            }
+                        }
+                    }
+                    if self.fwfm_k > 0 {
+                        let fwfm_index = (self.fwfm_weights_offset - self.ffm_weights_offset) as usize;
+                        let pair_index = (self.fwfm_pair_weights_offset - self.ffm_weights_offset) as usize;
+                        let fwfm_k = self.fwfm_k as usize;
+                        for (i, hashvalue) in fb.fwfm_buffer.iter().enumerate() {
+                            let feature_index = fwfm_index + hashvalue.hash as usize;
+                            for k in 0..fwfm_k {
+                                let gradient = general_gradient * *self.fwfm_local_values.get_unchecked(i * fwfm_k + k);
+                                let update = self.optimizer_ffm.calculate_update(gradient, &mut ffm_weights.get_unchecked_mut(feature_index + k).optimizer_data);
+                                ffm_weights.get_unchecked_mut(feature_index + k).weight += update;
+                            }
+                        }
+                        for (pair, pair_gradient) in self.fwfm_pair_gradients.iter().enumerate() {
+                            if *pair_gradient != 0.0 {
+                                let gradient = general_gradient * pair_gradient;
+                                let update = self.optimizer_ffm.calculate_update(gradient, &mut ffm_weights.get_unchecked_mut(pair_index + pair).optimizer_data);
+                                ffm_weights.get_unchecked_mut(pair_index + pair).weight += update;
+                            }
+                        }
+                    }
                 }
         
             };
@@ -391,7 +812,7 @@ L: std::clone::Clone
         unsafe {
              let buf_view:&[u8] = slice::from_raw_parts(self.weights.as_ptr() as *const u8, 
                                               self.weights.len() *mem::size_of::<WeightAndOptimizerData<L>>());
-             output_bufwriter.write(buf_view)?;
+             output_bufwriter.write_all(buf_view)?;
         }
         
         Ok(())
@@ -440,12 +861,7 @@ L: std::clone::Clone
             }
         }
 
-        let fr = ImmutableRegressor {
-                        weights: Arc::new(out_weights), 
-                        ffm_weights_offset: self.ffm_weights_offset,
-                        ffm_k: self.ffm_k,
-        };
-        Ok(fr)
+        self.immutable_regressor_from_weights(ImmutableWeights::Owned(out_weights))
     }
 
     // Create immutable regressor from current regressor
@@ -455,10 +871,28 @@ L: std::clone::Clone
             weights.push(Weight{weight:w.weight});
         }
 
+        self.immutable_regressor_from_weights(ImmutableWeights::Owned(weights))
+    }
+
+    // Create immutable regressor from current setup and already loaded (or mapped) weights
+    fn immutable_regressor_from_weights(&mut self, weights: ImmutableWeights) -> Result<ImmutableRegressor, Box<dyn Error>> {
+        if weights.len() != self.weights_len as usize {
+            return Err(format!("Lenghts of weights array in regressor file differ: got {}, expected {}", weights.len(), self.weights_len))?;
+        }
         let fr = ImmutableRegressor {
                         weights: Arc::new(weights), 
                         ffm_weights_offset: self.ffm_weights_offset,
                         ffm_k: self.ffm_k,
+                        ffm_k_threshold: self.ffm_k_threshold,
+                        ffm_k_state_offset: self.ffm_k_state_offset,
+                        ffm_no_intra_field: self.ffm_no_intra_field,
+                        fm_weights_offset: self.fm_weights_offset,
+                        fm_k: self.fm_k,
+                        fwfm_weights_offset: self.fwfm_weights_offset,
+                        fwfm_pair_weights_offset: self.fwfm_pair_weights_offset,
+                        fwfm_k: self.fwfm_k,
+                        fwfm_fields: self.fwfm_fields,
+                        mlp_layout: self.mlp_layout,
         };
         Ok(fr)
     }
@@ -469,39 +903,186 @@ L: std::clone::Clone
 
 impl ImmutableRegressor {
 
+    // Accumulated gradients of adaptive k are exported as the middle of their active k's range,
+    // so quantization can't change active k
+    fn export_weights(&self) -> Result<ImmutableWeights, Box<dyn Error>> {
+        let mut weights = self.weights.f32_weights().ok_or("Weights are already quantized or pruned")?.to_vec();
+        if self.ffm_k_threshold > 0.0 {
+            for w in &mut weights[self.ffm_k_state_range()] {
+                w.weight = (ffm_active_k(w.weight, self.ffm_k_threshold, self.ffm_k) as f32 - 0.5) * self.ffm_k_threshold;
+            }
+        }
+        Ok(ImmutableWeights::Owned(weights))
+    }
+
+    fn ffm_k_state_range(&self) -> ops::Range<usize> {
+        self.ffm_k_state_offset as usize .. self.fm_weights_offset as usize
+    }
+
+    // Same regressor with weights quantized
+    pub fn quantized(&self, quantization: quantization::WeightQuantization) -> Result<ImmutableRegressor, Box<dyn Error>> {
+        Ok(ImmutableRegressor {
+            weights: Arc::new(self.export_weights()?.quantize(quantization)?),
+            ffm_weights_offset: self.ffm_weights_offset,
+            ffm_k: self.ffm_k,
+            ffm_k_threshold: self.ffm_k_threshold,
+            ffm_k_state_offset: self.ffm_k_state_offset,
+            ffm_no_intra_field: self.ffm_no_intra_field,
+            fm_weights_offset: self.fm_weights_offset,
+            fm_k: self.fm_k,
+            fwfm_weights_offset: self.fwfm_weights_offset,
+            fwfm_pair_weights_offset: self.fwfm_pair_weights_offset,
+            fwfm_k: self.fwfm_k,
+            fwfm_fields: self.fwfm_fields,
+            mlp_layout: self.mlp_layout,
+        })
+    }
+
+    // Same regressor with weights of absolute value up to the threshold dropped, adaptive k state is always kept
+    pub fn pruned(&self, threshold: f32) -> Result<ImmutableRegressor, Box<dyn Error>> {
+        Ok(ImmutableRegressor {
+            weights: Arc::new(self.export_weights()?.prune(threshold, self.ffm_k_state_range())?),
+            ffm_weights_offset: self.ffm_weights_offset,
+            ffm_k: self.ffm_k,
+            ffm_k_threshold: self.ffm_k_threshold,
+            ffm_k_state_offset: self.ffm_k_state_offset,
+            ffm_no_intra_field: self.ffm_no_intra_field,
+            fm_weights_offset: self.fm_weights_offset,
+            fm_k: self.fm_k,
+            fwfm_weights_offset: self.fwfm_weights_offset,
+            fwfm_pair_weights_offset: self.fwfm_pair_weights_offset,
+            fwfm_k: self.fwfm_k,
+            fwfm_fields: self.fwfm_fields,
+            mlp_layout: self.mlp_layout,
+        })
+    }
+
     pub fn predict(&self, fb: &feature_buffer::FeatureBuffer, example_num: u32) -> f32 {
+        match &*self.weights {
+            ImmutableWeights::Sparse(weights) => self.predict_with_weights(weights, fb, example_num),
+            ImmutableWeights::F16(weights) => self.predict_with_weights(&weights[..], fb, example_num),
+            ImmutableWeights::Int8(weights) => self.predict_with_weights(weights, fb, example_num),
+            weights => self.predict_with_weights(weights.f32_weights().unwrap(), fb, example_num),
+        }
+    }
+
+    #[inline(always)]
+    fn predict_with_weights<W: WeightLookup + ?Sized>(&self, weights: &W, fb: &feature_buffer::FeatureBuffer, example_num: u32) -> f32 {
         let fbuf = &fb.lr_buffer;
         let mut wsum:f32 = 0.0;
         unsafe {
         for val in fbuf {
             let hash = val.hash as usize;
             let feature_value:f32 = val.value;
-            wsum += self.weights.get_unchecked(hash).weight * feature_value;    
+            wsum += weights.weight(hash) * feature_value;
+        }
+        // Inputs and activations of the neural network head, in a buffer that every thread reuses
+        let mut mlp_buffer: Vec<f32> = Vec::new();
+        if let Some(layout) = self.mlp_layout {
+            mlp_buffer = MLP_BUFFER.with(|buffer| buffer.take());
+            mlp_buffer.truncate(0);
+            mlp_buffer.resize(layout.inputs + layout.activations_len(), 0.0);
+        }
+        let (mlp_inputs, mlp_activations) = mlp_buffer.split_at_mut(self.mlp_layout.map_or(0, |layout| layout.inputs));
+        if self.mlp_layout.is_some() {
+            *mlp_inputs.get_unchecked_mut(0) = wsum;
         }
 
-        if self.ffm_k > 0 {
-            let ffm_weights = &self.weights[self.ffm_weights_offset as usize..];
+        if self.ffm_k > 0 && self.mlp_layout.is_some() {
+            // Interactions summed by pairs of fields are inputs of the neural network head
+            let ffm_weights_offset = self.ffm_weights_offset as usize;
+            let ffm_fields_count = fb.ffm_fields_count as usize;
             for (i, left_hash) in fb.ffm_buffer.iter().enumerate() {
+                let left_field = (left_hash.contra_field_index / self.ffm_k) as usize;
                 for right_hash in fb.ffm_buffer.get_unchecked(i+1 ..).iter() {
-               //     if left_hash.contra_field_index == right_hash.contra_field_index {
-               //         continue	// not combining within a field
-               //     }
+                    if self.ffm_no_intra_field && left_hash.contra_field_index == right_hash.contra_field_index {
+                        continue	// not combining within a field
+                    }
+                    let right_field = (right_hash.contra_field_index / self.ffm_k) as usize;
+                    let lindex = ffm_weights_offset + (left_hash.hash + right_hash.contra_field_index) as usize;
+                    let rindex = ffm_weights_offset + (right_hash.hash + left_hash.contra_field_index) as usize;
+                    let mut contribution:f32 = 0.0;
+                    for k in 0..self.ffm_k as usize {
+                        contribution += weights.weight(lindex + k) * weights.weight(rindex + k);
+                    }
+                    contribution *= left_hash.value * right_hash.value;
+                    *mlp_inputs.get_unchecked_mut(mlp::field_pair_input(min(left_field, right_field), std::cmp::max(left_field, right_field), ffm_fields_count)) += contribution;
+                    wsum += contribution;
+                }
+            }
+        } else if self.ffm_k > 0 {
+            let ffm_weights_offset = self.ffm_weights_offset as usize;
+            specialize_bool!(self.ffm_no_intra_field, NO_INTRA_FIELD, {
+            for (i, left_hash) in fb.ffm_buffer.iter().enumerate() {
+                for right_hash in fb.ffm_buffer.get_unchecked(i+1 ..).iter() {
+                    if NO_INTRA_FIELD && left_hash.contra_field_index == right_hash.contra_field_index {
+                        continue	// not combining within a field
+                    }
                     let joint_value = left_hash.value * right_hash.value;
                     let lindex = (left_hash.hash + right_hash.contra_field_index) as u32;
                     let rindex = (right_hash.hash + left_hash.contra_field_index) as u32;
-                    for k in 0..self.ffm_k {
-                        let left_hash_weight  = ffm_weights.get_unchecked((lindex+k) as usize).weight;
-                        let right_hash_weight = ffm_weights.get_unchecked((rindex+k) as usize).weight;
+                    let mut pair_k = self.ffm_k;
+                    if self.ffm_k_threshold > 0.0 {
+                        let ffm_k_state_offset = self.ffm_k_state_offset as usize;
+                        let ffm_k = self.ffm_k as usize;
+                        pair_k = min(ffm_active_k(weights.weight(ffm_k_state_offset + lindex as usize / ffm_k), self.ffm_k_threshold, self.ffm_k),
+                                     ffm_active_k(weights.weight(ffm_k_state_offset + rindex as usize / ffm_k), self.ffm_k_threshold, self.ffm_k));
+                    }
+                    for k in 0..pair_k {
+                        let left_hash_weight  = weights.weight(ffm_weights_offset + (lindex+k) as usize);
+                        let right_hash_weight = weights.weight(ffm_weights_offset + (rindex+k) as usize);
                         let right_side = right_hash_weight * joint_value;
                         wsum += left_hash_weight * right_side;
                     }
                 }
             
             }
+            });
 
             
         }
 
+        if self.fm_k > 0 {
+            let fm_weights_offset = self.fm_weights_offset as usize;
+            for k in 0..self.fm_k as usize {
+                let mut sum:f32 = 0.0;
+                let mut squares_sum:f32 = 0.0;
+                for val in fb.fm_buffer.iter() {
+                    let wx = weights.weight(fm_weights_offset + val.hash as usize + k) * val.value;
+                    sum += wx;
+                    squares_sum += wx * wx;
+                }
+                wsum += 0.5 * (sum * sum - squares_sum);
+            }
+        }
+
+        if self.fwfm_k > 0 {
+            let fwfm_weights_offset = self.fwfm_weights_offset as usize;
+            let fwfm_pair_weights_offset = self.fwfm_pair_weights_offset as usize;
+            let fwfm_fields = self.fwfm_fields as usize;
+            for (i, left_hash) in fb.fwfm_buffer.iter().enumerate() {
+                let lindex = fwfm_weights_offset + left_hash.hash as usize;
+                for right_hash in fb.fwfm_buffer.get_unchecked(i+1 ..).iter() {
+                    if self.ffm_no_intra_field && left_hash.contra_field_index == right_hash.contra_field_index {
+                        continue	// not combining within a field
+                    }
+                    let rindex = fwfm_weights_offset + right_hash.hash as usize;
+                    let pair = (min(left_hash.contra_field_index, right_hash.contra_field_index) as usize) * fwfm_fields
+                                + std::cmp::max(left_hash.contra_field_index, right_hash.contra_field_index) as usize;
+                    let mut dot:f32 = 0.0;
+                    for k in 0..self.fwfm_k as usize {
+                        dot += weights.weight(lindex + k) * weights.weight(rindex + k);
+                    }
+                    wsum += weights.weight(fwfm_pair_weights_offset + pair) * dot * left_hash.value * right_hash.value;
+                }
+            }
+        }
+
+        if let Some(layout) = self.mlp_layout {
+            wsum += mlp::forward(weights, &layout, mlp_inputs, mlp_activations);
+            MLP_BUFFER.with(|buffer| buffer.replace(mlp_buffer));
+        }
+
         let prediction = -wsum;
         let mut prediction_finalized = prediction;
         if prediction_finalized.is_nan() {
@@ -543,6 +1124,9 @@ impl RegressorTrait for ImmutableRegressor {
         panic!("Not implemented!");
     }
     fn immutable_regressor(&mut self) -> Result<ImmutableRegressor, Box<dyn Error>> {
+        Ok(self.clone())
+    }
+    fn immutable_regressor_from_weights(&mut self, weights: ImmutableWeights) -> Result<ImmutableRegressor, Box<dyn Error>> {
         panic!("Not implemented!");
     }
 
@@ -561,6 +1145,8 @@ mod tests {
                     lr_buffer: v,
                     ffm_buffer: Vec::new(),
                     ffm_fields_count: 0,
+                    fm_buffer: Vec::new(),
+                    fwfm_buffer: Vec::new(),
         }
     }
 
@@ -692,6 +1278,8 @@ mod tests {
                     lr_buffer: Vec::new(),
                     ffm_buffer: v,
                     ffm_fields_count: ffm_fields_count,
+                    fm_buffer: Vec::new(),
+                    fwfm_buffer: Vec::new(),
         }
     }
 
@@ -750,6 +1338,254 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_fm() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.learning_rate = 0.1;
+        mi.fm_learning_rate = 0.1;
+        mi.bit_precision = 18;
+        mi.fm_k = 2;
+        mi.fm_bit_precision = 10;
+        let mut re = Regressor::<optimizer::OptimizerSGD>::new(&mi);
+        assert_eq!(re.fm_weights_len, 1 << 10);
+        assert_eq!(re.weights_len, (1 << 18) + (1 << 10));
+        let mut fb = lr_vec(vec![]);
+        fb.fm_buffer = vec![HashAndValue{hash: 0, value: 1.0}, HashAndValue{hash: 2, value: 2.0}, HashAndValue{hash: 4, value: 1.0}];
+        // Latent vectors are [1, 0], [0.5, 1] and [1, 1]
+        for (i, w) in [1.0, 0.0, 0.5, 1.0, 1.0, 1.0].iter().enumerate() {
+            re.weights[re.fm_weights_offset as usize + i].weight = *w;
+        }
+        // Pairs: 1 * 2 * 0.5 + 1 * 1 * 1 + 2 * 1 * 1.5
+        let p = re.learn(&fb, false, 0);
+        assert_eq!(p, logistic(5.0));
+        assert_eq!(re.immutable_regressor().unwrap().predict(&fb, 0), p);
+
+        // Derivative of the first weight is its feature value times the sum of other features in the first dimension: 1 * (3 - 1)
+        re.learn(&fb, true, 0);
+        assert!((re.weights[re.fm_weights_offset as usize].weight - (1.0 - 0.1 * p * 2.0)).abs() < 1e-6);
+        assert!(re.learn(&fb, false, 0) < p);
+
+        // A single feature has no pairs
+        fb.fm_buffer = vec![HashAndValue{hash: 2, value: 2.0}];
+        assert_eq!(re.learn(&fb, false, 0), 0.5);
+    }
+
+    #[test]
+    fn test_fwfm() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.bit_precision = 18;
+        mi.fwfm_k = 2;
+        mi.ffm_bit_precision = 10;
+        mi.ffm_fields = vec![vec![], vec![], vec![]]; // This isn't really used
+        let mut re = Regressor::<optimizer::OptimizerSGD>::new(&mi);
+        assert_eq!(re.weights_len, (1 << 18) + (1 << 10) + 3 * 3);
+        let pair_weights = re.fwfm_pair_weights_offset as usize;
+        assert_eq!(re.weights[pair_weights + 4].weight, 1.0);
+        let mut fb = lr_vec(vec![]);
+        fb.ffm_fields_count = 3;
+        fb.fwfm_buffer = vec![HashAndValueAndSeq{hash: 0, value: 1.0, contra_field_index: 0},
+                              HashAndValueAndSeq{hash: 2, value: 2.0, contra_field_index: 1},
+                              HashAndValueAndSeq{hash: 4, value: 1.0, contra_field_index: 2}];
+        // Latent vectors are [1, 0], [0.5, 1] and [1, 1], pair of first two fields has weight 2.0
+        for (i, w) in [1.0, 0.0, 0.5, 1.0, 1.0, 1.0].iter().enumerate() {
+            re.weights[re.fwfm_weights_offset as usize + i].weight = *w;
+        }
+        re.weights[pair_weights + 0 * 3 + 1].weight = 2.0;
+        // Pairs: 2.0 * 1 * 2 * 0.5 + 1.0 * 1 * 1 * 1 + 1.0 * 2 * 1 * 1.5
+        let p = re.learn(&fb, false, 0);
+        assert_eq!(p, logistic(6.0));
+        assert_eq!(re.immutable_regressor().unwrap().predict(&fb, 0), p);
+
+        re.learn(&fb, true, 0);
+        // Derivative of a pair weight is the interaction of its pair: 1 * 2 * 0.5
+        assert!((re.weights[pair_weights + 1].weight - (2.0 - 0.1 * p * 1.0)).abs() < 1e-6);
+        assert_eq!(re.weights[pair_weights].weight, 1.0);
+        // Derivative of the first weight: 2.0 * 0.5 * 2 + 1.0 * 1 * 1
+        assert!((re.weights[re.fwfm_weights_offset as usize].weight - (1.0 - 0.1 * p * 3.0)).abs() < 1e-6);
+
+        // Learning and immutable prediction stay the same, also in reversed field order
+        let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        for _ in 0..5 {
+            let p = re.learn(&fb, false, 0);
+            assert_eq!(re.immutable_regressor().unwrap().predict(&fb, 0), p);
+            re.learn(&fb, true, 0);
+        }
+        fb.fwfm_buffer.reverse();
+        assert_eq!(re.learn(&fb, false, 0), re.immutable_regressor().unwrap().predict(&fb, 0));
+
+        // With --ffm_no_intra_field features of the same field don't interact
+        mi.ffm_no_intra_field = true;
+        let mut re = Regressor::<optimizer::OptimizerSGD>::new(&mi);
+        for (i, w) in [1.0, 0.0, 0.5, 1.0, 1.0, 1.0].iter().enumerate() {
+            re.weights[re.fwfm_weights_offset as usize + i].weight = *w;
+        }
+        fb.fwfm_buffer = vec![HashAndValueAndSeq{hash: 0, value: 1.0, contra_field_index: 0},
+                              HashAndValueAndSeq{hash: 2, value: 2.0, contra_field_index: 0},
+                              HashAndValueAndSeq{hash: 4, value: 1.0, contra_field_index: 1}];
+        // Pairs: 1 * 1 * 1 + 2 * 1 * 1.5
+        let p = re.learn(&fb, false, 0);
+        assert_eq!(p, logistic(4.0));
+        assert_eq!(re.immutable_regressor().unwrap().predict(&fb, 0), p);
+        re.learn(&fb, true, 0);
+        // Pair of the first field with itself got no gradient
+        assert_eq!(re.weights[pair_weights].weight, 1.0);
+        assert!(re.weights[pair_weights + 1].weight < 1.0);
+    }
+
+    #[test]
+    fn test_mlp() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.mlp_learning_rate = 0.1;
+        mi.bit_precision = 18;
+        mi.ffm_k = 2;
+        mi.ffm_bit_precision = 10;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        let mut fb = ffm_vec(vec![
+                                  HashAndValueAndSeq{hash:1, value: 1.0, contra_field_index: 0},
+                                  HashAndValueAndSeq{hash:100, value: 2.0, contra_field_index: 2}
+                                  ], 2);
+        fb.lr_buffer = vec![HashAndValue{hash: 1, value: 1.0}];
+        let mut re_plain = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        mi.mlp_hidden = vec![4, 3];
+        let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        // LR sum and three field pairs as inputs, head goes after all other weights
+        let layout = re.mlp_layout.unwrap();
+        assert_eq!(layout.inputs, 4);
+        assert_eq!(layout.offset, re_plain.weights_len as usize);
+        assert_eq!(re.weights_len as usize, layout.offset + 4 * 5 + 3 * 5 + 4);
+
+        // Untrained head outputs zero, so the model starts as the plain one
+        assert_eq!(re.learn(&fb, false, 0), re_plain.learn(&fb, false, 0));
+        re_plain.learn(&fb, true, 0);
+        let mut p = 0.0;
+        for _ in 0..5 {
+            p = re.learn(&fb, false, 0);
+            assert_eq!(re.immutable_regressor().unwrap().predict(&fb, 0), p);
+            re.learn(&fb, true, 0);
+        }
+        // Head is trained and the prediction differs from the plain model
+        assert!(re.weights[layout.offset + layout.weights_len() - 1].weight != 0.0);
+        assert!(p != re_plain.learn(&fb, false, 0));
+
+        // Without ffm the head only sees the LR sum
+        mi.ffm_k = 0;
+        let re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        assert_eq!(re.mlp_layout.unwrap().inputs, 1);
+    }
+
+    #[test]
+    fn test_ffm_no_intra_field() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        // Two features in the first field and one in the second
+        let ffm_buf = ffm_vec(vec![
+                                  HashAndValueAndSeq{hash:1, value: 1.0, contra_field_index: 0},
+                                  HashAndValueAndSeq{hash:50, value: 1.0, contra_field_index: 0},
+                                  HashAndValueAndSeq{hash:100, value: 1.0, contra_field_index: 4}
+                                  ], 2);
+
+        // By default all three pairs interact
+        let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        ffm_init(&mut re);
+        assert_eq!(re.learn(&ffm_buf, false, 0), logistic(12.0));
+
+        // Without intra field interactions only the two cross-field pairs do
+        mi.ffm_no_intra_field = true;
+        let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        ffm_init(&mut re);
+        assert_eq!(re.learn(&ffm_buf, false, 0), logistic(8.0));
+        assert_eq!(re.immutable_regressor().unwrap().predict(&ffm_buf, 0), logistic(8.0));
+        re.learn(&ffm_buf, true, 0);
+        // Same field pair got no gradient
+        assert_eq!(re.weights[(re.ffm_weights_offset + 1) as usize].weight, 1.0);
+        assert!(re.weights[(re.ffm_weights_offset + 1 + 4) as usize].weight < 1.0);
+        assert_eq!(re.learn(&ffm_buf, false, 0), re.immutable_regressor().unwrap().predict(&ffm_buf, 0));
+    }
+
+    #[test]
+    fn test_ffm_adaptive_k() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.bit_precision = 18;
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        let ffm_buf = ffm_vec(vec![
+                                  HashAndValueAndSeq{hash:1, value: 1.0, contra_field_index: 0},
+                                  HashAndValueAndSeq{hash:100, value: 1.0, contra_field_index: 4}
+                                  ], 2);
+
+        // Threshold that is never reached: only the first dimension is ever used
+        mi.ffm_k_threshold = 1000.0;
+        let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        ffm_init(&mut re);
+        for i in re.ffm_k_state_offset as usize..re.weights.len() {
+            re.weights[i].weight = 0.0;
+        }
+        // One accumulated gradient per vector, in its own int8 quantization blocks
+        assert_eq!(re.ffm_k_state_offset % quantization::INT8_BLOCK_LEN as u32, 0);
+        assert_eq!(re.weights_len, re.ffm_k_state_offset + (re.ffm_weights_len / 4 + 255) / 256 * 256);
+        assert_eq!(re.learn(&ffm_buf, true, 0), logistic(1.0));
+        // Locked dimensions are not updated
+        assert_eq!(re.weights[(re.ffm_weights_offset + 1 + 4 + 1) as usize].weight, 1.0);
+        assert!(re.weights[(re.ffm_weights_offset + 1 + 4) as usize].weight < 1.0);
+        // Gradient of the active dimension is accumulated for both vectors
+        let accumulated = re.weights[(re.ffm_k_state_offset + (1 + 4) / 4) as usize].weight;
+        assert!(accumulated > 0.0);
+        assert_eq!(re.weights[(re.ffm_k_state_offset + 100 / 4) as usize].weight, accumulated);
+
+        // Low threshold: a dimension gets unlocked with each update
+        let threshold = accumulated * 0.99;
+        mi.ffm_k_threshold = threshold;
+        let mut re = Regressor::<optimizer::OptimizerAdagradFlex>::new(&mi);
+        ffm_init(&mut re);
+        for i in re.ffm_k_state_offset as usize..re.weights.len() {
+            re.weights[i].weight = 0.0;
+        }
+        assert_eq!(re.learn(&ffm_buf, true, 0), logistic(1.0));
+        let state_index = (re.ffm_k_state_offset + (1 + 4) / 4) as usize;
+        assert_eq!(ffm_active_k(re.weights[state_index].weight, threshold, 4), 2);
+        // Exported models keep active k, even when the accumulated gradient itself would be pruned
+        let re_immutable = re.immutable_regressor().unwrap();
+        for re_exported in vec![re_immutable.quantized(quantization::WeightQuantization::F16).unwrap(),
+                                re_immutable.quantized(quantization::WeightQuantization::Int8).unwrap(),
+                                re_immutable.pruned(re.weights[state_index].weight * 10.0).unwrap()] {
+            let exported_state = unsafe {
+                match &*re_exported.weights {
+                    ImmutableWeights::F16(weights) => weights[..].weight(state_index),
+                    ImmutableWeights::Int8(weights) => weights.weight(state_index),
+                    ImmutableWeights::Sparse(weights) => weights.weight(state_index),
+                    _ => unreachable!(),
+                }
+            };
+            assert_eq!(ffm_active_k(exported_state, threshold, 4), 2);
+        }
+        for _ in 0..3 {
+            let p = re.learn(&ffm_buf, false, 0);
+            let p_immutable = re.immutable_regressor().unwrap().predict(&ffm_buf, 0);
+            assert_eq!(p, p_immutable);
+            re.learn(&ffm_buf, true, 0);
+        }
+        // Active k never exceeds ffm_k
+        assert!(re.weights[state_index].weight > threshold * 4.0);
+        assert_eq!(ffm_active_k(re.weights[state_index].weight, threshold, 4), 4);
+        assert_eq!(re.learn(&ffm_buf, false, 0), re.immutable_regressor().unwrap().predict(&ffm_buf, 0));
+    }
+
 
     #[test]
     fn test_example_importance() {
@@ -0,0 +1,152 @@
+use merand48::*;
+
+use crate::model_instance;
+use crate::parser;
+
+/*
+Negative downsampling: negatives are dropped with probability 1 - rate. The bias this introduces
+is corrected either by scaling the importance of the kept negatives by 1/rate, or (with
+negative_sampling_calibrate_predictions) by leaving the importance alone and correcting the predictions:
+p = p' * rate / (p' * rate + 1 - p')
+*/
+
+// Decision of negative downsampling, shared by --negative_sampling_rate and --cache_negative_rate of the indexed cache
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NegativeDownsampling {
+    pub rate: f32,
+    pub importance_multiplier: f32,
+}
+
+impl NegativeDownsampling {
+    pub fn new(rate: f32, calibrate_predictions: bool) -> NegativeDownsampling {
+        NegativeDownsampling {
+            rate: rate,
+            importance_multiplier: if calibrate_predictions { 1.0 } else { 1.0 / rate },
+        }
+    }
+
+    // r is a random number in [0, 1). Returns importance multiplier of the negative, or None if it is dropped
+    #[inline(always)]
+    pub fn sample_negative(&self, r: f32) -> Option<f32> {
+        if r < self.rate {
+            Some(self.importance_multiplier)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct NegativeSampler {
+    downsampling: NegativeDownsampling,
+    seed: u64,
+}
+
+impl NegativeSampler {
+    pub fn new(mi: &model_instance::ModelInstance, seed: u64) -> NegativeSampler {
+        NegativeSampler {
+            downsampling: NegativeDownsampling::new(mi.negative_sampling_rate, mi.negative_sampling_calibrate_predictions),
+            seed: seed,
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_active(&self) -> bool {
+        self.downsampling.rate < 1.0
+    }
+
+    // Returns importance multiplier for the example, or None if the example is dropped
+    pub fn sample(&mut self, record_buffer: &[u32]) -> Option<f32> {
+        if record_buffer[parser::LABEL_OFFSET] != 0 {
+            return Some(1.0);
+        }
+        let r = merand48(self.seed);
+        self.seed += 1;
+        self.downsampling.sample_negative(r)
+    }
+}
+
+// Rate to use with calibrate_prediction(), 1.0 means predictions need no correction
+pub fn prediction_calibration_rate(mi: &model_instance::ModelInstance) -> f32 {
+    if mi.negative_sampling_calibrate_predictions {
+        mi.negative_sampling_rate
+    } else {
+        1.0
+    }
+}
+
+#[inline(always)]
+pub fn calibrate_prediction(prediction: f32, rate: f32) -> f32 {
+    if rate == 1.0 {
+        return prediction;
+    }
+    prediction * rate / (prediction * rate + 1.0 - prediction)
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_negative_sampler() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.negative_sampling_rate = 0.25;
+        let positive = vec![3, 1, parser::FLOAT32_ONE];
+        let negative = vec![3, 0, parser::FLOAT32_ONE];
+        let unlabeled = vec![3, parser::NO_LABEL, parser::FLOAT32_ONE];
+
+        let mut ns = NegativeSampler::new(&mi, 5);
+        assert!(ns.is_active());
+        let mut kept = 0;
+        for _ in 0..10000 {
+            assert_eq!(ns.sample(&positive), Some(1.0));
+            assert_eq!(ns.sample(&unlabeled), Some(1.0));
+            match ns.sample(&negative) {
+                Some(multiplier) => { assert_eq!(multiplier, 4.0); kept += 1 },
+                None => {}
+            }
+        }
+        assert!(kept > 2300 && kept < 2700, "{}", kept);
+        assert_eq!(prediction_calibration_rate(&mi), 1.0);
+
+        // Same seed, same decisions
+        let mut ns1 = NegativeSampler::new(&mi, 5);
+        let mut ns2 = NegativeSampler::new(&mi, 5);
+        for _ in 0..100 {
+            assert_eq!(ns1.sample(&negative), ns2.sample(&negative));
+        }
+
+        // Calibrating predictions instead of importance
+        mi.negative_sampling_calibrate_predictions = true;
+        let mut ns = NegativeSampler::new(&mi, 5);
+        for _ in 0..100 {
+            assert!(ns.sample(&negative).unwrap_or(1.0) == 1.0);
+        }
+        assert_eq!(prediction_calibration_rate(&mi), 0.25);
+
+        // Not sampling at all
+        let mi = model_instance::ModelInstance::new_empty().unwrap();
+        assert!(!NegativeSampler::new(&mi, 5).is_active());
+    }
+
+    #[test]
+    fn test_negative_downsampling() {
+        let nd = NegativeDownsampling::new(0.25, false);
+        assert_eq!(nd.sample_negative(0.1), Some(4.0));
+        assert_eq!(nd.sample_negative(0.25), None);
+        let nd = NegativeDownsampling::new(0.25, true);
+        assert_eq!(nd.sample_negative(0.1), Some(1.0));
+    }
+
+    #[test]
+    fn test_calibrate_prediction() {
+        assert_eq!(calibrate_prediction(0.3, 1.0), 0.3);
+        assert_eq!(calibrate_prediction(0.5, 0.25), 0.2);
+        assert_eq!(calibrate_prediction(0.0, 0.25), 0.0);
+        assert_eq!(calibrate_prediction(1.0, 0.25), 1.0);
+        // Odds are scaled by the rate
+        let p = calibrate_prediction(0.8, 0.1);
+        assert!((p / (1.0 - p) - 0.4).abs() < 1e-5);
+    }
+}
@@ -15,6 +15,10 @@ use crate::regressor;
 use crate::feature_buffer;
 use crate::model_instance;
 use crate::optimizer;
+use crate::sampling;
+use crate::multiclass;
+use crate::ldf;
+use crate::cb;
 use crate::regressor::ImmutableRegressor;
 use crate::regressor::RegressorTrait;
 
@@ -30,6 +34,13 @@ pub struct WorkerThread {
     re_fixed: Arc<regressor::ImmutableRegressor>,
     fbt: feature_buffer::FeatureBufferTranslator,
     pa: parser::VowpalParser,
+    calibration_rate: f32,
+    oaa: Option<multiclass::OneAgainstAll>,
+    multiclass_output: multiclass::MulticlassOutput,
+    class_predictions: Vec<f32>,
+    ldf_example: Option<ldf::LdfExample>,
+    action_scores: bool,
+    cb: Option<cb::ContextualBandit>,
 }
 
 pub trait IsEmpty {
@@ -54,13 +65,26 @@ impl WorkerThread {
     pub fn new(
         id: u32, re_fixed: Arc<regressor::ImmutableRegressor>, fbt:
         feature_buffer::FeatureBufferTranslator, pa: parser::VowpalParser,
+        calibration_rate: f32,
+        oaa: Option<multiclass::OneAgainstAll>,
+        multiclass_output: multiclass::MulticlassOutput,
+        ldf_example: Option<ldf::LdfExample>,
+        action_scores: bool,
+        cb: Option<cb::ContextualBandit>,
         receiver: Arc<Mutex<mpsc::Receiver<net::TcpStream>>>,
     ) -> Result<thread::JoinHandle<u32>, Box<dyn Error>> {
         let mut wt = WorkerThread {
             id: id,
             re_fixed: re_fixed,
             fbt: fbt,
-            pa: pa
+            pa: pa,
+            calibration_rate: calibration_rate,
+            oaa: oaa,
+            multiclass_output: multiclass_output,
+            class_predictions: Vec::new(),
+            ldf_example: ldf_example,
+            action_scores: action_scores,
+            cb: cb,
         };
         let thread = thread::spawn(move || {
             wt.start(receiver);
@@ -75,15 +99,53 @@ impl WorkerThread {
                              ) -> ConnectionEnd
     {
         let mut i = 0u32;
+        if let Some(ldf_example) = self.ldf_example.as_mut() {
+            ldf_example.clear();    // leftovers of a previous connection
+        }
         loop {
-            let reading_result = self.pa.next_vowpal(reader);
+            let mut reading_result = self.pa.next(reader);
+            if let (Some(ldf_example), Ok(buffer)) = (self.ldf_example.as_mut(), &reading_result) {
+                // Lines of a multi-line example are collected, it gets its line of output after the empty line that ends it
+                if !buffer.is_empty() {
+                    match ldf_example.push(buffer) {
+                        Ok(true) => {},
+                        Ok(false) => continue,
+                        Err(e) => reading_result = Err(e)
+                    }
+                }
+            }
 
             match reading_result {
                 Ok([]) => return ConnectionEnd::EndOfStream, // EOF
                 Ok(buffer2) => {
-                    self.fbt.translate(buffer2);
-                    let p = self.re_fixed.predict(&(self.fbt.feature_buffer), i);
-                    let p_res = format!("{:.6}\n", p);
+                    let p_res = match (self.oaa.as_mut(), self.ldf_example.as_mut()) {
+                        (_, Some(ldf_example)) => match self.cb.as_mut() {
+                            // Chosen action and its probability, or predicted costs of all actions
+                            Some(cb) => {
+                                cb.predict(ldf_example, &self.re_fixed, &mut self.fbt, i, &mut self.class_predictions);
+                                match self.action_scores {
+                                    true => ldf::format_prediction(&self.class_predictions, true),
+                                    false => cb.format_prediction()
+                                }
+                            },
+                            None => {
+                                ldf_example.predict(&self.re_fixed, &mut self.fbt, i, &mut self.class_predictions);
+                                ldf::format_prediction(&self.class_predictions, self.action_scores)
+                            }
+                        },
+                        (Some(oaa), None) => {
+                            self.fbt.translate(buffer2);
+                            // One line per example, with the class or probabilities of all classes
+                            oaa.predict(&self.re_fixed, &(self.fbt.feature_buffer), i, &mut self.class_predictions);
+                            self.multiclass_output.format(&self.class_predictions)
+                        },
+                        (None, None) => {
+                            self.fbt.translate(buffer2);
+                            let p = self.re_fixed.predict(&(self.fbt.feature_buffer), i);
+                            let p = sampling::calibrate_prediction(p, self.calibration_rate);
+                            format!("{:.6}\n", p)
+                        }
+                    };
                     match writer.write_all(p_res.as_bytes()) {
                         Ok(_) => {},
                         Err(_e) => { /*println!("Write to socket failed, dropping it"); */ return ConnectionEnd::StreamWriteError; }
@@ -181,12 +243,26 @@ impl Serving {
 
         let re_fixed = Arc::new(re_fixed);
         let fbt = feature_buffer::FeatureBufferTranslator::new(mi);
-        let pa = parser::VowpalParser::new(&vw);
+        let mut pa = parser::VowpalParser::new_from_cmdline(&vw, cl)?;
+        pa.oaa = mi.oaa;
+        pa.ldf = mi.csoaa_ldf || mi.cb_explore_adf;
+        pa.cb = mi.cb_explore_adf;
+        let oaa = multiclass::OneAgainstAll::new(mi);
+        let multiclass_output = multiclass::MulticlassOutput::new_from_cmdline(cl)?;
         for i in 0..num_children {
             let newt = WorkerThread::new(i,
                                          re_fixed.clone(),
                                          fbt.clone(),
                                          pa.clone(),
+                                         sampling::prediction_calibration_rate(mi),
+                                         oaa.clone(),
+                                         multiclass_output,
+                                         match mi.csoaa_ldf || mi.cb_explore_adf {
+                                             true => Some(ldf::LdfExample::new(vw)),
+                                             false => None
+                                         },
+                                         cl.is_present("action_scores"),
+                                         cb::ContextualBandit::new_from_cmdline(cl, mi, i as u64)?,
                                          Arc::clone(&receiver),
             )?;
             s.worker_threads.push(newt);
@@ -244,6 +320,13 @@ C,featureC
                                  fbt: fbt,
                                  pa: pa,
                                  re_fixed: re_fixed,
+                                 calibration_rate: 1.0,
+                                 oaa: None,
+                                 multiclass_output: multiclass::MulticlassOutput {probabilities: false, softmax: false},
+                                 class_predictions: Vec::new(),
+                                 ldf_example: None,
+                                 action_scores: false,
+                                 cb: None,
                                  };
 
         { // WORKING STREAM TEST
@@ -296,7 +379,87 @@ C,featureC
                                  
     }
 
+    #[test]
+    fn test_handle_connection_ldf() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.csoaa_ldf = true;
+        let mut re = regressor::Regressor::<optimizer::OptimizerAdagradLUT>::new(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        pa.ldf = true;
+        let mut newt = WorkerThread {id: 1,
+                                 fbt: feature_buffer::FeatureBufferTranslator::new(&mi),
+                                 pa: pa,
+                                 re_fixed: Arc::new(re.immutable_regressor().unwrap()),
+                                 calibration_rate: 1.0,
+                                 oaa: None,
+                                 multiclass_output: multiclass::MulticlassOutput {probabilities: false, softmax: false},
+                                 class_predictions: Vec::new(),
+                                 ldf_example: Some(ldf::LdfExample::new(&vw)),
+                                 action_scores: true,
+                                 cb: None,
+                                 };
+        let mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        // One line of output per example, after its empty line
+        let mut mocked_stream = mocked_stream;
+        mocked_stream.push_bytes_to_read(b"shared |A a\n|B b\n|B c\n\n|B d\n\nshared |A a\n|B b\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        assert_eq!(mocked_stream.pop_bytes_written(), b"1:0.500000 2:0.500000\n1:0.500000\n");
+
+        // Unfinished example of the previous connection is dropped
+        mocked_stream.push_bytes_to_read(b"|B b\n\nshared |A a\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        assert_eq!(mocked_stream.pop_bytes_written(), b"1:0.500000\n");
+        mocked_stream.push_bytes_to_read(b"|B b\nshared |A a\n");
+        assert_eq!(ConnectionEnd::ParseError, newt.handle_connection(&mut reader, &mut writer));
+        assert_eq!(&mocked_stream.pop_bytes_written()[..], &b"ERR: Shared line has to be the first line of an example\n"[..]);
+    }
 
-
+    #[test]
+    fn test_handle_connection_cb() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.cb_explore_adf = true;
+        let mut re = regressor::Regressor::<optimizer::OptimizerAdagradLUT>::new(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        pa.ldf = true;
+        pa.cb = true;
+        let mut newt = WorkerThread {id: 1,
+                                 fbt: feature_buffer::FeatureBufferTranslator::new(&mi),
+                                 pa: pa,
+                                 re_fixed: Arc::new(re.immutable_regressor().unwrap()),
+                                 calibration_rate: 1.0,
+                                 oaa: None,
+                                 multiclass_output: multiclass::MulticlassOutput {probabilities: false, softmax: false},
+                                 class_predictions: Vec::new(),
+                                 ldf_example: Some(ldf::LdfExample::new(&vw)),
+                                 action_scores: false,
+                                 cb: cb::ContextualBandit::new(&mi, cb::Exploration::Softmax(1.0), 0),
+                                 };
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        // Equal predicted costs, so actions are chosen uniformly, labels of logged actions are ignored
+        mocked_stream.push_bytes_to_read(b"shared |A a\n|B b\n0:1:0.5 |B c\n\n|B d\n\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        let output = String::from_utf8(mocked_stream.pop_bytes_written()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0] == "1:0.500000" || lines[0] == "2:0.500000");
+        assert_eq!(lines[1], "1:1.000000");
+        assert_eq!(lines.len(), 2);
+
+        mocked_stream.push_bytes_to_read(b"|B b\n0:1 |B c\n\n");
+        assert_eq!(ConnectionEnd::ParseError, newt.handle_connection(&mut reader, &mut writer));
+        assert_eq!(&mocked_stream.pop_bytes_written()[..], &b"ERR: Logged action label has to be action:cost:probability, got: 0:1\n"[..]);
+    }
 }
-
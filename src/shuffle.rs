@@ -0,0 +1,106 @@
+use std::mem;
+use merand48::*;
+
+/*
+Reservoir-style shuffling of examples. The buffer is first filled with N examples, then every
+incoming example replaces a randomly chosen one, which is given out. At the end of the input,
+the remaining examples are given out in random order.
+*/
+
+pub struct ShuffleBuffer {
+    capacity: usize,
+    records: Vec<Vec<u32>>,
+    output: Vec<u32>,
+    seed: u64,
+}
+
+impl ShuffleBuffer {
+    pub fn new(capacity: usize, seed: u64) -> ShuffleBuffer {
+        ShuffleBuffer {
+            capacity: capacity,
+            records: Vec::with_capacity(capacity),
+            output: Vec::new(),
+            seed: seed,
+        }
+    }
+
+    fn random_index(&mut self, n: usize) -> usize {
+        // merand48 gives 23 random bits, we combine two of them for large buffers
+        let high = (merand48(self.seed) * (1 << 23) as f32) as u64;
+        let low = (merand48(self.seed + 1) * (1 << 23) as f32) as u64;
+        self.seed += 2;
+        (((high << 23) | low) % n as u64) as usize
+    }
+
+    // Returns an example to use, or None while the buffer is still filling up
+    pub fn push(&mut self, record_buf: &[u32]) -> Option<&[u32]> {
+        if self.records.len() < self.capacity {
+            self.records.push(record_buf.to_vec());
+            return None;
+        }
+        if self.capacity == 0 {
+            self.output.clear();
+            self.output.extend_from_slice(record_buf);
+            return Some(&self.output);
+        }
+        let i = self.random_index(self.records.len());
+        mem::swap(&mut self.output, &mut self.records[i]);
+        self.records[i].clear();
+        self.records[i].extend_from_slice(record_buf);
+        Some(&self.output)
+    }
+
+    // Empties the buffer at the end of the input
+    pub fn pop(&mut self) -> Option<&[u32]> {
+        if self.records.is_empty() {
+            return None;
+        }
+        let i = self.random_index(self.records.len());
+        self.output = self.records.swap_remove(i);
+        Some(&self.output)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn shuffle(capacity: usize, seed: u64, n: u32) -> Vec<u32> {
+        let mut sb = ShuffleBuffer::new(capacity, seed);
+        let mut out: Vec<u32> = Vec::new();
+        for i in 0..n {
+            if let Some(record) = sb.push(&[2, i]) {
+                out.push(record[1]);
+            }
+        }
+        while let Some(record) = sb.pop() {
+            out.push(record[1]);
+        }
+        out
+    }
+
+    #[test]
+    fn test_shuffle() {
+        let out = shuffle(100, 1, 1000);
+        // Nothing is lost or duplicated
+        let mut sorted = out.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..1000).collect::<Vec<u32>>());
+        // Order changed, but examples don't travel further back than the buffer allows
+        assert_ne!(out, sorted);
+        for (position, example) in out.iter().enumerate() {
+            assert!((*example as usize) < position + 101);
+        }
+        // Reproducible with the same seed, different with another one
+        assert_eq!(out, shuffle(100, 1, 1000));
+        assert_ne!(out, shuffle(100, 2, 1000));
+        // Shorter input than the buffer
+        let mut out = shuffle(100, 1, 10);
+        out.sort();
+        assert_eq!(out, (0..10).collect::<Vec<u32>>());
+        // Buffer of zero passes examples through
+        assert_eq!(shuffle(0, 1, 10), (0..10).collect::<Vec<u32>>());
+    }
+}